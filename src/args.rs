@@ -0,0 +1,162 @@
+use crate::calibration::Checkerboard;
+
+pub(crate) const USAGE: &str = "usage: voxel_carving [CONFIG.toml] [--scene NAME]
+    [--calibration-board COLSxROWSxSQUARE] [--fisheye FIELD_OF_VIEW]
+    [--estimate-bounds] [--bounds-padding PADDING] [--refine-poses]
+    [--continue-with-new-views FIRST_NEW] [--explain X,Y,Z]...";
+
+/// What `main` carves and what it does around the carve, from the command line, see `USAGE`.
+/// The carve itself is tuned by the config file, see `CarvingConfig`.
+#[derive(Debug)]
+pub(crate) struct Args {
+    // TOML file of carving parameters, the defaults if left out
+    pub(crate) config: Option<String>,
+    // the generated scene to carve, see `scene_generator::by_name`
+    pub(crate) scene: String,
+    // carve the photos in ./data/input/photos instead, with the cameras calibrated from photos of
+    // this checkerboard in ./data/input/calibration taken from the same positions
+    pub(crate) calibration_board: Option<Checkerboard>,
+    // the images were taken through an equidistant fisheye lens with this field of view in radians
+    pub(crate) fisheye_field_of_view: Option<f32>,
+    // fit the block to the cameras' view of the object instead of the configured length around
+    // the origin, adding `bounds_padding` world units around it
+    pub(crate) estimate_bounds: bool,
+    pub(crate) bounds_padding: f32,
+    // alternate carving with silhouette based camera pose refinement
+    pub(crate) refine_poses: bool,
+    // continue the carve saved in block.txt, the images after this many being new
+    pub(crate) continue_with_new_views: Option<usize>,
+    // print why the voxels at these grid coordinates were carved or kept, see `provenance::explain`
+    pub(crate) explain: Vec<(usize, usize, usize)>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            config: None,
+            scene: "two_cones".to_owned(),
+            calibration_board: None,
+            fisheye_field_of_view: None,
+            estimate_bounds: false,
+            bounds_padding: 0.1,
+            refine_poses: false,
+            continue_with_new_views: None,
+            explain: vec![],
+        }
+    }
+}
+
+impl Args {
+    /// Parses the arguments after the program name
+    pub(crate) fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+            match arg.as_str() {
+                "--scene" => parsed.scene = value()?,
+                "--calibration-board" => parsed.calibration_board = Some(board(&value()?)?),
+                "--fisheye" => parsed.fisheye_field_of_view = Some(number(&value()?)?),
+                "--estimate-bounds" => parsed.estimate_bounds = true,
+                "--bounds-padding" => parsed.bounds_padding = number(&value()?)?,
+                "--refine-poses" => parsed.refine_poses = true,
+                "--continue-with-new-views" => {
+                    parsed.continue_with_new_views = Some(number(&value()?)?)
+                }
+                "--explain" => {
+                    let value = value()?;
+                    let coordinates: Vec<usize> =
+                        value.split(',').map(number).collect::<Result<_, _>>()?;
+                    let [x, y, z] = coordinates[..] else {
+                        return Err(format!("--explain needs X,Y,Z, got {value}"));
+                    };
+                    parsed.explain.push((x, y, z));
+                }
+                _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+                _ if parsed.config.is_none() => parsed.config = Some(arg),
+                _ => return Err(format!("unexpected argument {arg}")),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{value} is not a valid number"))
+}
+
+/// a board written as COLSxROWSxSQUARE, e.g. 9x6x0.025
+fn board(value: &str) -> Result<Checkerboard, String> {
+    let parts: Vec<&str> = value.split('x').collect();
+    let [cols, rows, square_size] = parts[..] else {
+        return Err(format!(
+            "--calibration-board needs COLSxROWSxSQUARE, got {value}"
+        ));
+    };
+    Ok(Checkerboard {
+        cols: number(cols)?,
+        rows: number(rows)?,
+        square_size: number(square_size)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Args;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.config, None);
+        assert_eq!(args.scene, "two_cones");
+        assert!(!args.estimate_bounds);
+
+        let args = parse(&[
+            "config.toml",
+            "--scene",
+            "cone",
+            "--calibration-board",
+            "9x6x0.025",
+            "--fisheye",
+            "3.0",
+            "--estimate-bounds",
+            "--bounds-padding",
+            "0.2",
+            "--refine-poses",
+            "--continue-with-new-views",
+            "4",
+            "--explain",
+            "1,2,3",
+            "--explain",
+            "0,0,0",
+        ])
+        .unwrap();
+        assert_eq!(args.config.as_deref(), Some("config.toml"));
+        assert_eq!(args.scene, "cone");
+        let board = args.calibration_board.unwrap();
+        assert_eq!((board.cols, board.rows, board.square_size), (9, 6, 0.025));
+        assert_eq!(args.fisheye_field_of_view, Some(3.0));
+        assert!(args.estimate_bounds);
+        assert_eq!(args.bounds_padding, 0.2);
+        assert!(args.refine_poses);
+        assert_eq!(args.continue_with_new_views, Some(4));
+        assert_eq!(args.explain, vec![(1, 2, 3), (0, 0, 0)]);
+
+        for invalid in [
+            &["--scene"][..],
+            &["--sceen", "cone"],
+            &["a.toml", "b.toml"],
+            &["--explain", "1,2"],
+            &["--calibration-board", "9x6"],
+            &["--continue-with-new-views", "-1"],
+        ] {
+            assert!(parse(invalid).is_err(), "{invalid:?}");
+        }
+    }
+}
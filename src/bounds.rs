@@ -0,0 +1,341 @@
+use nalgebra::{Vector2, Vector3};
+
+use crate::{
+    camera::{CameraModel, Lens},
+    image::Image,
+};
+
+/// Half-space of points p with normal . p <= offset
+struct HalfSpace {
+    normal: Vector3<f32>,
+    offset: f32,
+}
+
+impl HalfSpace {
+    /// How far the point is outside, negative inside
+    fn distance(&self, point: &Vector3<f32>) -> f32 {
+        self.normal.dot(point) - self.offset
+    }
+}
+
+/// A convex polyhedron, as the polygons of its faces with their vertices in order around them
+struct Polytope {
+    faces: Vec<Vec<Vector3<f32>>>,
+}
+
+impl Polytope {
+    fn cuboid(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+        let corner = |x: bool, y: bool, z: bool| {
+            Vector3::new(
+                if x { max.x } else { min.x },
+                if y { max.y } else { min.y },
+                if z { max.z } else { min.z },
+            )
+        };
+        let mut faces = vec![];
+        for axis in 0..3 {
+            for side in [false, true] {
+                // the corners with this coordinate on this side, in order around the face
+                let face = [(false, false), (true, false), (true, true), (false, true)]
+                    .iter()
+                    .map(|&(a, b)| match axis {
+                        0 => corner(side, a, b),
+                        1 => corner(b, side, a),
+                        _ => corner(a, b, side),
+                    })
+                    .collect();
+                faces.push(face);
+            }
+        }
+        Polytope { faces }
+    }
+
+    /// Cuts away everything outside the half-space, closing the cut with a new face.
+    fn clip(&mut self, half_space: &HalfSpace) {
+        let mut faces = vec![];
+        let mut cut = vec![];
+        for face in &self.faces {
+            let mut clipped = vec![];
+            for (i, a) in face.iter().enumerate() {
+                let b = &face[(i + 1) % face.len()];
+                let (distance_a, distance_b) = (half_space.distance(a), half_space.distance(b));
+                if distance_a <= 0.0 {
+                    clipped.push(*a);
+                }
+                if (distance_a <= 0.0) != (distance_b <= 0.0) {
+                    let point = a + (b - a) * (distance_a / (distance_a - distance_b));
+                    clipped.push(point);
+                    cut.push(point);
+                }
+            }
+            if clipped.len() >= 3 {
+                faces.push(clipped);
+            }
+        }
+        if cut.len() >= 3 {
+            // the two faces along each edge crossing the plane both cut it, order the points
+            // around their middle and drop the doubles
+            let middle = cut.iter().sum::<Vector3<f32>>() / cut.len() as f32;
+            let u = half_space.normal.cross(&Vector3::x());
+            let u = if u.norm() > 0.5 {
+                u
+            } else {
+                half_space.normal.cross(&Vector3::y())
+            };
+            let v = half_space.normal.cross(&u);
+            let angle =
+                |point: &Vector3<f32>| (point - middle).dot(&v).atan2((point - middle).dot(&u));
+            cut.sort_by(|a, b| angle(a).total_cmp(&angle(b)));
+            cut.dedup_by(|a, b| (*a - *b).norm() <= 1e-5 * (1.0 + b.norm()));
+            faces.push(cut);
+        }
+        self.faces = faces;
+    }
+
+    fn bounds(&self) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        self.faces.iter().flatten().fold(None, |bounds, &vertex| {
+            Some(match bounds {
+                Some((min, max)) => (vertex.inf(&min), vertex.sup(&max)),
+                None => (vertex, vertex),
+            })
+        })
+    }
+}
+
+/// Estimates an axis aligned box around the object from where it shows up in each image.
+/// Every view's foreground bounding rectangle, swept out from the camera center, gives a cone
+/// the object has to lie in; the box is the bounds of the intersection of all the cones,
+/// grown by `padding` world units on every side. Cameras see nothing further than their far
+/// distance, so the intersection starts as the box that far around every camera and is clipped
+/// by each cone's sides in turn.
+/// Returns None if no image sees any foreground or the cones don't overlap.
+pub(crate) fn estimate_bounds(
+    images: &[Image],
+    padding: f32,
+) -> Option<(Vector3<f32>, Vector3<f32>)> {
+    let mut half_spaces = vec![];
+    let mut min = Vector3::repeat(f32::MIN);
+    let mut max = Vector3::repeat(f32::MAX);
+    for image in images {
+        let Some(view) = view_half_spaces(image) else {
+            continue;
+        };
+        half_spaces.extend(view);
+        let far = image.camera.far();
+        min = min.sup(&image.camera.pos.add_scalar(-far));
+        max = max.inf(&image.camera.pos.add_scalar(far));
+    }
+    if half_spaces.is_empty() || (0..3).any(|axis| min[axis] > max[axis]) {
+        return None;
+    }
+
+    let mut polytope = Polytope::cuboid(min, max);
+    for half_space in &half_spaces {
+        polytope.clip(half_space);
+    }
+    polytope
+        .bounds()
+        .map(|(min, max)| (min.add_scalar(-padding), max.add_scalar(padding)))
+}
+
+/// The half-spaces bounding the region of space the image's foreground can come from, None if
+/// the image has no foreground.
+/// Sides of the foreground rectangle touching the image border don't bound anything, the object
+/// may continue outside the image, so only the camera's far distance limits those directions.
+fn view_half_spaces(image: &Image) -> Option<Vec<HalfSpace>> {
+    let (min, max) = foreground_rectangle(image)?;
+    let camera = &image.camera;
+    let ray = |x: f32, y: f32| camera.unproject(Vector2::new(x, y)).d.xyz();
+    let pos = camera.pos;
+    let axis = ray(camera.width as f32 / 2.0, camera.height as f32 / 2.0);
+
+    let mut half_spaces = vec![HalfSpace {
+        normal: axis,
+        offset: axis.dot(&pos) + camera.far(),
+    }];
+    // fisheyes wider than 180 degrees see behind themselves, but a radial lens sees furthest off
+    // its axis at the corner furthest from the image center, so checking the corners is enough
+    let corners = [
+        Vector2::new(min.x, min.y),
+        Vector2::new(max.x, min.y),
+        Vector2::new(max.x, max.y),
+        Vector2::new(min.x, max.y),
+    ];
+    let in_front = match camera.lens {
+        Lens::Pinhole => true,
+        Lens::Fisheye { .. } => corners
+            .iter()
+            .all(|corner| ray(corner.x, corner.y).dot(&axis) > 0.0),
+    };
+    if in_front {
+        half_spaces.push(HalfSpace {
+            normal: -axis,
+            offset: -axis.dot(&pos),
+        });
+    }
+
+    let inside = ray((min.x + max.x) / 2.0, (min.y + max.y) / 2.0);
+    // top, right, bottom and left sides, and whether each touches the border
+    let sides = [
+        (
+            Vector2::new(min.x, min.y),
+            Vector2::new(max.x, min.y),
+            min.y <= 0.0,
+        ),
+        (
+            Vector2::new(max.x, min.y),
+            Vector2::new(max.x, max.y),
+            max.x >= camera.width as f32,
+        ),
+        (
+            Vector2::new(max.x, max.y),
+            Vector2::new(min.x, max.y),
+            max.y >= camera.height as f32,
+        ),
+        (
+            Vector2::new(min.x, max.y),
+            Vector2::new(min.x, min.y),
+            min.x <= 0.0,
+        ),
+    ];
+    for (start, end, on_border) in sides {
+        if on_border {
+            continue;
+        }
+        let mut normal = ray(start.x, start.y).cross(&ray(end.x, end.y));
+        if normal.norm() < 1e-6 {
+            continue;
+        }
+        if normal.dot(&inside) > 0.0 {
+            normal = -normal;
+        }
+        normal.normalize_mut();
+        // lenses with distortion bend the sides, only keep the plane if it holds the whole side
+        let holds_side = (0..=16).all(|step| {
+            let point = start.lerp(&end, step as f32 / 16.0);
+            normal.dot(&ray(point.x, point.y)) <= 1e-4
+        });
+        if holds_side {
+            half_spaces.push(HalfSpace {
+                normal,
+                offset: normal.dot(&pos),
+            });
+        }
+    }
+    Some(half_spaces)
+}
+
+/// Continuous image coordinates of the corners of the smallest rectangle around the foreground
+fn foreground_rectangle(image: &Image) -> Option<(Vector2<f32>, Vector2<f32>)> {
+    let mut rectangle: Option<(Vector2<usize>, Vector2<usize>)> = None;
+    for j in 0..image.height {
+        for i in 0..image.width {
+            if image.is_background(i + j * image.width) {
+                continue;
+            }
+            let pixel = Vector2::new(i, j);
+            rectangle = Some(match rectangle {
+                Some((min, max)) => (min.inf(&pixel), max.sup(&pixel)),
+                None => (pixel, pixel),
+            });
+        }
+    }
+    rectangle.map(|(min, max)| (min.cast::<f32>(), max.add_scalar(1).cast::<f32>()))
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::estimate_bounds;
+    use crate::{
+        camera::{Camera, Lens},
+        image::Image,
+        raytracer::{generate_ray, trace_ray},
+        test_scenes::render_with,
+        voxel::VoxelBlock,
+    };
+
+    #[test]
+    fn test_estimate_bounds() {
+        // a small block away from the origin, seen from the front, side and top
+        let object = VoxelBlock::with_center(Vector3::new(1.0, 0.5, -0.5), 1.0, 1);
+        let positions = [
+            Vector3::new(1.0, 0.5, 6.0),
+            Vector3::new(7.0, 0.5, -0.5),
+            Vector3::new(1.0, 6.5, -0.5),
+        ];
+        let images: Vec<Image> = positions
+            .iter()
+            .map(|&pos| {
+                let up = if pos.y > 1.0 {
+                    Vector3::z()
+                } else {
+                    Vector3::y()
+                };
+                let target = object.center;
+                let camera = Camera::new(64, 64, pos, target, up, 0.6, 0.01, 1000.0);
+                let mut data = vec![0; 64 * 64 * 3];
+                for j in 0..64 {
+                    for i in 0..64 {
+                        if trace_ray(&generate_ray(i, j, &camera), &object, 0).is_some() {
+                            let index = (i + j * 64) * 3;
+                            data[index..index + 3].copy_from_slice(&[200, 100, 50]);
+                        }
+                    }
+                }
+                Image::new(format!("{pos}"), data, camera)
+            })
+            .collect();
+
+        let (min, max) = estimate_bounds(&images, 0.0).unwrap();
+        let (object_min, object_max) = object.bounds();
+        // contains the object, and is at most a few pixels looser
+        for axis in 0..3 {
+            assert!(
+                min[axis] <= object_min[axis] + 1e-3,
+                "{min} vs {object_min}"
+            );
+            assert!(
+                max[axis] >= object_max[axis] - 1e-3,
+                "{max} vs {object_max}"
+            );
+            assert!(object_min[axis] - min[axis] < 0.2, "{min} vs {object_min}");
+            assert!(max[axis] - object_max[axis] < 0.2, "{max} vs {object_max}");
+        }
+
+        let (padded_min, padded_max) = estimate_bounds(&images, 0.5).unwrap();
+        assert!((padded_min - min.add_scalar(-0.5)).norm() < 1e-4);
+        assert!((padded_max - max.add_scalar(0.5)).norm() < 1e-4);
+
+        let blank = Image::new("blank".to_owned(), vec![0; 64 * 64 * 3], images[0].camera);
+        assert!(estimate_bounds(&[blank], 0.0).is_none());
+
+        // a fisheye wider than 180 degrees sees the object partly behind itself
+        let pos = Vector3::new(-1.0, 0.5, -0.5);
+        let camera = Camera::new(
+            64,
+            64,
+            pos,
+            pos - Vector3::z(),
+            Vector3::y(),
+            0.6,
+            0.01,
+            1000.0,
+        )
+        .with_lens(Lens::equidistant(220.0_f32.to_radians(), 64));
+        let mut images = images;
+        images.push(render_with(&object, camera, |_| [200, 100, 50]));
+        let (min, max) = estimate_bounds(&images, 0.0).unwrap();
+        for axis in 0..3 {
+            assert!(
+                min[axis] <= object_min[axis] + 1e-3,
+                "{min} vs {object_min}"
+            );
+            assert!(
+                max[axis] >= object_max[axis] - 1e-3,
+                "{max} vs {object_max}"
+            );
+        }
+    }
+}
@@ -0,0 +1,1192 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use image::{image_dimensions, open};
+use nalgebra::{
+    DMatrix, DVector, Isometry3, Matrix2, Matrix3, Matrix4, Point2, Point3, Rotation3, SMatrix,
+    Translation3, UnitQuaternion, Vector2, Vector3, Vector6,
+};
+
+use crate::{camera::Camera, image::Image};
+
+// radius (in pixels) of the ring sampled around a saddle point to check it looks like a checker corner
+const RING_RADIUS: f64 = 4.0;
+// half size of the window used for sub-pixel corner refinement
+const REFINE_RADIUS: i64 = 4;
+
+/// Layout of a printed checkerboard, counted in inner corners.
+/// The board frame has its origin at the first inner corner, x along `cols`, y along `rows`
+/// and z pointing out of the printed side. The square between corners (0,0) and (1,1) is dark.
+/// Boards where `cols + rows` is odd have an unambiguous orientation.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Checkerboard {
+    pub(crate) cols: usize,
+    pub(crate) rows: usize,
+    // side length of a square, in world units
+    pub(crate) square_size: f64,
+}
+
+impl Checkerboard {
+    /// positions of the inner corners on the board plane, row by row
+    pub(crate) fn points(&self) -> Vec<Point3<f64>> {
+        let mut points = Vec::with_capacity(self.cols * self.rows);
+        for j in 0..self.rows {
+            for i in 0..self.cols {
+                points.push(Point3::new(
+                    i as f64 * self.square_size,
+                    j as f64 * self.square_size,
+                    0.0,
+                ));
+            }
+        }
+        points
+    }
+}
+
+/// Pinhole intrinsics in pixels, with pixel centers at integer coordinates.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Intrinsics {
+    pub(crate) fx: f64,
+    pub(crate) fy: f64,
+    pub(crate) cx: f64,
+    pub(crate) cy: f64,
+}
+
+/// Radial distortion coefficients applied to normalized image coordinates.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Distortion {
+    pub(crate) k1: f64,
+    pub(crate) k2: f64,
+}
+
+#[derive(Debug)]
+pub(crate) enum CalibrationError {
+    // the file, and why it couldn't be read
+    Unreadable(String, String),
+    // the file, its size and the size of the first image
+    SizeMismatch(String, (usize, usize), (usize, usize)),
+    BoardNotFound(String),
+    NotEnoughViews(usize),
+    Degenerate,
+    // the images given, and the views calibrated
+    ViewCountMismatch(usize, usize),
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrationError::Unreadable(file, error) => write!(f, "can't read {file}: {error}"),
+            CalibrationError::SizeMismatch(
+                file,
+                (width, height),
+                (expected_width, expected_height),
+            ) => {
+                write!(
+                    f,
+                    "{file} is {width}x{height}, not {expected_width}x{expected_height}"
+                )
+            }
+            CalibrationError::BoardNotFound(file) => write!(f, "no checkerboard found in {file}"),
+            CalibrationError::NotEnoughViews(views) => {
+                write!(f, "{views} views, calibration needs at least 2")
+            }
+            CalibrationError::Degenerate => write!(f, "the views don't constrain the camera"),
+            CalibrationError::ViewCountMismatch(images, views) => {
+                write!(f, "{images} images for {views} calibrated views")
+            }
+        }
+    }
+}
+
+pub(crate) struct Calibration {
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) intrinsics: Intrinsics,
+    pub(crate) distortion: Distortion,
+    // board-to-camera transform for each view, camera looking down +z with y down
+    pub(crate) poses: Vec<Isometry3<f64>>,
+    // root mean square reprojection error in pixels
+    pub(crate) rms_error: f64,
+}
+
+/// Detects the checkerboard in every image and calibrates the camera that took them.
+/// The images all have to be the size of the first.
+pub(crate) fn calibrate_from_files(
+    file_paths: &[String],
+    board: &Checkerboard,
+) -> Result<Calibration, CalibrationError> {
+    let mut size = None;
+    for file_path in file_paths {
+        let (width, height) = image_dimensions(file_path)
+            .map_err(|error| CalibrationError::Unreadable(file_path.clone(), error.to_string()))?;
+        let found = (width as usize, height as usize);
+        match size {
+            Some(size) if size != found => {
+                return Err(CalibrationError::SizeMismatch(
+                    file_path.clone(),
+                    found,
+                    size,
+                ))
+            }
+            _ => size = Some(found),
+        }
+    }
+    let (width, height) = size.unwrap_or_default();
+
+    let mut corners = vec![];
+    for file_path in file_paths {
+        let data = load_rgb(file_path)?;
+        match find_corners(&data, width, height, board) {
+            Some(found) => corners.push(found),
+            None => return Err(CalibrationError::BoardNotFound(file_path.clone())),
+        }
+    }
+    calibrate(&corners, board, width, height)
+}
+
+/// The rgb data of an image file
+fn load_rgb(file_path: &str) -> Result<Vec<u8>, CalibrationError> {
+    let image = open(file_path)
+        .map_err(|error| CalibrationError::Unreadable(file_path.to_owned(), error.to_string()))?;
+    Ok(image.into_rgb8().into_vec())
+}
+
+/// Zhang's method: closed form intrinsics and poses from the board homographies,
+/// then joint refinement of intrinsics, distortion and poses by Levenberg-Marquardt.
+/// `corners` holds the detected inner corners of each view in the order of `Checkerboard::points`.
+pub(crate) fn calibrate(
+    corners: &[Vec<Point2<f64>>],
+    board: &Checkerboard,
+    width: usize,
+    height: usize,
+) -> Result<Calibration, CalibrationError> {
+    // the zero skew constraint makes two views the minimum
+    if corners.len() < 2 {
+        return Err(CalibrationError::NotEnoughViews(corners.len()));
+    }
+    let object_points = board.points();
+    let plane_points: Vec<Point2<f64>> = object_points.iter().map(|p| p.xy()).collect();
+
+    let mut homographies = vec![];
+    for view in corners {
+        homographies
+            .push(find_homography(&plane_points, view).ok_or(CalibrationError::Degenerate)?);
+    }
+
+    let intrinsics = initial_intrinsics(&homographies, width, height)?;
+    let poses: Vec<Isometry3<f64>> = homographies
+        .iter()
+        .map(|h| pose_from_homography(h, &intrinsics))
+        .collect::<Option<_>>()
+        .ok_or(CalibrationError::Degenerate)?;
+    let distortion = initial_distortion(&intrinsics, &poses, &object_points, corners);
+
+    // pack everything into one parameter vector for the nonlinear refinement
+    let mut params = DVector::zeros(6 + 6 * poses.len());
+    params[0] = intrinsics.fx;
+    params[1] = intrinsics.fy;
+    params[2] = intrinsics.cx;
+    params[3] = intrinsics.cy;
+    params[4] = distortion.k1;
+    params[5] = distortion.k2;
+    for (view, pose) in poses.iter().enumerate() {
+        let rotation = pose.rotation.scaled_axis();
+        let translation = pose.translation.vector;
+        for k in 0..3 {
+            params[6 + 6 * view + k] = rotation[k];
+            params[6 + 6 * view + 3 + k] = translation[k];
+        }
+    }
+
+    let residuals = |params: &DVector<f64>| {
+        let (intrinsics, distortion, poses) = unpack(params, corners.len());
+        let mut residuals = DVector::zeros(2 * object_points.len() * corners.len());
+        for (view, observed) in corners.iter().enumerate() {
+            for (k, (point, seen)) in object_points.iter().zip(observed).enumerate() {
+                let projected = project_point(&intrinsics, &distortion, &poses[view], point);
+                let row = 2 * (view * object_points.len() + k);
+                residuals[row] = projected.x - seen.x;
+                residuals[row + 1] = projected.y - seen.y;
+            }
+        }
+        residuals
+    };
+    let params = levenberg_marquardt(residuals, params, 100);
+    let rms_error =
+        (residuals(&params).norm_squared() / (object_points.len() * corners.len()) as f64).sqrt();
+    let (intrinsics, distortion, poses) = unpack(&params, corners.len());
+
+    Ok(Calibration {
+        width,
+        height,
+        intrinsics,
+        distortion,
+        poses,
+        rms_error,
+    })
+}
+
+fn unpack(params: &DVector<f64>, views: usize) -> (Intrinsics, Distortion, Vec<Isometry3<f64>>) {
+    let intrinsics = Intrinsics {
+        fx: params[0],
+        fy: params[1],
+        cx: params[2],
+        cy: params[3],
+    };
+    let distortion = Distortion {
+        k1: params[4],
+        k2: params[5],
+    };
+    let poses = (0..views)
+        .map(|view| {
+            let offset = 6 + 6 * view;
+            let rotation = Vector3::new(params[offset], params[offset + 1], params[offset + 2]);
+            let translation =
+                Vector3::new(params[offset + 3], params[offset + 4], params[offset + 5]);
+            Isometry3::from_parts(
+                Translation3::from(translation),
+                UnitQuaternion::from_scaled_axis(rotation),
+            )
+        })
+        .collect();
+    (intrinsics, distortion, poses)
+}
+
+/// projects a board point into the (distorted) image of a view
+fn project_point(
+    intrinsics: &Intrinsics,
+    distortion: &Distortion,
+    pose: &Isometry3<f64>,
+    point: &Point3<f64>,
+) -> Point2<f64> {
+    let p = pose * point;
+    let x = p.x / p.z;
+    let y = p.y / p.z;
+    let r2 = x * x + y * y;
+    let radial = 1.0 + distortion.k1 * r2 + distortion.k2 * r2 * r2;
+    Point2::new(
+        intrinsics.fx * x * radial + intrinsics.cx,
+        intrinsics.fy * y * radial + intrinsics.cy,
+    )
+}
+
+impl Calibration {
+    /// Height angle of the ideal pinhole camera the undistorted images are resampled into.
+    /// It keeps the calibrated vertical focal length, with square pixels and a centered principal point.
+    pub(crate) fn height_angle(&self) -> f32 {
+        (2.0 * f64::atan(self.height as f64 / (2.0 * self.intrinsics.fy))) as f32
+    }
+
+    /// The camera of one view, in board coordinates.
+    pub(crate) fn camera(&self, view: usize) -> Camera {
+        // flip from the calibration camera frame (y down, looking down +z) to ours (y up, looking down -z)
+        let flip = Matrix4::from_diagonal(&nalgebra::Vector4::new(1.0, -1.0, -1.0, 1.0));
+        let view_matrix = flip * self.poses[view].to_homogeneous();
+        Camera::from_view_matrix(
+            self.width,
+            self.height,
+            view_matrix.cast::<f32>(),
+            self.height_angle(),
+            0.01,
+            1000.0,
+        )
+    }
+
+    /// Resamples rgb data taken with the calibrated lens into the ideal pinhole camera of `camera`.
+    pub(crate) fn undistort(&self, data: &[u8]) -> Vec<u8> {
+        let f = self.intrinsics.fy;
+        let mut undistorted = vec![0; self.width * self.height * 3];
+        for j in 0..self.height {
+            for i in 0..self.width {
+                let x = (i as f64 + 0.5 - self.width as f64 / 2.0) / f;
+                let y = (j as f64 + 0.5 - self.height as f64 / 2.0) / f;
+                let r2 = x * x + y * y;
+                let radial = 1.0 + self.distortion.k1 * r2 + self.distortion.k2 * r2 * r2;
+                let u = self.intrinsics.fx * x * radial + self.intrinsics.cx;
+                let v = self.intrinsics.fy * y * radial + self.intrinsics.cy;
+                for channel in 0..3 {
+                    let value = sample_rgb(data, self.width, self.height, u, v, channel);
+                    undistorted[(i + j * self.width) * 3 + channel] = value.round() as u8;
+                }
+            }
+        }
+        undistorted
+    }
+
+    /// Loads the images of the calibrated views, undistorted and paired with their cameras,
+    /// ready to be passed to `carver::carve`.
+    /// `file_paths` are in the same order as the views used for calibration.
+    pub(crate) fn images(&self, file_paths: &[String]) -> Result<Vec<Image>, CalibrationError> {
+        if file_paths.len() != self.poses.len() {
+            return Err(CalibrationError::ViewCountMismatch(
+                file_paths.len(),
+                self.poses.len(),
+            ));
+        }
+        file_paths
+            .iter()
+            .enumerate()
+            .map(|(view, file_path)| {
+                let data = load_rgb(file_path)?;
+                if data.len() != self.width * self.height * 3 {
+                    let (width, height) = image_dimensions(file_path).unwrap_or_default();
+                    return Err(CalibrationError::SizeMismatch(
+                        file_path.clone(),
+                        (width as usize, height as usize),
+                        (self.width, self.height),
+                    ));
+                }
+                Ok(Image::new(
+                    file_path.clone(),
+                    self.undistort(&data),
+                    self.camera(view),
+                ))
+            })
+            .collect()
+    }
+}
+
+/// bilinear sample of one channel of rgb data, clamped at the borders
+fn sample_rgb(data: &[u8], width: usize, height: usize, x: f64, y: f64, channel: usize) -> f64 {
+    let x = x.clamp(0.0, (width - 1) as f64);
+    let y = y.clamp(0.0, (height - 1) as f64);
+    let x0 = x.floor() as usize;
+    let y0 = y.floor() as usize;
+    let x1 = (x0 + 1).min(width - 1);
+    let y1 = (y0 + 1).min(height - 1);
+    let fx = x - x0 as f64;
+    let fy = y - y0 as f64;
+    let at = |x: usize, y: usize| data[(x + y * width) * 3 + channel] as f64;
+    (1.0 - fy) * ((1.0 - fx) * at(x0, y0) + fx * at(x1, y0))
+        + fy * ((1.0 - fx) * at(x0, y1) + fx * at(x1, y1))
+}
+
+/// Normalized DLT estimate of the homography taking `src` to `dst`.
+fn find_homography(src: &[Point2<f64>], dst: &[Point2<f64>]) -> Option<Matrix3<f64>> {
+    let src_transform = normalizing_transform(src);
+    let dst_transform = normalizing_transform(dst);
+
+    let mut ata = SMatrix::<f64, 9, 9>::zeros();
+    for (s, d) in src.iter().zip(dst) {
+        let s = src_transform * s.to_homogeneous();
+        let d = dst_transform * d.to_homogeneous();
+        let (x, y, u, v) = (s.x, s.y, d.x, d.y);
+        let rows = [
+            [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, -u],
+            [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, -v],
+        ];
+        for row in rows {
+            let row = SMatrix::<f64, 9, 1>::from_row_slice(&row);
+            ata += row * row.transpose();
+        }
+    }
+    let h = smallest_eigenvector(ata);
+    let normalized = Matrix3::from_row_slice(h.as_slice());
+    let homography = dst_transform.try_inverse()? * normalized * src_transform;
+    if homography[(2, 2)].abs() < f64::EPSILON {
+        return None;
+    }
+    Some(homography / homography[(2, 2)])
+}
+
+/// similarity moving the points' centroid to the origin with a mean distance of sqrt(2)
+fn normalizing_transform(points: &[Point2<f64>]) -> Matrix3<f64> {
+    let count = points.len() as f64;
+    let centroid = points
+        .iter()
+        .fold(Vector2::zeros(), |acc, p| acc + p.coords)
+        / count;
+    let mean_distance = points
+        .iter()
+        .map(|p| (p.coords - centroid).norm())
+        .sum::<f64>()
+        / count;
+    let scale = std::f64::consts::SQRT_2 / mean_distance.max(f64::EPSILON);
+    Matrix3::new(
+        scale,
+        0.0,
+        -scale * centroid.x,
+        0.0,
+        scale,
+        -scale * centroid.y,
+        0.0,
+        0.0,
+        1.0,
+    )
+}
+
+fn smallest_eigenvector<const N: usize>(matrix: SMatrix<f64, N, N>) -> DVector<f64> {
+    let eigen = DMatrix::from_column_slice(N, N, matrix.as_slice()).symmetric_eigen();
+    let smallest = eigen.eigenvalues.imin();
+    eigen.eigenvectors.column(smallest).into_owned()
+}
+
+/// Closed form intrinsics from the image of the absolute conic, assuming zero skew.
+fn initial_intrinsics(
+    homographies: &[Matrix3<f64>],
+    width: usize,
+    height: usize,
+) -> Result<Intrinsics, CalibrationError> {
+    // work in normalized pixel coordinates to keep the system well conditioned
+    let scale = 2.0 / (width + height) as f64;
+    let normalize = Matrix3::new(
+        scale,
+        0.0,
+        -scale * width as f64 / 2.0,
+        0.0,
+        scale,
+        -scale * height as f64 / 2.0,
+        0.0,
+        0.0,
+        1.0,
+    );
+
+    let v = |h: &Matrix3<f64>, i: usize, j: usize| {
+        Vector6::new(
+            h[(0, i)] * h[(0, j)],
+            h[(0, i)] * h[(1, j)] + h[(1, i)] * h[(0, j)],
+            h[(1, i)] * h[(1, j)],
+            h[(2, i)] * h[(0, j)] + h[(0, i)] * h[(2, j)],
+            h[(2, i)] * h[(1, j)] + h[(1, i)] * h[(2, j)],
+            h[(2, i)] * h[(2, j)],
+        )
+    };
+
+    let mut vtv = SMatrix::<f64, 6, 6>::zeros();
+    for homography in homographies {
+        let h = normalize * homography;
+        let h = h / h.norm();
+        let v12 = v(&h, 0, 1);
+        let v11_22 = v(&h, 0, 0) - v(&h, 1, 1);
+        vtv += v12 * v12.transpose() + v11_22 * v11_22.transpose();
+    }
+    // zero skew
+    let skew = Vector6::new(0.0, 1.0, 0.0, 0.0, 0.0, 0.0);
+    vtv += skew * skew.transpose();
+
+    let b = smallest_eigenvector(vtv);
+    let (b11, b12, b22, b13, b23, b33) = (b[0], b[1], b[2], b[3], b[4], b[5]);
+    let denominator = b11 * b22 - b12 * b12;
+    let v0 = (b12 * b13 - b11 * b23) / denominator;
+    let lambda = b33 - (b13 * b13 + v0 * (b12 * b13 - b11 * b23)) / b11;
+    let alpha_squared = lambda / b11;
+    let beta_squared = lambda * b11 / denominator;
+    if !(alpha_squared > 0.0 && beta_squared > 0.0) {
+        return Err(CalibrationError::Degenerate);
+    }
+    let alpha = alpha_squared.sqrt();
+    let beta = beta_squared.sqrt();
+    let u0 = -b13 * alpha * alpha / lambda;
+
+    // undo the normalization
+    Ok(Intrinsics {
+        fx: alpha / scale,
+        fy: beta / scale,
+        cx: u0 / scale + width as f64 / 2.0,
+        cy: v0 / scale + height as f64 / 2.0,
+    })
+}
+
+/// Board-to-camera pose from a board homography and known intrinsics.
+fn pose_from_homography(
+    homography: &Matrix3<f64>,
+    intrinsics: &Intrinsics,
+) -> Option<Isometry3<f64>> {
+    let k = Matrix3::new(
+        intrinsics.fx,
+        0.0,
+        intrinsics.cx,
+        0.0,
+        intrinsics.fy,
+        intrinsics.cy,
+        0.0,
+        0.0,
+        1.0,
+    );
+    let k_inv = k.try_inverse()?;
+    let mut r1 = k_inv * homography.column(0);
+    let mut r2 = k_inv * homography.column(1);
+    let mut t = k_inv * homography.column(2);
+    let lambda = 1.0 / r1.norm();
+    r1 *= lambda;
+    r2 *= lambda;
+    t *= lambda;
+    // the board has to be in front of the camera
+    if t.z < 0.0 {
+        r1 = -r1;
+        r2 = -r2;
+        t = -t;
+    }
+    let r3 = r1.cross(&r2);
+    let approximate = Matrix3::from_columns(&[r1, r2, r3]);
+
+    // closest proper rotation
+    let svd = approximate.svd(true, true);
+    let (u, v_t) = (svd.u?, svd.v_t?);
+    let mut rotation = u * v_t;
+    if rotation.determinant() < 0.0 {
+        rotation = -rotation;
+    }
+    let rotation = Rotation3::from_matrix_unchecked(rotation);
+    Some(Isometry3::from_parts(
+        Translation3::from(t),
+        UnitQuaternion::from_rotation_matrix(&rotation),
+    ))
+}
+
+/// Linear least squares estimate of the radial distortion given the other parameters.
+fn initial_distortion(
+    intrinsics: &Intrinsics,
+    poses: &[Isometry3<f64>],
+    object_points: &[Point3<f64>],
+    corners: &[Vec<Point2<f64>>],
+) -> Distortion {
+    let mut ata = Matrix2::zeros();
+    let mut atb = Vector2::zeros();
+    for (pose, observed) in poses.iter().zip(corners) {
+        for (point, seen) in object_points.iter().zip(observed) {
+            let ideal = project_point(intrinsics, &Distortion::default(), pose, point);
+            let p = pose * point;
+            let r2 = (p.x * p.x + p.y * p.y) / (p.z * p.z);
+            let du = ideal.x - intrinsics.cx;
+            let dv = ideal.y - intrinsics.cy;
+            let rows = [
+                (Vector2::new(du * r2, du * r2 * r2), seen.x - ideal.x),
+                (Vector2::new(dv * r2, dv * r2 * r2), seen.y - ideal.y),
+            ];
+            for (a, b) in rows {
+                ata += a * a.transpose();
+                atb += a * b;
+            }
+        }
+    }
+    match ata.try_inverse() {
+        Some(inverse) => {
+            let k = inverse * atb;
+            Distortion { k1: k[0], k2: k[1] }
+        }
+        None => Distortion::default(),
+    }
+}
+
+/// Minimizes the squared norm of `residuals` starting from `params`, using a central difference Jacobian.
+fn levenberg_marquardt<F: Fn(&DVector<f64>) -> DVector<f64>>(
+    residuals: F,
+    mut params: DVector<f64>,
+    max_iterations: usize,
+) -> DVector<f64> {
+    let mut damping = 1e-3;
+    let mut current = residuals(&params);
+    let mut cost = current.norm_squared();
+
+    for _ in 0..max_iterations {
+        let mut jacobian = DMatrix::zeros(current.len(), params.len());
+        for k in 0..params.len() {
+            let step = 1e-6 * params[k].abs().max(1.0);
+            let mut forward = params.clone();
+            forward[k] += step;
+            let mut backward = params.clone();
+            backward[k] -= step;
+            let derivative = (residuals(&forward) - residuals(&backward)) / (2.0 * step);
+            jacobian.set_column(k, &derivative);
+        }
+        let jtj = jacobian.transpose() * &jacobian;
+        let gradient = jacobian.transpose() * &current;
+
+        let mut improved = false;
+        while damping < 1e12 {
+            let mut system = jtj.clone();
+            for k in 0..params.len() {
+                system[(k, k)] += damping * jtj[(k, k)].max(f64::EPSILON);
+            }
+            let Some(cholesky) = system.cholesky() else {
+                damping *= 10.0;
+                continue;
+            };
+            let delta = cholesky.solve(&-&gradient);
+            let candidate = &params + &delta;
+            let candidate_residuals = residuals(&candidate);
+            let candidate_cost = candidate_residuals.norm_squared();
+            if candidate_cost < cost {
+                let converged = cost - candidate_cost < 1e-12 * cost.max(f64::EPSILON)
+                    || delta.norm() < 1e-12 * params.norm();
+                params = candidate;
+                current = candidate_residuals;
+                cost = candidate_cost;
+                damping = (damping / 10.0).max(1e-12);
+                improved = !converged;
+                break;
+            }
+            damping *= 10.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+    params
+}
+
+/// Finds the inner corners of the checkerboard with sub-pixel accuracy,
+/// in the order of `Checkerboard::points`.
+pub(crate) fn find_corners(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    board: &Checkerboard,
+) -> Option<Vec<Point2<f64>>> {
+    let gray = Grayscale::from_rgb(data, width, height);
+    // detection wants a smooth saddle response, refinement wants sharp edges
+    let smooth = gray.blur(1.5);
+    let sharp = gray.blur(0.7);
+    let candidates = saddle_points(&smooth);
+    let corners = organize_grid(&candidates, board, &smooth)?;
+    Some(
+        corners
+            .into_iter()
+            .map(|corner| refine_corner(&sharp, corner))
+            .collect(),
+    )
+}
+
+struct Grayscale {
+    data: Vec<f64>,
+    width: usize,
+    height: usize,
+}
+
+impl Grayscale {
+    fn from_rgb(data: &[u8], width: usize, height: usize) -> Self {
+        let data = data
+            .chunks_exact(3)
+            .map(|rgb| {
+                (0.299 * rgb[0] as f64 + 0.587 * rgb[1] as f64 + 0.114 * rgb[2] as f64) / 255.0
+            })
+            .collect();
+        Grayscale {
+            data,
+            width,
+            height,
+        }
+    }
+
+    /// value at a pixel, clamped to the image bounds
+    fn get(&self, x: i64, y: i64) -> f64 {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let y = y.clamp(0, self.height as i64 - 1) as usize;
+        self.data[x + y * self.width]
+    }
+
+    fn sample(&self, x: f64, y: f64) -> f64 {
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        (1.0 - fy) * ((1.0 - fx) * self.get(x0, y0) + fx * self.get(x0 + 1, y0))
+            + fy * ((1.0 - fx) * self.get(x0, y0 + 1) + fx * self.get(x0 + 1, y0 + 1))
+    }
+
+    /// separable gaussian blur
+    fn blur(&self, sigma: f64) -> Self {
+        let radius = (3.0 * sigma).ceil() as i64;
+        let kernel: Vec<f64> = (-radius..=radius)
+            .map(|k| (-((k * k) as f64) / (2.0 * sigma * sigma)).exp())
+            .collect();
+        let total: f64 = kernel.iter().sum();
+
+        let mut horizontal = vec![0.0; self.data.len()];
+        for y in 0..self.height as i64 {
+            for x in 0..self.width as i64 {
+                let value: f64 = (-radius..=radius)
+                    .map(|k| kernel[(k + radius) as usize] * self.get(x + k, y))
+                    .sum();
+                horizontal[x as usize + y as usize * self.width] = value / total;
+            }
+        }
+        let horizontal = Grayscale {
+            data: horizontal,
+            width: self.width,
+            height: self.height,
+        };
+
+        let mut data = vec![0.0; self.data.len()];
+        for y in 0..self.height as i64 {
+            for x in 0..self.width as i64 {
+                let value: f64 = (-radius..=radius)
+                    .map(|k| kernel[(k + radius) as usize] * horizontal.get(x, y + k))
+                    .sum();
+                data[x as usize + y as usize * self.width] = value / total;
+            }
+        }
+        Grayscale {
+            data,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+/// Local maxima of the Hessian saddle response that look like the meeting point of four squares.
+fn saddle_points(gray: &Grayscale) -> Vec<Point2<f64>> {
+    let (width, height) = (gray.width as i64, gray.height as i64);
+    let mut response = vec![0.0; gray.data.len()];
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let ixx = gray.get(x + 1, y) - 2.0 * gray.get(x, y) + gray.get(x - 1, y);
+            let iyy = gray.get(x, y + 1) - 2.0 * gray.get(x, y) + gray.get(x, y - 1);
+            let ixy = (gray.get(x + 1, y + 1) - gray.get(x + 1, y - 1) - gray.get(x - 1, y + 1)
+                + gray.get(x - 1, y - 1))
+                / 4.0;
+            response[(x + y * width) as usize] = ixy * ixy - ixx * iyy;
+        }
+    }
+    let max_response = response.iter().cloned().fold(0.0, f64::max);
+    if max_response <= 0.0 {
+        return vec![];
+    }
+    let threshold = 0.05 * max_response;
+
+    let margin = RING_RADIUS.ceil() as i64 + 1;
+    let suppression = 3;
+    let mut points = vec![];
+    for y in margin..height - margin {
+        for x in margin..width - margin {
+            let value = response[(x + y * width) as usize];
+            if value < threshold {
+                continue;
+            }
+            let mut is_max = true;
+            'window: for dy in -suppression..=suppression {
+                for dx in -suppression..=suppression {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if (dx, dy) == (0, 0) || nx < 0 || ny < 0 || nx >= width || ny >= height {
+                        continue;
+                    }
+                    let other = response[(nx + ny * width) as usize];
+                    // break ties towards the first pixel in scan order
+                    if other > value || (other == value && (dy, dx) < (0, 0)) {
+                        is_max = false;
+                        break 'window;
+                    }
+                }
+            }
+            let point = Point2::new(x as f64, y as f64);
+            if is_max && looks_like_checker_corner(gray, &point) {
+                points.push(point);
+            }
+        }
+    }
+    points
+}
+
+/// A checker corner is surrounded by alternating dark and light sectors,
+/// so a ring around it crosses between them exactly four times.
+fn looks_like_checker_corner(gray: &Grayscale, point: &Point2<f64>) -> bool {
+    let samples = 32;
+    let ring: Vec<f64> = (0..samples)
+        .map(|k| {
+            let angle = k as f64 / samples as f64 * std::f64::consts::TAU;
+            gray.sample(
+                point.x + RING_RADIUS * angle.cos(),
+                point.y + RING_RADIUS * angle.sin(),
+            )
+        })
+        .collect();
+    let min = ring.iter().cloned().fold(f64::MAX, f64::min);
+    let max = ring.iter().cloned().fold(f64::MIN, f64::max);
+    if max - min < 0.2 {
+        return false;
+    }
+    let mid = (min + max) / 2.0;
+    let crossings = (0..samples)
+        .filter(|&k| (ring[k] > mid) != (ring[(k + 1) % samples] > mid))
+        .count();
+    crossings == 4
+}
+
+/// Grows a lattice from the candidate nearest the middle and orders it like `Checkerboard::points`.
+fn organize_grid(
+    candidates: &[Point2<f64>],
+    board: &Checkerboard,
+    gray: &Grayscale,
+) -> Option<Vec<Point2<f64>>> {
+    if candidates.len() < board.cols * board.rows {
+        return None;
+    }
+    let count = candidates.len() as f64;
+    let centroid = candidates
+        .iter()
+        .fold(Vector2::zeros(), |acc, p| acc + p.coords)
+        / count;
+    let seed = (0..candidates.len()).min_by(|&a, &b| {
+        (candidates[a].coords - centroid)
+            .norm()
+            .total_cmp(&(candidates[b].coords - centroid).norm())
+    })?;
+
+    // initial lattice directions from the nearest neighbors of the seed
+    let mut neighbors: Vec<usize> = (0..candidates.len()).filter(|&k| k != seed).collect();
+    neighbors.sort_by(|&a, &b| {
+        (candidates[a] - candidates[seed])
+            .norm()
+            .total_cmp(&(candidates[b] - candidates[seed]).norm())
+    });
+    // a lone candidate has no lattice to grow
+    let (&nearest, others) = neighbors.split_first()?;
+    let u = candidates[nearest] - candidates[seed];
+    let v = others
+        .iter()
+        .map(|&k| candidates[k] - candidates[seed])
+        .find(|d| (d.dot(&u) / (d.norm() * u.norm())).abs() < 0.5)?;
+
+    let mut used = vec![false; candidates.len()];
+    let mut grid: HashMap<(i64, i64), Point2<f64>> = HashMap::new();
+    grid.insert((0, 0), candidates[seed]);
+    used[seed] = true;
+    let mut queue = VecDeque::from([(0, 0)]);
+    while let Some((i, j)) = queue.pop_front() {
+        let here = grid[&(i, j)];
+        for (di, dj) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let next = (i + di, j + dj);
+            if grid.contains_key(&next) {
+                continue;
+            }
+            // extrapolate from the previous corner on the same line, or borrow a neighboring line's step
+            let step = if let Some(previous) = grid.get(&(i - di, j - dj)) {
+                here - previous
+            } else if let Some((a, b)) =
+                [(i + dj, j + di), (i - dj, j - di)]
+                    .iter()
+                    .find_map(|side| {
+                        let a = grid.get(side)?;
+                        let b = grid.get(&(side.0 + di, side.1 + dj))?;
+                        Some((*a, *b))
+                    })
+            {
+                b - a
+            } else if di != 0 {
+                u * di as f64
+            } else {
+                v * dj as f64
+            };
+            let predicted = here + step;
+            let nearest = (0..candidates.len())
+                .filter(|&k| !used[k])
+                .min_by(|&a, &b| {
+                    (candidates[a] - predicted)
+                        .norm()
+                        .total_cmp(&(candidates[b] - predicted).norm())
+                });
+            if let Some(nearest) = nearest {
+                if (candidates[nearest] - predicted).norm() < 0.35 * step.norm() {
+                    used[nearest] = true;
+                    grid.insert(next, candidates[nearest]);
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+
+    let min_i = grid.keys().map(|k| k.0).min()?;
+    let max_i = grid.keys().map(|k| k.0).max()?;
+    let min_j = grid.keys().map(|k| k.1).min()?;
+    let max_j = grid.keys().map(|k| k.1).max()?;
+    let extent_i = (max_i - min_i + 1) as usize;
+    let extent_j = (max_j - min_j + 1) as usize;
+    if grid.len() != extent_i * extent_j {
+        return None;
+    }
+    let at = |a: usize, b: usize| grid[&(min_i + a as i64, min_j + b as i64)];
+
+    // every symmetry of the lattice that gives the board's shape
+    let (cols, rows) = (board.cols, board.rows);
+    let mut orderings: Vec<Vec<Point2<f64>>> = vec![];
+    for flip_a in [false, true] {
+        for flip_b in [false, true] {
+            for transpose in [false, true] {
+                let (size_a, size_b) = if transpose {
+                    (extent_j, extent_i)
+                } else {
+                    (extent_i, extent_j)
+                };
+                if (size_a, size_b) != (cols, rows) {
+                    continue;
+                }
+                let mut points = Vec::with_capacity(cols * rows);
+                for j in 0..rows {
+                    for i in 0..cols {
+                        let a = if flip_a { cols - 1 - i } else { i };
+                        let b = if flip_b { rows - 1 - j } else { j };
+                        points.push(if transpose { at(b, a) } else { at(a, b) });
+                    }
+                }
+                orderings.push(points);
+            }
+        }
+    }
+
+    // keep the board's z axis facing the camera, i.e. board y pointing up in the image,
+    // then pick the orientation whose first square is dark
+    orderings
+        .into_iter()
+        .filter(|points| {
+            let x_axis = points[1] - points[0];
+            let y_axis = points[cols] - points[0];
+            x_axis.perp(&y_axis) < 0.0
+        })
+        .min_by(|a, b| {
+            let first_square = |points: &Vec<Point2<f64>>| {
+                let center = (points[0].coords
+                    + points[1].coords
+                    + points[cols].coords
+                    + points[cols + 1].coords)
+                    / 4.0;
+                gray.sample(center.x, center.y)
+            };
+            first_square(a).total_cmp(&first_square(b))
+        })
+}
+
+/// Iterative sub-pixel refinement: at the true corner every nearby gradient is orthogonal
+/// to the vector from the corner to where it was measured.
+fn refine_corner(gray: &Grayscale, start: Point2<f64>) -> Point2<f64> {
+    let mut corner = start;
+    for _ in 0..20 {
+        let mut a = Matrix2::zeros();
+        let mut b = Vector2::zeros();
+        let (cx, cy) = (corner.x.round() as i64, corner.y.round() as i64);
+        for dy in -REFINE_RADIUS..=REFINE_RADIUS {
+            for dx in -REFINE_RADIUS..=REFINE_RADIUS {
+                let (x, y) = (cx + dx, cy + dy);
+                let gradient = Vector2::new(
+                    (gray.get(x + 1, y) - gray.get(x - 1, y)) / 2.0,
+                    (gray.get(x, y + 1) - gray.get(x, y - 1)) / 2.0,
+                );
+                let outer = gradient * gradient.transpose();
+                a += outer;
+                b += outer * Vector2::new(x as f64, y as f64);
+            }
+        }
+        let Some(inverse) = a.try_inverse() else {
+            return start;
+        };
+        let refined = Point2::from(inverse * b);
+        let moved = (refined - corner).norm();
+        corner = refined;
+        if moved < 1e-3 {
+            break;
+        }
+    }
+    if (corner - start).norm() > REFINE_RADIUS as f64 {
+        start
+    } else {
+        corner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Isometry3, Matrix3, Point2, Point3, Vector3};
+
+    use crate::camera::CameraModel;
+
+    use super::{
+        calibrate, calibrate_from_files, find_corners, organize_grid, project_point,
+        CalibrationError, Checkerboard, Distortion, Grayscale, Intrinsics,
+    };
+
+    const BOARD: Checkerboard = Checkerboard {
+        cols: 7,
+        rows: 6,
+        square_size: 1.0,
+    };
+
+    /// board-to-camera pose of a camera at `eye` looking at the middle of the board
+    fn pose_looking_at_board(eye: Point3<f64>) -> Isometry3<f64> {
+        let target = Point3::new(3.0, 2.5, 0.0);
+        let view = Isometry3::look_at_rh(&eye, &target, &Vector3::y());
+        // flip from looking down -z with y up to looking down +z with y down
+        let flip = Isometry3::rotation(Vector3::new(std::f64::consts::PI, 0.0, 0.0));
+        flip * view
+    }
+
+    fn render_board(
+        intrinsics: &Intrinsics,
+        pose: &Isometry3<f64>,
+        width: usize,
+        height: usize,
+    ) -> Vec<u8> {
+        // the board plane seen through a pinhole is a homography, so invert it once
+        let rotation = pose.rotation.to_rotation_matrix();
+        let k = Matrix3::new(
+            intrinsics.fx,
+            0.0,
+            intrinsics.cx,
+            0.0,
+            intrinsics.fy,
+            intrinsics.cy,
+            0.0,
+            0.0,
+            1.0,
+        );
+        let homography = k * Matrix3::from_columns(&[
+            rotation.matrix().column(0).into_owned(),
+            rotation.matrix().column(1).into_owned(),
+            pose.translation.vector,
+        ]);
+        let h = homography.try_inverse().unwrap();
+        let samples = 8;
+        let mut data = vec![0; width * height * 3];
+        for v in 0..height {
+            for u in 0..width {
+                let mut total = 0.0;
+                for sy in 0..samples {
+                    for sx in 0..samples {
+                        let x = u as f64 - 0.5 + (sx as f64 + 0.5) / samples as f64;
+                        let y = v as f64 - 0.5 + (sy as f64 + 0.5) / samples as f64;
+                        let w = h[(2, 0)] * x + h[(2, 1)] * y + h[(2, 2)];
+                        let board_x = (h[(0, 0)] * x + h[(0, 1)] * y + h[(0, 2)]) / w;
+                        let board_y = (h[(1, 0)] * x + h[(1, 1)] * y + h[(1, 2)]) / w;
+                        let bx = board_x.floor() as i64;
+                        let by = board_y.floor() as i64;
+                        let mut value = 128.0;
+                        if (-1..BOARD.cols as i64).contains(&bx)
+                            && (-1..BOARD.rows as i64).contains(&by)
+                        {
+                            value = if (bx + by) % 2 == 0 { 20.0 } else { 230.0 };
+                        } else if (-2..=BOARD.cols as i64).contains(&bx)
+                            && (-2..=BOARD.rows as i64).contains(&by)
+                        {
+                            value = 230.0;
+                        }
+                        total += value;
+                    }
+                }
+                let value = (total / (samples * samples) as f64).round() as u8;
+                let index = (u + v * width) * 3;
+                data[index..index + 3].copy_from_slice(&[value, value, value]);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_find_corners() {
+        let (width, height) = (320, 240);
+        let intrinsics = Intrinsics {
+            fx: 300.0,
+            fy: 300.0,
+            cx: 159.5,
+            cy: 119.5,
+        };
+        let pose = pose_looking_at_board(Point3::new(5.0, 1.0, 12.0));
+        let data = render_board(&intrinsics, &pose, width, height);
+
+        let corners = find_corners(&data, width, height, &BOARD).unwrap();
+        assert_eq!(corners.len(), BOARD.cols * BOARD.rows);
+        for (point, corner) in BOARD.points().iter().zip(corners) {
+            let expected = project_point(&intrinsics, &Distortion::default(), &pose, point);
+            assert!((expected - corner).norm() < 0.1, "{expected} vs {corner}");
+        }
+
+        // a single corner doesn't give the lattice its directions
+        let board = Checkerboard {
+            cols: 1,
+            rows: 1,
+            square_size: 1.0,
+        };
+        let gray = Grayscale {
+            data: vec![0.0; 16],
+            width: 4,
+            height: 4,
+        };
+        assert!(organize_grid(&[Point2::new(2.0, 2.0)], &board, &gray).is_none());
+    }
+
+    #[test]
+    fn test_calibrate() {
+        let (width, height) = (640, 480);
+        let intrinsics = Intrinsics {
+            fx: 620.0,
+            fy: 600.0,
+            cx: 330.0,
+            cy: 235.0,
+        };
+        let distortion = Distortion { k1: -0.1, k2: 0.02 };
+        let eyes = [
+            Point3::new(3.0, 2.5, 12.0),
+            Point3::new(9.0, 2.5, 10.0),
+            Point3::new(-3.0, 0.0, 11.0),
+            Point3::new(3.0, 8.0, 10.0),
+            Point3::new(6.0, -3.0, 12.0),
+        ];
+        let poses: Vec<_> = eyes.iter().map(|eye| pose_looking_at_board(*eye)).collect();
+        let corners: Vec<Vec<Point2<f64>>> = poses
+            .iter()
+            .map(|pose| {
+                BOARD
+                    .points()
+                    .iter()
+                    .map(|point| project_point(&intrinsics, &distortion, pose, point))
+                    .collect()
+            })
+            .collect();
+
+        let calibration = calibrate(&corners, &BOARD, width, height).unwrap();
+        assert!(calibration.rms_error < 1e-6);
+        assert!((calibration.intrinsics.fx - intrinsics.fx).abs() < 1e-3);
+        assert!((calibration.intrinsics.fy - intrinsics.fy).abs() < 1e-3);
+        assert!((calibration.intrinsics.cx - intrinsics.cx).abs() < 1e-3);
+        assert!((calibration.intrinsics.cy - intrinsics.cy).abs() < 1e-3);
+        assert!((calibration.distortion.k1 - distortion.k1).abs() < 1e-5);
+        assert!((calibration.distortion.k2 - distortion.k2).abs() < 1e-5);
+
+        // the cameras handed to the carver sit where the board was seen from
+        // and project the board into the undistorted image
+        for view in 0..eyes.len() {
+            let camera = calibration.camera(view);
+            let eye = eyes[view].cast::<f32>();
+            assert!((camera.pos - eye.coords).norm() < 1e-3);
+
+            let point = Point3::new(2.0, 3.0, 0.0);
+            let seen = poses[view] * point;
+            let expected_x = width as f64 / 2.0 + calibration.intrinsics.fy * seen.x / seen.z;
+            let expected_y = height as f64 / 2.0 + calibration.intrinsics.fy * seen.y / seen.z;
+
+            let pixel = camera.project(point.coords.cast::<f32>()).unwrap();
+            assert!((pixel.x as f64 - expected_x).abs() < 1e-2);
+            assert!((pixel.y as f64 - expected_y).abs() < 1e-2);
+        }
+
+        assert!(matches!(
+            calibration.images(&[]),
+            Err(CalibrationError::ViewCountMismatch(0, 5))
+        ));
+    }
+
+    #[test]
+    fn test_unusable_files() {
+        let directory = std::env::temp_dir();
+        let path = |name: &str| directory.join(name).to_str().unwrap().to_owned();
+        let (small, large) = (path("calibration_small.png"), path("calibration_large.png"));
+        image::RgbImage::new(32, 24).save(&small).unwrap();
+        image::RgbImage::new(64, 48).save(&large).unwrap();
+
+        let missing = path("calibration_missing.png");
+        assert!(matches!(
+            calibrate_from_files(&[small.clone(), missing.clone()], &BOARD),
+            Err(CalibrationError::Unreadable(file, _)) if file == missing
+        ));
+        // sizes are checked before looking for the board
+        assert!(matches!(
+            calibrate_from_files(&[small.clone(), large.clone()], &BOARD),
+            Err(CalibrationError::SizeMismatch(file, (64, 48), (32, 24))) if file == large
+        ));
+        assert!(matches!(
+            calibrate_from_files(&[small.clone(), small], &BOARD),
+            Err(CalibrationError::BoardNotFound(_))
+        ));
+    }
+}
@@ -1,27 +1,59 @@
-use nalgebra::{Isometry3, Matrix4, Perspective3, Point3, Vector3};
+use std::f32::consts::PI;
+
+use nalgebra::{Isometry3, Matrix3x4, Matrix4, Point3, Vector2, Vector3, Vector4};
+
+use crate::raytracer::Ray;
+
+/// How rays through the camera center map onto the image.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Lens {
+    // perspective projection described by `height_angle` and `image_matrix`
+    Pinhole,
+    // Kannala-Brandt fisheye: a ray at angle theta from the optical axis lands at radius
+    // focal * (theta + k1 theta^3 + k2 theta^5 + k3 theta^7 + k4 theta^9) from the image center.
+    // All zero coefficients give the equidistant model.
+    Fisheye {
+        // pixels per radian near the optical axis
+        focal: f32,
+        coefficients: [f32; 4],
+        // full angle of the cone of rays the lens captures, may exceed 180 degrees
+        field_of_view: f32,
+    },
+}
+
+impl Lens {
+    /// An equidistant fisheye whose image circle spans the height of the image.
+    pub(crate) fn equidistant(field_of_view: f32, height: usize) -> Self {
+        Lens::Fisheye {
+            focal: height as f32 / field_of_view,
+            coefficients: [0.0; 4],
+            field_of_view,
+        }
+    }
+}
 
 #[derive(Clone, Copy)]
 pub(crate) struct Camera {
     pub(crate) width: usize,
     pub(crate) height: usize,
     pub(crate) pos: Vector3<f32>,
-    pub(crate) look: Vector3<f32>,
-    pub(crate) up: Vector3<f32>,
     pub(crate) height_angle: f32,
     pub(crate) view_matrix: Matrix4<f32>,
     pub(crate) inv_view_matrix: Matrix4<f32>,
-    pub(crate) proj_matrix: Matrix4<f32>,
+    // K·[R|t], world points to homogeneous image coordinates with the depth in front as the last
+    pub(crate) image_matrix: Matrix3x4<f32>,
+    pub(crate) lens: Lens,
     near: f32,
     far: f32,
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         width: usize,
         height: usize,
         pos: Vector3<f32>,
         target: Vector3<f32>,
-        look: Vector3<f32>,
         up: Vector3<f32>,
         height_angle: f32,
         near: f32,
@@ -29,10 +61,67 @@ impl Camera {
     ) -> Self {
         let eye = Point3::from(pos);
         let target = Point3::from(target);
-        let view_matrix = Isometry3::look_at_rh(&eye, &target, &Vector3::y());
+        let view_matrix = Isometry3::look_at_rh(&eye, &target, &up);
         let inv_view_matrix = view_matrix.inverse();
+        let view_matrix = view_matrix.to_homogeneous();
+        let proj_matrix = Self::projection_matrix(width, height, height_angle, near, far);
+
+        Camera {
+            width,
+            height,
+            pos,
+            height_angle,
+            view_matrix,
+            inv_view_matrix: inv_view_matrix.to_homogeneous(),
+            image_matrix: Self::image_matrix(width, height, proj_matrix * view_matrix),
+            lens: Lens::Pinhole,
+            near,
+            far,
+        }
+    }
+
+    /// Builds a camera from a rigid world-to-camera transform, e.g. a pose recovered by calibration.
+    /// The camera frame follows the same convention as `new`: looking down -z with y up.
+    pub fn from_view_matrix(
+        width: usize,
+        height: usize,
+        view_matrix: Matrix4<f32>,
+        height_angle: f32,
+        near: f32,
+        far: f32,
+    ) -> Self {
+        let inv_view_matrix = view_matrix
+            .try_inverse()
+            .expect("View matrix must be invertible");
+        let pos = (inv_view_matrix * Vector4::new(0.0, 0.0, 0.0, 1.0)).xyz();
+        let proj_matrix = Self::projection_matrix(width, height, height_angle, near, far);
+
+        Camera {
+            width,
+            height,
+            pos,
+            height_angle,
+            view_matrix,
+            inv_view_matrix,
+            image_matrix: Self::image_matrix(width, height, proj_matrix * view_matrix),
+            lens: Lens::Pinhole,
+            near,
+            far,
+        }
+    }
 
-        // projection matrix
+    fn projection_matrix(
+        width: usize,
+        height: usize,
+        height_angle: f32,
+        near: f32,
+        far: f32,
+    ) -> Matrix4<f32> {
+        // outside of this the tangent flips sign and the image comes out upside down
+        assert!(
+            height_angle > 0.0 && height_angle < PI,
+            "Height angle must be in radians between 0 and pi, got {height_angle}"
+        );
         let width_angle =
             f32::atan((width as f32 / height as f32) * f32::tan(height_angle / 2.0)) * 2.0;
         let scaling = Matrix4::new(
@@ -65,25 +154,342 @@ impl Camera {
         remapping[10] = -2.0;
         remapping[14] = -1.0;
 
-        let proj_matrix = remapping * unhinging * scaling;
-        // println!("proj_matrix {proj_matrix}");
+        // println!("proj_matrix {}", remapping * unhinging * scaling);
+        remapping * unhinging * scaling
+    }
 
-        Camera {
-            width,
-            height,
-            pos,
-            look,
-            up,
+    /// Folds the mapping from clip space to image coordinates into the projection and view, so a
+    /// point projects with one product.
+    fn image_matrix(width: usize, height: usize, clip_matrix: Matrix4<f32>) -> Matrix3x4<f32> {
+        // x from -1 at the left to width at 1, y from height at -1 to 0 at 1, w kept
+        let (half_width, half_height) = (width as f32 / 2.0, height as f32 / 2.0);
+        #[rustfmt::skip]
+        let to_image = Matrix3x4::new(
+            half_width, 0.0, 0.0, half_width,
+            0.0, -half_height, 0.0, half_height,
+            0.0, 0.0, 0.0, 1.0,
+        );
+        to_image * clip_matrix
+    }
+
+    /// The same camera moved to a new pose and field of view.
+    pub fn with_view_matrix(&self, view_matrix: Matrix4<f32>, height_angle: f32) -> Self {
+        Self::from_view_matrix(
+            self.width,
+            self.height,
+            view_matrix,
             height_angle,
-            view_matrix: view_matrix.to_homogeneous(),
-            inv_view_matrix: inv_view_matrix.to_homogeneous(),
-            proj_matrix,
-            near,
-            far,
+            self.near,
+            self.far,
+        )
+        .with_lens(self.lens)
+    }
+
+    pub fn with_lens(mut self, lens: Lens) -> Self {
+        self.lens = lens;
+        self
+    }
+
+    /// Direction in camera space of the ray through continuous image coordinates.
+    /// Pinhole directions have z = -1.
+    fn camera_space_direction(&self, pixel: Vector2<f32>) -> Vector3<f32> {
+        let (x, y) = (pixel.x, pixel.y);
+        match self.lens {
+            Lens::Pinhole => {
+                let x = x / self.width as f32 - 0.5;
+                let y = y / self.height as f32 - 0.5;
+                let big_v = 2.0 * (self.height_angle / 2.0).tan();
+                let big_u = big_v * self.get_aspect_ratio();
+                Vector3::new(big_u * x, -big_v * y, -1.0)
+            }
+            Lens::Fisheye {
+                focal,
+                coefficients,
+                ..
+            } => {
+                let dx = x - self.width as f32 / 2.0;
+                let dy = self.height as f32 / 2.0 - y;
+                let r = (dx * dx + dy * dy).sqrt();
+                if r == 0.0 {
+                    return Vector3::new(0.0, 0.0, -1.0);
+                }
+                let theta = fisheye_angle(r / focal, &coefficients);
+                let (sin_theta, cos_theta) = theta.sin_cos();
+                Vector3::new(sin_theta * dx / r, sin_theta * dy / r, -cos_theta)
+            }
+        }
+    }
+
+    /// Approximate pixels per world unit for an object at unit distance, used to size footprints.
+    pub fn focal_length(&self) -> f32 {
+        match self.lens {
+            Lens::Pinhole => self.height as f32 / (2.0 * (self.height_angle / 2.0).tan().abs()),
+            Lens::Fisheye { focal, .. } => focal,
         }
     }
 
+    /// Distance beyond which the camera sees nothing
+    pub fn far(&self) -> f32 {
+        self.far
+    }
+
     pub fn get_aspect_ratio(&self) -> f32 {
         self.width as f32 / self.height as f32
     }
 }
+
+/// Maps between world space and image coordinates. Everything that projects voxels into images
+/// or casts rays out of pixels goes through this, so both directions always agree.
+/// Image coordinates are continuous, x to the right and y down from the top left corner,
+/// so pixel (i, j) covers [i, i + 1) x [j, j + 1).
+pub(crate) trait CameraModel {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+
+    /// Returns None if the camera does not see the point.
+    fn project(&self, point: Vector3<f32>) -> Option<Vector2<f32>> {
+        self.project_unclipped(point)
+            .filter(|&pixel| self.contains(pixel))
+    }
+
+    /// Whether the image coordinates are inside the image
+    fn contains(&self, pixel: Vector2<f32>) -> bool {
+        pixel.x >= 0.0
+            && pixel.x < self.width() as f32
+            && pixel.y >= 0.0
+            && pixel.y < self.height() as f32
+    }
+
+    /// Like `project`, but points outside the image still get coordinates,
+    /// only points the lens can't see at all return None.
+    fn project_unclipped(&self, point: Vector3<f32>) -> Option<Vector2<f32>>;
+
+    /// World space ray from the camera center through the image coordinates, with a unit direction.
+    fn unproject(&self, pixel: Vector2<f32>) -> Ray;
+}
+
+impl CameraModel for Camera {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn project_unclipped(&self, point: Vector3<f32>) -> Option<Vector2<f32>> {
+        match self.lens {
+            Lens::Pinhole => {
+                let image_coord = self.image_matrix * point.push(1.0);
+                // behind the camera
+                if image_coord.z <= 0.0 {
+                    return None;
+                }
+                Some(image_coord.xy() / image_coord.z)
+            }
+            Lens::Fisheye {
+                focal,
+                coefficients,
+                field_of_view,
+            } => {
+                let view_coord = self.view_matrix * point.push(1.0);
+                // angle from the optical axis, which points down -z
+                let radial = view_coord.xy().norm();
+                let theta = f32::atan2(radial, -view_coord.z);
+                if theta > field_of_view / 2.0 {
+                    return None;
+                }
+                let r = focal * fisheye_radius(theta, &coefficients);
+                let (sin_phi, cos_phi) = if radial > 0.0 {
+                    (view_coord.y / radial, view_coord.x / radial)
+                } else {
+                    (0.0, 1.0)
+                };
+                Some(Vector2::new(
+                    self.width as f32 / 2.0 + r * cos_phi,
+                    self.height as f32 / 2.0 - r * sin_phi,
+                ))
+            }
+        }
+    }
+
+    fn unproject(&self, pixel: Vector2<f32>) -> Ray {
+        let dir = self.camera_space_direction(pixel).push(0.0);
+        Ray {
+            p: self.pos.push(1.0),
+            d: (self.inv_view_matrix * dir).normalize(),
+        }
+    }
+}
+
+/// normalized fisheye image radius for a ray at angle theta from the optical axis
+fn fisheye_radius(theta: f32, coefficients: &[f32; 4]) -> f32 {
+    let theta2 = theta * theta;
+    let mut power = theta;
+    let mut radius = theta;
+    for k in coefficients {
+        power *= theta2;
+        radius += k * power;
+    }
+    radius
+}
+
+/// inverts `fisheye_radius` with Newton's method
+fn fisheye_angle(radius: f32, coefficients: &[f32; 4]) -> f32 {
+    let mut theta = radius;
+    for _ in 0..20 {
+        let theta2 = theta * theta;
+        let mut derivative = 1.0;
+        let mut power = 1.0;
+        for (n, k) in coefficients.iter().enumerate() {
+            power *= theta2;
+            derivative += (2 * n + 3) as f32 * k * power;
+        }
+        let step = (fisheye_radius(theta, coefficients) - radius) / derivative;
+        theta -= step;
+        if step.abs() < 1e-7 {
+            break;
+        }
+    }
+    theta
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Vector2, Vector3};
+
+    use super::{Camera, CameraModel, Lens};
+
+    fn pinhole_camera(pos: Vector3<f32>) -> Camera {
+        Camera::new(
+            1024,
+            768,
+            pos,
+            Vector3::zeros(),
+            Vector3::y(),
+            0.8,
+            0.01,
+            1000.0,
+        )
+    }
+
+    fn fisheye_camera(field_of_view: f32) -> Camera {
+        Camera::new(
+            400,
+            400,
+            Vector3::zeros(),
+            Vector3::new(0.0, 0.0, -1.0),
+            Vector3::y(),
+            1.0,
+            0.01,
+            1000.0,
+        )
+        .with_lens(Lens::Fisheye {
+            focal: 400.0 / field_of_view,
+            coefficients: [0.02, -0.003, 0.0, 0.0],
+            field_of_view,
+        })
+    }
+
+    fn cameras() -> Vec<Camera> {
+        vec![
+            pinhole_camera(Vector3::new(3.0, 3.0, 3.0)),
+            pinhole_camera(Vector3::new(-3.0, -0.5, 0.0)),
+            pinhole_camera(Vector3::new(0.0, 3.0, -3.0)).with_lens(Lens::Pinhole),
+            fisheye_camera(220.0_f32.to_radians()),
+            fisheye_camera(120.0_f32.to_radians()),
+        ]
+    }
+
+    #[test]
+    fn test_pixel_round_trip() {
+        for camera in cameras() {
+            for (u, v) in [(0.5, 0.5), (0.3, 0.7), (0.05, 0.5), (0.5, 0.97), (0.9, 0.1)] {
+                let pixel = Vector2::new(u * camera.width as f32, v * camera.height as f32);
+                let ray = camera.unproject(pixel);
+                assert!((ray.d.norm() - 1.0).abs() < 1e-5);
+                let point = ray.p + ray.d * 4.0;
+                let Some(projected) = camera.project(point.xyz()) else {
+                    // outside the image circle of the narrower fisheye
+                    assert!(matches!(camera.lens, Lens::Fisheye { .. }));
+                    continue;
+                };
+                assert!((projected - pixel).norm() < 1e-2, "{projected} vs {pixel}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_point_round_trip() {
+        for camera in cameras() {
+            for point in [
+                Vector3::new(0.0, 0.0, 0.0),
+                Vector3::new(0.5, -0.3, 0.2),
+                Vector3::new(-0.8, 0.6, -0.4),
+                Vector3::new(1.0, 1.0, -3.0),
+            ] {
+                let Some(pixel) = camera.project(point) else {
+                    continue;
+                };
+                // the ray back out of the pixel passes through the point
+                let ray = camera.unproject(pixel);
+                let to_point = point - ray.p.xyz();
+                let off_ray = to_point - ray.d.xyz() * to_point.dot(&ray.d.xyz());
+                assert!(off_ray.norm() < 1e-3 * to_point.norm());
+            }
+        }
+    }
+
+    #[test]
+    fn test_project_looks_at_target() {
+        // a pinhole camera sees its target in the middle of the image
+        let pos = Vector3::new(3.0, 3.0, 3.0);
+        let camera = Camera::new(
+            1024,
+            768,
+            pos,
+            Vector3::zeros(),
+            Vector3::y(),
+            0.5,
+            0.01,
+            1000.0,
+        );
+        let center = camera.project(Vector3::zeros()).unwrap();
+        assert!((center - Vector2::new(512.0, 384.0)).norm() < 1e-3);
+        // and nothing behind it
+        assert!(camera.project(Vector3::new(6.0, 6.0, 6.0)).is_none());
+        // points above the target are higher up in the image
+        assert!(camera.project(Vector3::new(0.0, 0.5, 0.0)).unwrap().y < center.y);
+    }
+
+    #[test]
+    #[should_panic(expected = "Height angle must be in radians")]
+    fn test_height_angle_in_degrees() {
+        Camera::new(
+            64,
+            48,
+            Vector3::z(),
+            Vector3::zeros(),
+            Vector3::y(),
+            30.0,
+            0.01,
+            1000.0,
+        );
+    }
+
+    #[test]
+    fn test_fisheye_wider_than_180() {
+        let camera = fisheye_camera(220.0_f32.to_radians());
+        // 100 degrees off axis is behind the camera plane but inside the lens' field of view
+        let angle = 100.0_f32.to_radians();
+        let behind = Vector3::new(angle.sin(), 0.0, -angle.cos());
+        assert!(behind.z > 0.0);
+        assert!(camera.project(behind).is_some());
+        // straight behind the camera is outside of it
+        assert!(camera.project(Vector3::new(0.0, 0.0, 1.0)).is_none());
+
+        // a narrower lens does not see behind itself
+        let camera = fisheye_camera(160.0_f32.to_radians());
+        assert!(camera.project(behind).is_none());
+    }
+}
@@ -1,18 +1,36 @@
-use nalgebra::{Vector3, Vector4};
+use std::{collections::HashMap, time::Instant};
 
-use crate::{image::Image, voxel::VoxelBlock};
+use nalgebra::{Vector2, Vector3};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
 
-enum Consistency {
-    Consistent(Vector3<u8>),
-    Inconsistent,
-    Inconclusive,
-    Background,
-}
+use crate::{
+    camera::CameraModel,
+    config::{CarvingConfig, CarvingMode, Inconclusive, Sampling, Sweep},
+    consistency::{median_color, ViewSample},
+    footprint::image_footprint,
+    graph_cut::regularize,
+    image::Image,
+    probabilistic::{estimate_occupancy, Estimate},
+    progress::{CancellationToken, Cancelled, CarvingObserver, PrintProgress},
+    projection::ProjectionCache,
+    provenance::{carved_by, Provenance, Reason},
+    stats::{CameraStats, CarvingStats, RoundStats, SweepStats},
+    visibility::ItemBuffer,
+    visual_hull::carve_visual_hull,
+    voxel::{Votes, VoxelBlock},
+    voxel_coloring::{color_voxels, ColoringError},
+};
 
-enum ProjectedColor {
-    Color(Vector3<u8>),
-    Background,
-    Unknown,
+/// A voxel's verdict, with the positions in the views of those it rests on
+#[derive(Debug)]
+pub(crate) enum Consistency {
+    // the color, and which of the views saw the voxel
+    Consistent(Vector3<u8>, Vec<usize>),
+    // the views whose colors were compared
+    Inconsistent(Vec<usize>),
+    Inconclusive,
+    // the views that saw background
+    Background(Vec<usize>),
 }
 
 #[derive(Debug)]
@@ -25,81 +43,440 @@ enum Plane {
 /// given voxelblock and image, for each voxel, project ray to each camera and get pixel and color
 /// ray trace to see if the colors are consistent
 /// if not, then carve away
-pub(crate) fn carve(voxel_block: &mut VoxelBlock, images: &mut [Image]) {
-    // carve in each of the 6 directions until nothing left to be removed
-    loop {
-        println!("loop!");
-        let mut carved_count = 0;
-        let sweeps = vec![
-            (Plane::X, false),
-            (Plane::X, true),
-            (Plane::Y, false),
-            (Plane::Y, true),
-            (Plane::Z, false),
-            (Plane::Z, true),
-        ];
+pub(crate) fn carve(
+    voxel_block: &mut VoxelBlock,
+    images: &mut [Image],
+    config: &CarvingConfig,
+) -> CarvingStats {
+    carve_with_progress(
+        voxel_block,
+        images,
+        config,
+        &mut PrintProgress,
+        &CancellationToken::default(),
+    )
+}
 
-        for (plane, reverse) in sweeps {
-            println!("sweep plane {plane:?} reversed? {reverse}");
-            let count = sweep_plane(&plane, reverse, images, voxel_block);
-            println!("carved {count} voxels");
-            carved_count += count;
-        }
-        if carved_count == 0 {
-            break;
+/// `carve`, reporting to the observer as it goes and stopping between slices once the token is
+/// cancelled. A cancelled carve leaves the block partially carved but consistent, every slice
+/// either fully applied or untouched, and skips carving inconclusive voxels and regularizing.
+/// Its stats cover what it did before stopping and say it was cancelled.
+/// Panics if the config doesn't validate. Voxel coloring from cameras that no plane separates from
+/// the block falls back to space carving's sweeps.
+pub(crate) fn carve_with_progress(
+    voxel_block: &mut VoxelBlock,
+    images: &mut [Image],
+    config: &CarvingConfig,
+    observer: &mut dyn CarvingObserver,
+    cancel: &CancellationToken,
+) -> CarvingStats {
+    let pool = thread_pool(config);
+    carve_pass(
+        voxel_block,
+        images,
+        config,
+        true,
+        pool.as_ref(),
+        observer,
+        cancel,
+    )
+}
+
+/// The pool to test voxels on, None for rayon's global one when the config asks for a thread per
+/// core. Carves build it once, however many passes and sweeps they make.
+fn thread_pool(config: &CarvingConfig) -> Option<ThreadPool> {
+    (config.threads != 0).then(|| {
+        ThreadPoolBuilder::new()
+            .num_threads(config.threads)
+            .build()
+            .expect("Unable to start carving threads")
+    })
+}
+
+/// One carve of the block, only finishing it on the `last` one
+fn carve_pass(
+    voxel_block: &mut VoxelBlock,
+    images: &mut [Image],
+    config: &CarvingConfig,
+    last: bool,
+    pool: Option<&ThreadPool>,
+    observer: &mut dyn CarvingObserver,
+    cancel: &CancellationToken,
+) -> CarvingStats {
+    // configs from files are validated when loaded, this catches ones built in code
+    if let Err(error) = config.validate() {
+        panic!("Invalid carving config: {error}");
+    }
+    let start = Instant::now();
+    for image in images.iter_mut() {
+        image.background = config.background.into();
+        // cameras may have moved since the last carve, e.g. during pose refinement
+        image.projections = config
+            .cache_projections
+            .then(|| ProjectionCache::new(voxel_block));
+    }
+    keep_side_tables(voxel_block, config);
+    let mut stats = CarvingStats::new(images);
+    let carved_before = carved_count(voxel_block);
+    let result = carve_mode(
+        voxel_block,
+        images,
+        config,
+        pool,
+        observer,
+        cancel,
+        &mut stats,
+    );
+    if result.is_ok() && last {
+        finish(voxel_block, images, config, observer, &mut stats);
+    }
+    stats.carved = carved_count(voxel_block).saturating_sub(carved_before);
+    for (camera, image) in stats.cameras.iter_mut().zip(images.iter()) {
+        camera.projection_cache_bytes = image.projections.as_ref().map_or(0, |cache| cache.bytes());
+    }
+    stats.cancelled = result.is_err();
+    stats.seconds = start.elapsed().as_secs_f64();
+    observer.finished(stats.carved);
+    stats
+}
+
+/// Allocates the block's side tables the config needs and drops the ones it doesn't. Votes and
+/// provenance carry over from an earlier pass, e.g. the new views' one, estimates are made afresh
+/// by `carve_mode`.
+fn keep_side_tables(voxel_block: &mut VoxelBlock, config: &CarvingConfig) {
+    let voxels = voxel_block.voxels.len();
+    if !matches!(config.mode, CarvingMode::Probabilistic(_)) {
+        voxel_block.occupancy = None;
+    }
+    if config.regularization.is_some() {
+        voxel_block
+            .votes
+            .get_or_insert_with(|| vec![Votes::default(); voxels]);
+    } else {
+        voxel_block.votes = None;
+    }
+    if config.provenance {
+        voxel_block.provenance.get_or_insert_with(HashMap::new);
+    } else {
+        voxel_block.provenance = None;
+    }
+}
+
+/// Carves inconclusive voxels if the config says to and regularizes, once the sweeps are done
+fn finish(
+    voxel_block: &mut VoxelBlock,
+    images: &[Image],
+    config: &CarvingConfig,
+    observer: &mut dyn CarvingObserver,
+    stats: &mut CarvingStats,
+) {
+    // the visual hull has no colors to judge inconclusive voxels by
+    let visual_hull = matches!(config.mode, CarvingMode::VisualHull { .. });
+    if !visual_hull && config.inconclusive == Inconclusive::Carve {
+        // surface voxels that never got a color were never tested
+        let inconclusive: Vec<usize> = (0..voxel_block.voxels.len())
+            .filter(|&index| {
+                let voxel = &voxel_block.voxels[index];
+                voxel.visible && !voxel.carved && voxel.color.is_none()
+            })
+            .collect();
+        stats.inconclusive = inconclusive.len();
+        observer.message(&format!(
+            "carved {} inconclusive voxels",
+            inconclusive.len()
+        ));
+        for index in inconclusive {
+            voxel_block.set_provenance(
+                index,
+                Provenance {
+                    round: None,
+                    sweep: None,
+                    slice: None,
+                    reason: Reason::Inconclusive,
+                    cameras: vec![],
+                },
+            );
+            voxel_block.carve(index);
         }
     }
+    if let Some(regularization) = &config.regularization {
+        let (carved, restored) = regularize(voxel_block, images, config, regularization);
+        stats.regularization_carved = carved;
+        stats.regularization_restored = restored;
+        observer.message(&format!(
+            "regularization carved {carved} voxels and restored {restored}"
+        ));
+    }
 }
 
-fn sweep_plane(
-    plane: &Plane,
-    reverse: bool,
-    images: &mut [Image],
+/// Carves on from a block carved before, e.g. one loaded with `VoxelBlock::load_state_from_file`,
+/// with more images, `images[first_new..]` being the new ones. The new views carve what they
+/// can see through on their own first, keeping the voxels they don't decide and the colors the
+/// earlier views gave, only coloring voxels that had none. The carve is only run again with
+/// every view when they carved something, otherwise nothing the earlier views decided has
+/// changed and the block is just finished, see `finish`.
+pub(crate) fn carve_with_new_views(
     voxel_block: &mut VoxelBlock,
-) -> usize {
-    let plane_bounds: Box<dyn Iterator<Item = _>> = if reverse {
-        Box::new((0..voxel_block.resolution).rev())
+    images: &mut [Image],
+    first_new: usize,
+    config: &CarvingConfig,
+    observer: &mut dyn CarvingObserver,
+    cancel: &CancellationToken,
+) -> CarvingStats {
+    let start = Instant::now();
+    let pool = thread_pool(config);
+    let mut stats = CarvingStats::new(images);
+    let colors: Vec<_> = voxel_block.voxels.iter().map(|voxel| voxel.color).collect();
+    let new_views = carve_pass(
+        voxel_block,
+        &mut images[first_new..],
+        config,
+        false,
+        pool.as_ref(),
+        observer,
+        cancel,
+    );
+    for (voxel, color) in voxel_block.voxels.iter_mut().zip(colors) {
+        if color.is_some() {
+            voxel.color = color;
+        }
+    }
+    let carved = new_views.carved;
+    let cancelled = new_views.cancelled;
+    stats.absorb(new_views, first_new);
+    if cancelled {
+        stats.seconds = start.elapsed().as_secs_f64();
+        return stats;
+    }
+    if carved == 0 {
+        observer.message("the new views carved nothing, keeping what the earlier views decided");
+        let carved_before = carved_count(voxel_block);
+        finish(voxel_block, images, config, observer, &mut stats);
+        stats.carved += carved_count(voxel_block).saturating_sub(carved_before);
     } else {
-        Box::new(0..voxel_block.resolution)
-    };
+        observer.message(&format!(
+            "the new views carved {carved} voxels, carving again with every view"
+        ));
+        let every_view = carve_pass(
+            voxel_block,
+            images,
+            config,
+            true,
+            pool.as_ref(),
+            observer,
+            cancel,
+        );
+        stats.absorb(every_view, 0);
+    }
+    stats.seconds = start.elapsed().as_secs_f64();
+    stats
+}
 
-    let valid_images = &mut vec![];
+fn carved_count(voxel_block: &VoxelBlock) -> usize {
+    voxel_block
+        .voxels
+        .iter()
+        .filter(|voxel| voxel.carved)
+        .count()
+}
 
-    for image in images {
-        match plane {
-            Plane::X => {
-                if (reverse && image.camera.look.x < 0.0) || (!reverse && image.camera.look.x > 0.0)
-                {
-                    println!("{}", image.camera.pos);
-                    valid_images.push(image);
-                }
+#[allow(clippy::too_many_arguments)]
+fn carve_mode(
+    voxel_block: &mut VoxelBlock,
+    images: &mut [Image],
+    config: &CarvingConfig,
+    pool: Option<&ThreadPool>,
+    observer: &mut dyn CarvingObserver,
+    cancel: &CancellationToken,
+    stats: &mut CarvingStats,
+) -> Result<(), Cancelled> {
+    match config.mode {
+        CarvingMode::SpaceCarving => {
+            space_carve(voxel_block, images, config, pool, observer, cancel, stats)?
+        }
+        CarvingMode::Probabilistic(model) => {
+            // image indices are this carve's, evidence from another one doesn't carry over
+            let prior = Estimate {
+                occupancy: model.prior,
+                evidence: vec![],
+            };
+            voxel_block.occupancy = Some(vec![prior; voxel_block.voxels.len()]);
+            space_carve(voxel_block, images, config, pool, observer, cancel, stats)?;
+        }
+        CarvingMode::VisualHull {
+            min_background_views,
+        } => {
+            if cancel.is_cancelled() {
+                return Err(Cancelled);
             }
-            Plane::Y => {
-                if (reverse && image.camera.look.y < 0.0) || (!reverse && image.camera.look.y > 0.0)
-                {
-                    println!("{}", image.camera.pos);
-                    valid_images.push(image);
+            let count = carve_visual_hull(
+                voxel_block,
+                images,
+                min_background_views,
+                config,
+                &mut stats.cameras,
+            );
+            stats.outside_visual_hull = count;
+            observer.message(&format!("carved {count} voxels outside the visual hull"));
+        }
+        CarvingMode::VoxelColoring => {
+            match color_voxels(voxel_block, images, config, observer, cancel, stats) {
+                Ok(_) => {}
+                Err(ColoringError::Cancelled) => return Err(Cancelled),
+                Err(ColoringError::NoOrdinalVisibility) => {
+                    observer.message(
+                        "no plane separates the cameras from the block, carving with sweeps instead",
+                    );
+                    space_carve(voxel_block, images, config, pool, observer, cancel, stats)?;
                 }
             }
-            Plane::Z => {
-                if (reverse && image.camera.look.z < 0.0) || (!reverse && image.camera.look.z > 0.0)
-                {
-                    println!("{}", image.camera.pos);
-                    valid_images.push(image);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sweeps in the configured directions until a whole round carves nothing
+fn space_carve(
+    voxel_block: &mut VoxelBlock,
+    images: &mut [Image],
+    config: &CarvingConfig,
+    pool: Option<&ThreadPool>,
+    observer: &mut dyn CarvingObserver,
+    cancel: &CancellationToken,
+    stats: &mut CarvingStats,
+) -> Result<(), Cancelled> {
+    // which voxel each pixel sees, kept up to date as voxels are carved
+    let item_buffers: Vec<ItemBuffer> = images
+        .iter()
+        .map(|image| ItemBuffer::new(&image.camera))
+        .collect();
+
+    let mut carve = || {
+        let mut round = 0;
+        loop {
+            observer.round_started(round);
+            let round_start = Instant::now();
+            let mut round_stats = RoundStats::default();
+            for &sweep in &config.sweep_order {
+                let sweep_start = Instant::now();
+                let mut sweep_stats = SweepStats {
+                    sweep: Some(sweep),
+                    ..Default::default()
+                };
+                let result = sweep_plane(
+                    sweep,
+                    round,
+                    images,
+                    &item_buffers,
+                    voxel_block,
+                    config,
+                    observer,
+                    cancel,
+                    &mut sweep_stats,
+                    &mut stats.cameras,
+                );
+                sweep_stats.seconds = sweep_start.elapsed().as_secs_f64();
+                let carved = sweep_stats.carved();
+                round_stats.push(sweep_stats);
+                if result.is_err() {
+                    round_stats.seconds = round_start.elapsed().as_secs_f64();
+                    stats.rounds.push(round_stats);
+                    return result;
                 }
+                observer.sweep_finished(sweep, carved);
             }
+            round_stats.seconds = round_start.elapsed().as_secs_f64();
+            let carved_count = round_stats.background + round_stats.inconsistent;
+            stats.rounds.push(round_stats);
+            observer.round_finished(round, carved_count);
+            if carved_count == 0 {
+                return Ok(());
+            }
+            round += 1;
         }
+    };
+    // the sweeps' parallel slices run on whichever pool this runs on
+    match pool {
+        Some(pool) => pool.install(carve),
+        None => carve(),
     }
+}
 
-    // sweep through the slices
-    let mut carved_count = 0;
-    for a in plane_bounds {
-        // reset marked pixels
-        // for image in &mut *valid_images {
-        //     image.marked = vec![false; image.data.len()/3];
-        // }
+/// One sweep, counted into `stats` and `cameras` as it goes
+#[allow(clippy::too_many_arguments)]
+fn sweep_plane(
+    sweep: Sweep,
+    round: usize,
+    images: &mut [Image],
+    item_buffers: &[ItemBuffer],
+    voxel_block: &mut VoxelBlock,
+    config: &CarvingConfig,
+    observer: &mut dyn CarvingObserver,
+    cancel: &CancellationToken,
+    stats: &mut SweepStats,
+    cameras: &mut [CameraStats],
+) -> Result<(), Cancelled> {
+    let (plane, reverse) = match sweep {
+        Sweep::X => (Plane::X, false),
+        Sweep::ReverseX => (Plane::X, true),
+        Sweep::Y => (Plane::Y, false),
+        Sweep::ReverseY => (Plane::Y, true),
+        Sweep::Z => (Plane::Z, false),
+        Sweep::ReverseZ => (Plane::Z, true),
+    };
+    let plane_bounds: Box<dyn Iterator<Item = _>> = if reverse {
+        Box::new((0..voxel_block.resolution).rev())
+    } else {
+        Box::new(0..voxel_block.resolution)
+    };
+    let axis = match plane {
+        Plane::X => 0,
+        Plane::Y => 1,
+        Plane::Z => 2,
+    };
+    let slices: Vec<usize> = plane_bounds.collect();
+    // image indices of the views of each slice
+    let selections: Vec<Vec<usize>> = slices
+        .iter()
+        .map(|&a| slice_cameras(images, voxel_block, axis, reverse, a))
+        .collect();
+    let mut taking_part = vec![false; images.len()];
+    for &image_index in selections.iter().flatten() {
+        taking_part[image_index] = true;
+    }
+    stats.views = taking_part
+        .iter()
+        .filter(|&&taking_part| taking_part)
+        .count();
+    observer.sweep_started(sweep, stats.views);
 
-        let mut carved = vec![];
+    let mut all_views: Vec<(&mut Image, &ItemBuffer)> = images
+        .iter_mut()
+        .zip(item_buffers.iter())
+        .map(|(image, item_buffer)| {
+            // marks only hold within a sweep
+            image.marked.fill(false);
+            (image, item_buffer)
+        })
+        .collect();
+
+    // sweep through the slices
+    for (slice_number, (a, view_images)) in slices.into_iter().zip(selections).enumerate() {
+        if cancel.is_cancelled() {
+            return Err(Cancelled);
+        }
+        stats.slice_views.push(view_images.len());
+        for &image_index in &view_images {
+            cameras[image_index].slices += 1;
+        }
+        let mut valid_images: Vec<(&mut Image, &ItemBuffer)> = all_views
+            .iter_mut()
+            .enumerate()
+            .filter(|(image_index, _)| view_images.contains(image_index))
+            .map(|(_, (image, item_buffer))| (&mut **image, *item_buffer))
+            .collect();
+        let mut slice = vec![];
         for b in 0..voxel_block.resolution {
             for c in 0..voxel_block.resolution {
                 // get coordinate of voxel
@@ -112,174 +489,916 @@ fn sweep_plane(
                     + y * voxel_block.resolution
                     + z * voxel_block.resolution * voxel_block.resolution;
                 // skip not visible voxels
-                let voxel = &mut voxel_block.voxels[index];
-                if !voxel.visible || voxel.carved {
-                    continue;
+                let voxel = &voxel_block.voxels[index];
+                if voxel.visible && !voxel.carved {
+                    slice.push(index);
                 }
+            }
+        }
 
-                match should_carve_voxel(index, valid_images, voxel_block) {
-                    Consistency::Consistent(color) => {
-                        let voxel = &mut voxel_block.voxels[index];
-                        voxel.color = Some(color);
-                    }
-                    Consistency::Inconsistent => {
-                        // println!("inconsistent");
-                        carved.push(index);
+        // voxels in one slice are independent until the slice is applied, so they are tested in
+        // parallel and the results applied in slice order, the same as one at a time
+        let views: &[(&mut Image, &ItemBuffer)] = &valid_images;
+        let block: &VoxelBlock = voxel_block;
+        let decisions: Vec<_> = slice
+            .par_iter()
+            .map(|&index| {
+                let (consistency, votes, occupancy) = match config.mode {
+                    CarvingMode::Probabilistic(model) => {
+                        estimate_occupancy(index, views, &view_images, block, config, &model)
                     }
-                    Consistency::Inconclusive => {
-                        // println!("Inconclusive");
-                    }
-                    Consistency::Background => {
-                        // println!("background");
-                        carved.push(index);
+                    _ => {
+                        let (consistency, votes) = should_carve_voxel(index, views, block, config);
+                        (consistency, votes, None)
                     }
+                };
+                let carved_by = if config.provenance {
+                    carved_by(
+                        &consistency,
+                        index,
+                        views,
+                        &view_images,
+                        block,
+                        config.sampling,
+                    )
+                } else {
+                    None
+                };
+                (consistency, votes, occupancy, carved_by)
+            })
+            .collect();
+
+        let mut carved = vec![];
+        let mut consistent = vec![];
+        for (index, (consistency, votes, occupancy, carved_by)) in slice.into_iter().zip(decisions)
+        {
+            if let Some((reason, cameras)) = carved_by {
+                voxel_block.set_provenance(
+                    index,
+                    Provenance {
+                        round: Some(round),
+                        sweep: Some(sweep),
+                        slice: Some(slice_number),
+                        reason,
+                        cameras,
+                    },
+                );
+            }
+            voxel_block.set_votes(index, votes);
+            stats.highlights += votes.highlights;
+            if let (Some(estimate), Some(estimates)) = (occupancy, &mut voxel_block.occupancy) {
+                estimates[index] = estimate;
+            }
+            let voxel = &mut voxel_block.voxels[index];
+            stats.record(&consistency, &view_images, cameras);
+            match consistency {
+                Consistency::Consistent(color, seen_by) => {
+                    voxel.color = Some(color);
+                    consistent.push((index, seen_by));
+                }
+                Consistency::Inconsistent(_) => {
+                    // println!("inconsistent");
+                    carved.push(index);
+                }
+                Consistency::Inconclusive => {
+                    // println!("Inconclusive");
+                }
+                Consistency::Background(_) => {
+                    // println!("background");
+                    carved.push(index);
                 }
             }
         }
+        // mark after the whole slice, voxels in one slice don't hide each other
+        if config.mark_pixels {
+            mark_footprints(&mut valid_images, voxel_block, consistent);
+        }
+
         // carve voxels
-        carved_count += carved.len();
+        observer.slice_finished(slice_number, voxel_block.resolution, carved.len());
         for voxel in carved {
             voxel_block.carve(voxel);
         }
     }
-    carved_count
+    if config.highlights.is_some() {
+        observer.message(&format!("rejected {} highlight samples", stats.highlights));
+    }
+    Ok(())
 }
 
-fn should_carve_voxel(
-    index: usize,
-    images: &mut [&mut Image],
-    voxel_block: &mut VoxelBlock,
-) -> Consistency {
-    let (x, y, z) = voxel_block.index_to_coordinate(index);
-    let mut projected_colors = vec![];
-    for image in images {
-        if let Some(projected_index) = project_coordinate(x, y, z, image, voxel_block) {
-            let r = image.data[projected_index * 3];
-            let g = image.data[projected_index * 3 + 1];
-            let b = image.data[projected_index * 3 + 2];
-            if r == 0 && g == 0 && b == 0 {
-                return Consistency::Background;
+/// Image indices of the cameras that take part in slice `a` of a sweep along `axis`: those
+/// entirely on the side the sweep has already passed, beyond the slice's leading face, as in
+/// Kutulakos and Seitz. Everything the slice could hide from them has been decided, and a camera
+/// inside the block joins once the sweep has passed it.
+fn slice_cameras(
+    images: &[Image],
+    voxel_block: &VoxelBlock,
+    axis: usize,
+    reverse: bool,
+    a: usize,
+) -> Vec<usize> {
+    let (min, _) = voxel_block.bounds();
+    let voxel_length = voxel_block.voxel_length();
+    (0..images.len())
+        .filter(|&image_index| {
+            let pos = images[image_index].camera.pos[axis];
+            if reverse {
+                pos > min[axis] + (a + 1) as f32 * voxel_length
             } else {
-                let color = Vector3::new(r, g, b);
-                projected_colors.push(color);
+                pos < min[axis] + a as f32 * voxel_length
+            }
+        })
+        .collect()
+}
+
+/// Marks the footprints of consistent voxels in the views that saw them,
+/// given as voxel indices with positions in `views`.
+pub(crate) fn mark_footprints(
+    views: &mut [(&mut Image, &ItemBuffer)],
+    voxel_block: &VoxelBlock,
+    consistent: Vec<(usize, Vec<usize>)>,
+) {
+    for (index, seen_by) in consistent {
+        for view in seen_by {
+            let image = &mut views[view].0;
+            for pixel in image_footprint(image, voxel_block, index, false) {
+                image.marked[pixel] = true;
+            }
+        }
+    }
+}
+
+/// Tests the voxel against the views that see it, views where something else is in front don't count.
+/// It is background once `voting.min_background_views` views see background. Otherwise up to
+/// `voting.max_outlier_views` of the views furthest from the median color may be left out, the
+/// fewest that make the rest consistent, and it is inconsistent if none will do.
+/// Also returns how the views voted.
+pub(crate) fn should_carve_voxel(
+    index: usize,
+    views: &[(&mut Image, &ItemBuffer)],
+    voxel_block: &VoxelBlock,
+    config: &CarvingConfig,
+) -> (Consistency, Votes) {
+    let radius = config.consistency.patch_radius() as i32;
+    let mut samples = vec![];
+    let mut seen_by = vec![];
+    let mut background_views = vec![];
+    for (view, (image, item_buffer)) in views.iter().enumerate() {
+        let observation = observe(index, image, item_buffer, voxel_block, config.sampling);
+        match observation {
+            Observation::Unseen => {}
+            Observation::Background => background_views.push(view),
+            Observation::Color(color, center) => {
+                let mut patch = vec![];
+                for dy in -radius..=radius {
+                    for dx in -radius..=radius {
+                        let offset = Vector2::new(dx as f32, dy as f32);
+                        // background around the edge of the object counts as the voxel's color
+                        patch.push(
+                            center
+                                .and_then(|center| image.sample(center + offset))
+                                .unwrap_or(color),
+                        );
+                    }
+                }
+                samples.push(ViewSample { color, patch });
+                seen_by.push(view);
             }
         }
     }
-    if projected_colors.is_empty() {
-        Consistency::Inconclusive
-    } else if let Some(color) = colors_roughly_equal(projected_colors) {
-        Consistency::Consistent(color)
+    let mut votes = Votes {
+        background: background_views.len(),
+        foreground: samples.len(),
+        ..Default::default()
+    };
+    if background_views.len() >= config.voting.min_background_views {
+        return (Consistency::Background(background_views), votes);
+    }
+    if samples.is_empty() {
+        return (Consistency::Inconclusive, votes);
+    }
+    if let Some(filter) = &config.highlights {
+        votes.highlights = filter.reject(&mut samples);
+    }
+
+    // nearest the median first, so outliers are dropped off the end
+    let median = median_color(&samples);
+    let distance = |sample: &ViewSample| {
+        (sample.color.cast::<i32>() - median.cast::<i32>())
+            .map(|channel| channel * channel)
+            .sum()
+    };
+    samples.sort_by_key(distance);
+
+    let max_outliers = config.voting.max_outlier_views.min(samples.len() - 1);
+    for outliers in 0..=max_outliers {
+        let inliers = &samples[..samples.len() - outliers];
+        if let Some(color) = config.consistency.consistent_color(inliers) {
+            votes.outliers = outliers;
+            // the outliers still see the voxel, it is just lit differently there
+            return (Consistency::Consistent(color, seen_by), votes);
+        }
+    }
+    votes.outliers = max_outliers;
+    (Consistency::Inconsistent(seen_by), votes)
+}
+
+/// What one view makes of a voxel
+#[derive(Clone, Debug)]
+pub(crate) enum Observation {
+    // hidden, outside the image or only on marked pixels
+    Unseen,
+    Background,
+    // the voxel's color and where its center lands, if that is in the image
+    Color(Vector3<u8>, Option<Vector2<f32>>),
+}
+
+/// Looks at the voxel in one view, through the pixels `sampling` picks.
+pub(crate) fn observe(
+    index: usize,
+    image: &Image,
+    item_buffer: &ItemBuffer,
+    voxel_block: &VoxelBlock,
+    sampling: Sampling,
+) -> Observation {
+    observe_through(index, image, Some(item_buffer), voxel_block, sampling)
+}
+
+/// Looks at the voxel in one view like `observe`, but through every voxel in front of it and
+/// ignoring marks, which is all a silhouette needs.
+pub(crate) fn observe_silhouette(
+    index: usize,
+    image: &Image,
+    voxel_block: &VoxelBlock,
+    sampling: Sampling,
+) -> Observation {
+    observe_through(index, image, None, voxel_block, sampling)
+}
+
+/// `observe`, with visibility and marks only taken into account given an item buffer
+fn observe_through(
+    index: usize,
+    image: &Image,
+    item_buffer: Option<&ItemBuffer>,
+    voxel_block: &VoxelBlock,
+    sampling: Sampling,
+) -> Observation {
+    match sampling {
+        Sampling::Center => observe_center(index, image, item_buffer, voxel_block),
+        Sampling::Footprint {
+            bounding_rectangle,
+            max_background_fraction,
+        } => observe_footprint(
+            index,
+            image,
+            item_buffer,
+            voxel_block,
+            bounding_rectangle,
+            max_background_fraction,
+        ),
+    }
+}
+
+/// The view's pixel under the voxel's center.
+fn observe_center(
+    index: usize,
+    image: &Image,
+    item_buffer: Option<&ItemBuffer>,
+    voxel_block: &VoxelBlock,
+) -> Observation {
+    let Some(pixel) = project_voxel(index, image, voxel_block) else {
+        return Observation::Unseen;
+    };
+    if let Some(item_buffer) = item_buffer {
+        // ignore if pixel has been marked
+        if image.marked[pixel.x as usize + image.width * pixel.y as usize] {
+            return Observation::Unseen;
+        }
+        if !item_buffer.sees(index, pixel, &image.camera, voxel_block) {
+            return Observation::Unseen;
+        }
+    }
+    match image.sample(pixel) {
+        Some(color) => Observation::Color(color, Some(pixel)),
+        None => Observation::Background,
+    }
+}
+
+/// The unmarked pixels of the voxel's footprint that the voxel owns in the item buffer,
+/// the mean of the foreground ones is its color.
+fn observe_footprint(
+    index: usize,
+    image: &Image,
+    item_buffer: Option<&ItemBuffer>,
+    voxel_block: &VoxelBlock,
+    bounding_rectangle: bool,
+    max_background_fraction: f32,
+) -> Observation {
+    let pixels = image_footprint(image, voxel_block, index, bounding_rectangle);
+    let mut background = 0;
+    let mut foreground = 0;
+    let mut sum = Vector3::<u32>::zeros();
+    for pixel in pixels {
+        let (i, j) = (pixel % image.width, pixel / image.width);
+        let center = Vector2::new(i as f32 + 0.5, j as f32 + 0.5);
+        let hidden = item_buffer.is_some_and(|item_buffer| {
+            image.marked[pixel] || !item_buffer.sees(index, center, &image.camera, voxel_block)
+        });
+        if hidden {
+            continue;
+        }
+        if image.is_background(pixel) {
+            background += 1;
+        } else {
+            foreground += 1;
+            sum += Vector3::from_column_slice(&image.data[pixel * 3..pixel * 3 + 3]).cast::<u32>();
+        }
+    }
+    let seen = background + foreground;
+    // the fraction is below 1, so a view that isn't background has a foreground pixel
+    if seen == 0 {
+        Observation::Unseen
+    } else if background as f32 > max_background_fraction * seen as f32 {
+        Observation::Background
     } else {
-        Consistency::Inconsistent
+        let color = (sum / foreground).map(|channel| channel as u8);
+        Observation::Color(color, project_voxel(index, image, voxel_block))
+    }
+}
+
+/// Image coordinates of the voxel's center, from the image's projection cache if it has one.
+/// None if the image doesn't see it.
+fn project_voxel(index: usize, image: &Image, voxel_block: &VoxelBlock) -> Option<Vector2<f32>> {
+    match &image.projections {
+        Some(cache) => cache
+            .center(&image.camera, voxel_block, index)
+            .filter(|&pixel| image.camera.contains(pixel)),
+        None => {
+            let (x, y, z) = voxel_block.index_to_coordinate(index);
+            let half_voxel_length = voxel_block.voxel_length() / 2.0;
+            image
+                .camera
+                .project(Vector3::new(x, y, z).add_scalar(half_voxel_length))
+        }
     }
 }
 
+/// Projects the center of the voxel at (x,y,z) into the image.
+/// Returns continuous image coordinates, or None if the image doesn't see it.
+// the carver goes through `project_voxel`, the tests check this against the raytracer
+#[cfg(test)]
 pub fn project_coordinate(
     x: f32,
     y: f32,
     z: f32,
     image: &Image,
     voxel_block: &VoxelBlock,
-) -> Option<usize> {
+) -> Option<Vector2<f32>> {
     // center the coordinate in the voxel
-    let half_voxel_length = voxel_block.length as f32 / voxel_block.resolution as f32 / 2.0;
-    let x_half = x + half_voxel_length;
-    let y_half = y + half_voxel_length;
-    let z_half = z + half_voxel_length;
-    // convert from world space to projected/clip space
-    let proj_coord = image.camera.proj_matrix
-        * image.camera.view_matrix
-        * Vector4::new(x_half, y_half, z_half, 1.0);
-
-    // normalize
-    let normed_coord = Vector3::new(
-        proj_coord[0] / proj_coord[3],
-        proj_coord[1] / proj_coord[3],
-        proj_coord[2] / proj_coord[3],
+    let half_voxel_length = voxel_block.voxel_length() / 2.0;
+    let center = Vector3::new(
+        x + half_voxel_length,
+        y + half_voxel_length,
+        z + half_voxel_length,
     );
-
-    // clip space goes from (-1,-1,0) to (1,1,1)
-    // discard Z, and transform into image coordinates
-    let x_index = ((normed_coord[0] + 1.0) / 2.0 * image.width as f32) as i32;
-    let y_index = ((normed_coord[1] + 1.0) / 2.0 * image.height as f32) as i32;
-
-    // check bounds
-    if x_index < 0 || x_index >= image.width as i32 || y_index < 0 || y_index >= image.height as i32
-    {
-        return None;
-    }
+    let pixel = image.camera.project(center)?;
 
     // ignore if pixel has been marked
-    let index = (x_index + image.width as i32 * y_index) as usize;
+    let index = pixel.x as usize + image.width * pixel.y as usize;
     if image.marked[index] {
-        println!("occluded!");
         return None;
     }
 
-    Some(index)
+    Some(pixel)
 }
 
-/// checks whether value1 and value2 are within a defined number of values apart
-fn is_roughly_equal(value1: u8, value2: u8, threshold: u8) -> bool {
-    let min = if value1.checked_sub(threshold).is_none() {
-        0
-    } else {
-        value1 - threshold
-    };
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
 
-    let max = if value1.checked_add(threshold).is_none() {
-        255
-    } else {
-        value1 + threshold
+    use crate::{
+        camera::Camera,
+        carver::{
+            carve, carve_with_new_views, carve_with_progress, project_coordinate,
+            should_carve_voxel, slice_cameras, Consistency,
+        },
+        config::{CarvingConfig, CarvingMode, Inconclusive, Sampling, Sweep, Voting},
+        graph_cut::Regularization,
+        image::Image,
+        progress::{CancellationToken, CarvingObserver, NoProgress},
+        raytracer::{generate_ray, trace_ray},
+        test_scenes::{camera_at, render, render_around, render_with, voxel_color},
+        visibility::ItemBuffer,
+        voxel::{Votes, VoxelBlock},
     };
 
-    value2 >= min && value2 <= max
-}
+    #[test]
+    fn test_projection_matches_pixel_rays() {
+        let voxel_block = VoxelBlock::new(2, 2);
+        let camera = camera_at(Vector3::new(3.0, 2.0, 4.0), 64, 48, 1.415);
+        let image = render_with(&voxel_block, camera, voxel_color);
+        let camera = &image.camera;
 
-fn colors_roughly_equal(colors: Vec<Vector3<u8>>) -> Option<Vector3<u8>> {
-    // println!("colors {colors:?}");
-    let range = 50;
-    let mut r_total = 0;
-    let mut g_total = 0;
-    let mut b_total = 0;
-    let count = colors.len();
-    for color in &colors {
-        r_total += color[0] as usize;
-        g_total += color[1] as usize;
-        b_total += color[2] as usize;
-    }
-    let r_avg = (r_total / count) as u8;
-    let g_avg = (g_total / count) as u8;
-    let b_avg = (b_total / count) as u8;
-    for color in &colors {
-        if !is_roughly_equal(r_avg, color[0], range)
-            || !is_roughly_equal(g_avg, color[1], range)
-            || !is_roughly_equal(b_avg, color[2], range)
-        {
-            return None;
+        // the pixel the carver projects a voxel into holds what that pixel's ray hits
+        for index in 0..voxel_block.voxels.len() {
+            let (x, y, z) = voxel_block.index_to_coordinate(index);
+            let pixel = project_coordinate(x, y, z, &image, &voxel_block).unwrap();
+            let (i, j) = (pixel.x as usize, pixel.y as usize);
+            let hit = trace_ray(&generate_ray(i, j, camera), &voxel_block, index).unwrap();
+            let pixel_index = (i + j * 64) * 3;
+            assert_eq!(image.data[pixel_index..pixel_index + 3], voxel_color(hit));
+            // the corner voxel facing the camera is hit by its own ray
+            if index == 7 {
+                assert_eq!(hit, 7);
+            }
         }
     }
-    Some(Vector3::new(r_avg, g_avg, b_avg))
-}
 
-#[cfg(test)]
-mod tests {
-    use nalgebra::Vector3;
+    #[test]
+    fn test_occluded_voxels_survive() {
+        // every voxel has its own color, so a voxel tested against pixels of one in front of it
+        // looks inconsistent and would be carved
+        let voxel_block = VoxelBlock::new(2, 2);
+        let positions = [
+            Vector3::new(4.0, 2.5, 3.0),
+            Vector3::new(2.5, 3.0, 4.0),
+            Vector3::new(3.0, 4.0, 2.5),
+        ];
+        for mark_pixels in [true, false] {
+            let mut voxel_block = voxel_block.uncarved();
+            let mut images: Vec<Image> = positions
+                .iter()
+                .map(|&pos| render(&voxel_block, pos))
+                .collect();
+            carve(
+                &mut voxel_block,
+                &mut images,
+                &CarvingConfig {
+                    mark_pixels,
+                    ..Default::default()
+                },
+            );
+
+            assert!(voxel_block.voxels.iter().all(|voxel| !voxel.carved));
+            // the corner facing the cameras is seen by all of them in its own color
+            let color = voxel_color(7);
+            assert_eq!(
+                voxel_block.voxels[7].color,
+                Some(Vector3::new(color[0], color[1], color[2]))
+            );
+            // the last sweep runs towards -z, which every camera takes part in
+            let marked = images.iter().all(|image| image.marked.contains(&true));
+            assert_eq!(marked, mark_pixels);
+        }
+    }
 
-    use crate::carver::is_roughly_equal;
+    #[test]
+    #[should_panic(expected = "Invalid carving config")]
+    fn test_invalid_config() {
+        let mut images = render_around(&VoxelBlock::new(2, 1));
+        let config = CarvingConfig {
+            length: -1.0,
+            ..Default::default()
+        };
+        carve(&mut VoxelBlock::new(2, 2), &mut images, &config);
+    }
 
     #[test]
-    fn test_is_roughly_equal() {
-        assert!(is_roughly_equal(10, 10, 10));
-        assert!(is_roughly_equal(10, 12, 10));
-        assert!(is_roughly_equal(12, 10, 10));
-        assert!(is_roughly_equal(20, 10, 10));
-        assert!(is_roughly_equal(10, 20, 10));
-        assert!(is_roughly_equal(5, 10, 10));
-        assert!(is_roughly_equal(10, 5, 10));
-        assert!(is_roughly_equal(254, 250, 10));
-        assert!(is_roughly_equal(250, 254, 10));
-        assert!(!is_roughly_equal(22, 10, 10));
-        assert!(!is_roughly_equal(10, 22, 10));
+    fn test_footprint_sampling() {
+        // a single voxel face on, covering pixels 4 to 11 each way. The top six of its eight rows
+        // are background, the bottom two split between two colors.
+        let voxel_block = VoxelBlock::new(2, 1);
+        let pos = Vector3::new(0.0, 0.0, 5.0);
+        let camera = Camera::new(
+            16,
+            16,
+            pos,
+            Vector3::zeros(),
+            Vector3::y(),
+            2.0 * f32::atan(0.5),
+            0.01,
+            1000.0,
+        );
+        let mut data = vec![0; 16 * 16 * 3];
+        for j in 10..12 {
+            for i in 4..12 {
+                let color = if i < 8 {
+                    [200, 100, 50]
+                } else {
+                    [100, 50, 250]
+                };
+                let index = (i + j * 16) * 3;
+                data[index..index + 3].copy_from_slice(&color);
+            }
+        }
+        let mut image = Image::new("footprint".to_owned(), data, camera);
+
+        let mut test = |bounding_rectangle, max_background_fraction| {
+            let config = CarvingConfig {
+                sampling: Sampling::Footprint {
+                    bounding_rectangle,
+                    max_background_fraction,
+                },
+                ..Default::default()
+            };
+            let item_buffer = ItemBuffer::new(&image.camera);
+            let views = [(&mut image, &item_buffer)];
+            should_carve_voxel(0, &views, &voxel_block, &config).0
+        };
+        for bounding_rectangle in [false, true] {
+            // three quarters background
+            assert!(matches!(
+                test(bounding_rectangle, 0.5),
+                Consistency::Background(_)
+            ));
+            // the color is the mean of the foreground pixels
+            match test(bounding_rectangle, 0.8) {
+                Consistency::Consistent(color, seen_by) => {
+                    assert_eq!(color, Vector3::new(150, 75, 150));
+                    assert_eq!(seen_by, vec![0]);
+                }
+                _ => panic!("expected the voxel to be consistent"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_voting() {
+        // four views of a single voxel, one with a highlight all over it and one that lost it
+        let voxel_block = VoxelBlock::new(2, 1);
+        let mut images: Vec<Image> = [
+            Vector3::new(4.0, 2.5, 3.0),
+            Vector3::new(-4.0, 2.5, 3.0),
+            Vector3::new(2.5, 3.0, -4.0),
+            Vector3::new(-3.0, 4.0, -2.5),
+        ]
+        .iter()
+        .map(|&pos| render(&voxel_block, pos))
+        .collect();
+        for channel in images[0].data.iter_mut().filter(|channel| **channel > 0) {
+            *channel = 255;
+        }
+        images[1].data.fill(0);
+
+        let mut test = |voting| {
+            let config = CarvingConfig {
+                voting,
+                ..Default::default()
+            };
+            let item_buffers: Vec<ItemBuffer> = images
+                .iter()
+                .map(|image| ItemBuffer::new(&image.camera))
+                .collect();
+            let views: Vec<_> = images.iter_mut().zip(item_buffers.iter()).collect();
+            should_carve_voxel(0, &views, &voxel_block, &config)
+        };
+        let votes = |outliers| Votes {
+            background: 1,
+            foreground: 3,
+            outliers,
+            ..Default::default()
+        };
+
+        // one background view is enough by default
+        let (consistency, counted) = test(Voting::default());
+        assert!(matches!(consistency, Consistency::Background(_)));
+        assert_eq!(counted, votes(0));
+
+        // ignoring it leaves the highlight
+        let strict = Voting {
+            min_background_views: 2,
+            max_outlier_views: 0,
+        };
+        let (consistency, counted) = test(strict);
+        assert!(matches!(consistency, Consistency::Inconsistent(_)));
+        assert_eq!(counted, votes(0));
+
+        // which is the view furthest from the median
+        let (consistency, counted) = test(Voting {
+            max_outlier_views: 2,
+            ..strict
+        });
+        let color = voxel_color(0);
+        match consistency {
+            Consistency::Consistent(consistent, seen_by) => {
+                assert_eq!(consistent, Vector3::new(color[0], color[1], color[2]));
+                assert_eq!(seen_by, vec![0, 2, 3]);
+            }
+            _ => panic!("expected the voxel to be consistent"),
+        }
+        assert_eq!(counted, votes(1));
+    }
+
+    #[test]
+    fn test_threads_match_sequential() {
+        // a block with a corner missing, carved at a finer resolution
+        let mut object = VoxelBlock::new(2, 2);
+        object.carve(7);
+        let carved = |threads, cache_projections| {
+            let mut images = render_around(&object);
+            let mut voxel_block = VoxelBlock::new(2, 6);
+            carve(
+                &mut voxel_block,
+                &mut images,
+                &CarvingConfig {
+                    threads,
+                    cache_projections,
+                    ..Default::default()
+                },
+            );
+            voxel_block
+                .voxels
+                .iter()
+                .map(|voxel| (voxel.carved, voxel.color))
+                .collect::<Vec<_>>()
+        };
+        let sequential = carved(1, false);
+        assert!(sequential.iter().any(|&(carved, _)| carved));
+        assert_eq!(carved(4, false), sequential);
+        // cached projections are the same projections
+        assert_eq!(carved(4, true), sequential);
+    }
+
+    #[test]
+    fn test_voxel_coloring_falls_back_to_sweeps() {
+        let mut object = VoxelBlock::new(2, 2);
+        object.carve(7);
+        // cameras all around the block, which no single front to back pass can order
+        let carved = |mode| {
+            let mut images = render_around(&object);
+            let mut voxel_block = VoxelBlock::new(2, 6);
+            let config = CarvingConfig {
+                mode,
+                ..Default::default()
+            };
+            carve(&mut voxel_block, &mut images, &config);
+            voxel_block
+                .voxels
+                .iter()
+                .map(|voxel| voxel.carved)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            carved(CarvingMode::VoxelColoring),
+            carved(CarvingMode::SpaceCarving)
+        );
+    }
+
+    /// counts what it hears, cancelling the carve after a number of slices
+    struct CancelAfter {
+        slices: usize,
+        cancel: CancellationToken,
+        rounds: usize,
+        sweeps: usize,
+        finished: Option<usize>,
+    }
+
+    impl CarvingObserver for CancelAfter {
+        fn round_started(&mut self, _round: usize) {
+            self.rounds += 1;
+        }
+
+        fn sweep_started(&mut self, _sweep: Sweep, _views: usize) {
+            self.sweeps += 1;
+        }
+
+        fn slice_finished(&mut self, _slice: usize, _slices: usize, _carved: usize) {
+            self.slices -= 1;
+            if self.slices == 0 {
+                self.cancel.cancel();
+            }
+        }
+
+        fn finished(&mut self, carved: usize) {
+            self.finished = Some(carved);
+        }
+    }
+
+    #[test]
+    fn test_cancel_between_slices() {
+        let mut object = VoxelBlock::new(2, 2);
+        object.carve(7);
+        let mut images = render_around(&object);
+        let mut voxel_block = VoxelBlock::new(2, 6);
+        let cancel = CancellationToken::default();
+        // the first sweep and half of the second
+        let mut observer = CancelAfter {
+            slices: 9,
+            cancel: cancel.clone(),
+            rounds: 0,
+            sweeps: 0,
+            finished: None,
+        };
+        let stats = carve_with_progress(
+            &mut voxel_block,
+            &mut images,
+            &CarvingConfig::default(),
+            &mut observer,
+            &cancel,
+        );
+
+        assert!(stats.cancelled);
+        assert_eq!((observer.rounds, observer.sweeps), (1, 2));
+        assert_eq!(stats.rounds.len(), 1);
+        assert_eq!(stats.rounds[0].sweeps.len(), 2);
+        let carved = voxel_block
+            .voxels
+            .iter()
+            .filter(|voxel| voxel.carved)
+            .count();
+        assert!(carved > 0);
+        assert_eq!(observer.finished, Some(carved));
+        assert_eq!(stats.carved, carved);
+
+        // carving on from the partial block gets where carving from scratch does
+        let mut full = VoxelBlock::new(2, 6);
+        carve(&mut full, &mut images, &CarvingConfig::default());
+        carve(&mut voxel_block, &mut images, &CarvingConfig::default());
+        let carved = |voxel_block: &VoxelBlock| -> Vec<bool> {
+            voxel_block
+                .voxels
+                .iter()
+                .map(|voxel| voxel.carved)
+                .collect()
+        };
+        assert_eq!(carved(&voxel_block), carved(&full));
+    }
+
+    #[test]
+    fn test_slice_cameras() {
+        let voxel_block = VoxelBlock::new(2, 4);
+        let camera = |pos: Vector3<f32>, look: Vector3<f32>| {
+            let camera = Camera::new(8, 8, pos, pos + look, Vector3::y(), 1.0, 0.01, 1000.0);
+            Image::new(format!("{pos}"), vec![0; 8 * 8 * 3], camera)
+        };
+        let images = [
+            // inside the block's extent along x, off to the side along z
+            camera(Vector3::new(0.2, 0.0, 5.0), -Vector3::z()),
+            // looking along +x from the +x side, which picking by look direction let in
+            camera(Vector3::new(3.0, 0.0, 3.0), Vector3::new(1.0, 0.0, -1.0)),
+            camera(Vector3::new(-3.0, 0.0, 0.0), Vector3::x()),
+        ];
+        let selections = |reverse: bool| -> Vec<Vec<usize>> {
+            (0..4)
+                .map(|a| slice_cameras(&images, &voxel_block, 0, reverse, a))
+                .collect()
+        };
+        // faces at -1, -0.5, 0, 0.5 and 1
+        assert_eq!(
+            selections(false),
+            vec![vec![2], vec![2], vec![2], vec![0, 2]]
+        );
+        assert_eq!(
+            selections(true),
+            vec![vec![0, 1], vec![0, 1], vec![1], vec![1]]
+        );
+    }
+
+    #[test]
+    fn test_new_views() {
+        // one corner of the block, so most of it is background to some view
+        let mut object = VoxelBlock::new(2, 2);
+        for index in 1..8 {
+            object.carve(index);
+        }
+        let mut images = render_around(&object);
+        let config = CarvingConfig::default();
+        let carved = |voxel_block: &VoxelBlock| -> Vec<bool> {
+            voxel_block
+                .voxels
+                .iter()
+                .map(|voxel| voxel.carved)
+                .collect()
+        };
+        let mut full = VoxelBlock::new(2, 6);
+        carve(&mut full, &mut images, &config);
+
+        // one view, saved, then the other three added to the loaded block
+        let mut voxel_block = VoxelBlock::new(2, 6);
+        carve(&mut voxel_block, &mut images[..1], &config);
+        let path = std::env::temp_dir().join("voxel_carving_test_new_views.txt");
+        let path = path.to_str().unwrap();
+        voxel_block.save_state_to_file(path);
+        let loaded = VoxelBlock::load_state_from_file(path).unwrap();
+        let mut voxel_block = loaded.clone();
+        let cancel = CancellationToken::default();
+        let stats = carve_with_new_views(
+            &mut voxel_block,
+            &mut images,
+            1,
+            &config,
+            &mut NoProgress,
+            &cancel,
+        );
+        assert!(stats.carved > 0);
+        assert_eq!(stats.cameras.len(), 4);
+        assert_eq!(carved(&voxel_block), carved(&full));
+
+        // a view that agrees with what is there carves nothing, so the old ones aren't tested again
+        let mut images: Vec<Image> = images.iter().chain(images.first()).cloned().collect();
+        let before = carved(&voxel_block);
+        let stats = carve_with_new_views(
+            &mut voxel_block,
+            &mut images,
+            4,
+            &config,
+            &mut NoProgress,
+            &cancel,
+        );
+        assert_eq!(stats.carved, 0);
+        assert_eq!(carved(&voxel_block), before);
+        assert_eq!(stats.cameras[0].tests, 0);
+        assert!(stats.cameras[4].tests > 0);
+
+        // stopped after their own pass, the new views have only carved what they saw through
+        let config = CarvingConfig {
+            inconclusive: Inconclusive::Carve,
+            regularization: Some(Regularization::default()),
+            ..Default::default()
+        };
+        let mut voxel_block = loaded.clone();
+        let cancel = CancellationToken::default();
+        let mut observer = CancelWhenFinished(cancel.clone());
+        let stats = carve_with_new_views(
+            &mut voxel_block,
+            &mut images[..4],
+            1,
+            &config,
+            &mut observer,
+            &cancel,
+        );
+        assert!(stats.cancelled);
+        assert!(stats.carved > 0);
+        assert_eq!(stats.inconclusive, 0);
+        assert_eq!(
+            stats.regularization_carved + stats.regularization_restored,
+            0
+        );
+        // in the colors they had, the new views only coloring voxels that had none
+        for (voxel, before) in voxel_block.voxels.iter().zip(&loaded.voxels) {
+            if !voxel.carved && before.color.is_some() {
+                assert_eq!(voxel.color, before.color);
+            }
+        }
+    }
+
+    /// cancels the carve once a pass of it finishes
+    struct CancelWhenFinished(CancellationToken);
+
+    impl CarvingObserver for CancelWhenFinished {
+        fn finished(&mut self, _carved: usize) {
+            self.0.cancel();
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut object = VoxelBlock::new(2, 2);
+        object.carve(7);
+        let mut images = render_around(&object);
+        let mut voxel_block = VoxelBlock::new(2, 6);
+        let config = CarvingConfig {
+            inconclusive: Inconclusive::Keep,
+            ..Default::default()
+        };
+        let stats = carve(&mut voxel_block, &mut images, &config);
+
+        assert!(!stats.cancelled);
+        assert!(stats.carved > 0);
+        // every carved voxel was carved by a sweep, for one reason
+        let by_round: usize = stats
+            .rounds
+            .iter()
+            .map(|round| round.background + round.inconsistent)
+            .sum();
+        assert_eq!(by_round, stats.carved);
+        // the last round carves nothing
+        assert_eq!(stats.rounds.last().map(|round| round.inconsistent), Some(0));
+        for round in &stats.rounds {
+            assert_eq!(round.sweeps.len(), config.sweep_order.len());
+            for sweep in &round.sweeps {
+                assert!(sweep.tested >= sweep.consistent + sweep.carved());
+                assert_eq!(sweep.slice_views.len(), 6);
+                assert!(sweep.slice_views.iter().all(|&views| views <= sweep.views));
+            }
+        }
+        // each carved voxel is on the evidence of at least one camera
+        assert_eq!(stats.cameras.len(), images.len());
+        assert!(
+            stats
+                .cameras
+                .iter()
+                .map(|camera| camera.carved)
+                .sum::<usize>()
+                >= stats.carved
+        );
+        assert!(stats
+            .cameras
+            .iter()
+            .all(|camera| camera.tests >= camera.carved));
+
+        let json = stats.to_json();
+        assert!(json.contains("\"rounds\""));
+        assert!(json.contains("\"sweep\": \"-x\""));
     }
 }
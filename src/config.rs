@@ -0,0 +1,393 @@
+use std::{fmt, fs};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    consistency::{
+        ColorRange, CrossCorrelation, DeltaE2000, HighlightFilter, HistogramIntersection, Ordinal,
+        PhotoConsistency, StandardDeviation,
+    },
+    graph_cut::Regularization,
+    probabilistic::OccupancyModel,
+};
+
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "{error}"),
+            ConfigError::Parse(error) => write!(f, "{error}"),
+            ConfigError::Invalid(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// Which algorithm `carve` runs
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum CarvingMode {
+    // photo-consistency plane sweeps along `sweep_order`
+    SpaceCarving,
+    // silhouettes only, carving voxels that land on background in at least this many views
+    VisualHull { min_background_views: usize },
+    // a single front to back sweep, for cameras that all lie on one side of the block
+    VoxelColoring,
+    // space carving's sweeps, but keeping voxels by their probability of being occupied
+    Probabilistic(OccupancyModel),
+}
+
+/// One plane sweep of space carving, along an axis in the increasing or (`-`) decreasing direction
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) enum Sweep {
+    #[serde(rename = "x")]
+    X,
+    #[serde(rename = "-x")]
+    ReverseX,
+    #[serde(rename = "y")]
+    Y,
+    #[serde(rename = "-y")]
+    ReverseY,
+    #[serde(rename = "z")]
+    Z,
+    #[serde(rename = "-z")]
+    ReverseZ,
+}
+
+/// Which pixels of a view a voxel's color and background test come from
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum Sampling {
+    // the pixel the voxel's center projects to
+    Center,
+    // every pixel the voxel covers and owns in the item buffer, averaged. The voxel is background
+    // in a view when more than `max_background_fraction` of them are background.
+    Footprint {
+        // the rectangle around the projected corners instead of their convex hull
+        #[serde(default)]
+        bounding_rectangle: bool,
+        #[serde(default = "default_background_fraction")]
+        max_background_fraction: f32,
+    },
+}
+
+fn default_background_fraction() -> f32 {
+    0.5
+}
+
+/// How many views it takes to carve a voxel, so a single mis-segmented view or specular
+/// highlight can't
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Voting {
+    // views that must see background for the voxel to be carved as background, fewer are ignored
+    pub(crate) min_background_views: usize,
+    // how many of the views furthest from the median color may be left out of the consistency test
+    pub(crate) max_outlier_views: usize,
+}
+
+impl Default for Voting {
+    fn default() -> Self {
+        Voting {
+            min_background_views: 1,
+            max_outlier_views: 0,
+        }
+    }
+}
+
+/// What happens to surface voxels no view could test by the end of a carve
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Inconclusive {
+    Keep,
+    Carve,
+}
+
+/// Everything that tunes a run. Any field left out of a config file keeps its default.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CarvingConfig {
+    // side length and voxels per side of the block, centered on the origin
+    pub(crate) length: f32,
+    pub(crate) resolution: usize,
+    // rgb value of the pixels that aren't the object
+    pub(crate) background: [u8; 3],
+    pub(crate) mode: CarvingMode,
+    // how views have to agree on a voxel's color for it to stay
+    #[serde(deserialize_with = "deserialize_metric")]
+    pub(crate) consistency: Box<dyn PhotoConsistency>,
+    pub(crate) sampling: Sampling,
+    pub(crate) voting: Voting,
+    // drop samples that look like specular highlights before the consistency test, off if left out
+    pub(crate) highlights: Option<HighlightFilter>,
+    pub(crate) sweep_order: Vec<Sweep>,
+    pub(crate) inconclusive: Inconclusive,
+    // mark the pixels of consistent voxels so voxels behind them later in the sweep ignore them
+    pub(crate) mark_pixels: bool,
+    // threads testing the voxels of a slice, 0 for one per core
+    pub(crate) threads: usize,
+    // project each voxel into each image once per carve instead of on every visit
+    pub(crate) cache_projections: bool,
+    // record on each carved voxel when, why and on which cameras' evidence it was carved
+    pub(crate) provenance: bool,
+    // smooth the carve with a graph cut once it is done, off if left out
+    pub(crate) regularization: Option<Regularization>,
+}
+
+impl Default for CarvingConfig {
+    fn default() -> Self {
+        CarvingConfig {
+            length: 4.0,
+            resolution: 100,
+            background: [0, 0, 0],
+            mode: CarvingMode::SpaceCarving,
+            consistency: Box::new(ColorRange::default()),
+            sampling: Sampling::Center,
+            voting: Voting::default(),
+            highlights: None,
+            sweep_order: vec![
+                Sweep::X,
+                Sweep::ReverseX,
+                Sweep::Y,
+                Sweep::ReverseY,
+                Sweep::Z,
+                Sweep::ReverseZ,
+            ],
+            inconclusive: Inconclusive::Keep,
+            mark_pixels: true,
+            threads: 0,
+            cache_projections: false,
+            provenance: false,
+            regularization: None,
+        }
+    }
+}
+
+impl CarvingConfig {
+    /// Reads a TOML config, e.g.
+    /// ```toml
+    /// resolution = 150
+    /// sweep_order = ["-x", "x", "-z", "z"]
+    /// consistency = { kind = "delta_e2000", max_difference = 8.0 }
+    /// ```
+    pub(crate) fn from_file(file_path: &str) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(file_path).map_err(ConfigError::Io)?;
+        Self::from_toml(&contents)
+    }
+
+    pub(crate) fn from_toml(contents: &str) -> Result<Self, ConfigError> {
+        let config: CarvingConfig = toml::from_str(contents).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if !(self.length.is_finite() && self.length > 0.0) {
+            return Err(ConfigError::Invalid(format!(
+                "length must be positive, got {}",
+                self.length
+            )));
+        }
+        if self.resolution == 0 {
+            return Err(ConfigError::Invalid(
+                "resolution must be at least 1".to_owned(),
+            ));
+        }
+        if self.voting.min_background_views == 0 {
+            return Err(ConfigError::Invalid(
+                "voting.min_background_views must be at least 1".to_owned(),
+            ));
+        }
+        if let Sampling::Footprint {
+            max_background_fraction,
+            ..
+        } = self.sampling
+        {
+            if !(0.0..1.0).contains(&max_background_fraction) {
+                return Err(ConfigError::Invalid(format!(
+                    "max_background_fraction must be in [0, 1), got {max_background_fraction}"
+                )));
+            }
+        }
+        self.consistency.validate().map_err(ConfigError::Invalid)?;
+        if let Some(highlights) = &self.highlights {
+            highlights.validate().map_err(ConfigError::Invalid)?;
+        }
+        if let Some(regularization) = &self.regularization {
+            regularization.validate().map_err(ConfigError::Invalid)?;
+        }
+        match self.mode {
+            CarvingMode::SpaceCarving | CarvingMode::Probabilistic(_)
+                if self.sweep_order.is_empty() =>
+            {
+                Err(ConfigError::Invalid(
+                    "space carving needs at least one sweep".to_owned(),
+                ))
+            }
+            CarvingMode::Probabilistic(model) => model.validate().map_err(ConfigError::Invalid),
+            CarvingMode::VisualHull {
+                min_background_views: 0,
+            } => Err(ConfigError::Invalid(
+                "min_background_views must be at least 1".to_owned(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The built in metrics as they appear in config files, tagged by `kind`
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Metric {
+    ColorRange(ColorRange),
+    StandardDeviation(StandardDeviation),
+    DeltaE2000(DeltaE2000),
+    CrossCorrelation(CrossCorrelation),
+    HistogramIntersection(HistogramIntersection),
+    Ordinal(Ordinal),
+}
+
+fn deserialize_metric<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Box<dyn PhotoConsistency>, D::Error> {
+    // its parameters are checked with the rest of the config, see `CarvingConfig::validate`
+    Ok(match Metric::deserialize(deserializer)? {
+        Metric::ColorRange(metric) => Box::new(metric),
+        Metric::StandardDeviation(metric) => Box::new(metric),
+        Metric::DeltaE2000(metric) => Box::new(metric),
+        Metric::CrossCorrelation(metric) => Box::new(metric),
+        Metric::HistogramIntersection(metric) => Box::new(metric),
+        Metric::Ordinal(metric) => Box::new(metric),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CarvingConfig, CarvingMode, ConfigError, Inconclusive, Sampling, Sweep};
+    use crate::{
+        consistency::{HighlightFilter, Ordinal, ViewSample},
+        probabilistic::OccupancyModel,
+    };
+
+    #[test]
+    fn test_from_toml() {
+        let config = CarvingConfig::from_toml(
+            r#"
+            resolution = 50
+            background = [0, 255, 0]
+            sweep_order = ["-x", "z"]
+            inconclusive = "carve"
+            mode = { kind = "visual_hull", min_background_views = 2 }
+            consistency = { kind = "cross_correlation", min_correlation = 0.8 }
+            sampling = { kind = "footprint", bounding_rectangle = true }
+            voting = { max_outlier_views = 1 }
+            highlights = { max_brightness_ratio = inf }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.resolution, 50);
+        assert_eq!(config.length, 4.0);
+        assert_eq!(config.background, [0, 255, 0]);
+        assert_eq!(config.sweep_order, vec![Sweep::ReverseX, Sweep::Z]);
+        assert_eq!(config.inconclusive, Inconclusive::Carve);
+        assert_eq!(
+            config.mode,
+            CarvingMode::VisualHull {
+                min_background_views: 2
+            }
+        );
+        assert_eq!(
+            config.sampling,
+            Sampling::Footprint {
+                bounding_rectangle: true,
+                max_background_fraction: 0.5
+            }
+        );
+        assert_eq!(config.voting.min_background_views, 1);
+        assert_eq!(config.voting.max_outlier_views, 1);
+        let highlights = config.highlights.unwrap();
+        assert_eq!(highlights.min_intensity, 235.0);
+        assert_eq!(highlights.max_brightness_ratio, f32::INFINITY);
+        // the default patch radius is kept
+        assert_eq!(config.consistency.patch_radius(), 1);
+
+        let config =
+            CarvingConfig::from_toml("mode = { kind = \"probabilistic\", color_sigma = 5.0 }")
+                .unwrap();
+        assert_eq!(
+            config.mode,
+            CarvingMode::Probabilistic(OccupancyModel {
+                color_sigma: 5.0,
+                ..Default::default()
+            })
+        );
+
+        // an empty file is the defaults
+        let config = CarvingConfig::from_toml("").unwrap();
+        assert_eq!(config.sweep_order.len(), 6);
+        assert_eq!(config.mode, CarvingMode::SpaceCarving);
+        let samples = [ViewSample {
+            color: [10, 20, 30].into(),
+            patch: vec![],
+        }];
+        assert!(config.consistency.consistent_color(&samples).is_some());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let invalid = [
+            "resolution = 0",
+            "length = -1.0",
+            "sweep_order = []",
+            "mode = { kind = \"visual_hull\", min_background_views = 0 }",
+            "consistency = { kind = \"ordinal\", min_correlation = 2.0 }",
+            "sampling = { kind = \"footprint\", max_background_fraction = 1.0 }",
+            "mode = { kind = \"probabilistic\", threshold = 1.0 }",
+            "voting = { min_background_views = 0 }",
+            "regularization = { smoothness = -1.0 }",
+            "consistency = { kind = \"ordinal\", radius = 0 }",
+            "consistency = { kind = \"standard_deviation\", max_deviation = -1.0 }",
+            "consistency = { kind = \"histogram_intersection\", bins_per_channel = 0 }",
+            "highlights = { max_saturation = -0.1 }",
+            "highlights = { max_brightness_ratio = 0.5 }",
+            "highlights = { min_intensity = nan }",
+        ];
+        for contents in invalid {
+            assert!(
+                matches!(
+                    CarvingConfig::from_toml(contents),
+                    Err(ConfigError::Invalid(_) | ConfigError::Parse(_))
+                ),
+                "{contents}"
+            );
+        }
+        // configs built in code are checked the same way
+        let config = CarvingConfig {
+            consistency: Box::new(Ordinal {
+                radius: 0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = CarvingConfig {
+            highlights: Some(HighlightFilter {
+                max_brightness_ratio: 0.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+
+        // typos are errors rather than silently ignored
+        assert!(matches!(
+            CarvingConfig::from_toml("resolutoin = 10"),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+}
@@ -0,0 +1,711 @@
+use nalgebra::Vector3;
+use serde::Deserialize;
+
+/// What one view sees of a voxel
+pub(crate) struct ViewSample {
+    pub(crate) color: Vector3<u8>,
+    // colors on the (2 * radius + 1)^2 grid of pixels around the projection, row by row,
+    // empty unless the metric asks for a patch
+    pub(crate) patch: Vec<Vector3<u8>>,
+}
+
+/// Decides whether the views agree on a voxel's color.
+/// Voxels are tested from several threads at once, so metrics are shared between them.
+pub(crate) trait PhotoConsistency: Sync {
+    /// The voxel's color if the samples are consistent, None if they aren't.
+    /// There is always at least one sample.
+    fn consistent_color(&self, samples: &[ViewSample]) -> Option<Vector3<u8>>;
+
+    /// Half the side of the patch each sample should carry, 0 for none
+    fn patch_radius(&self) -> usize {
+        0
+    }
+
+    /// Why the metric's parameters can't work, checked by `CarvingConfig::validate`
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Every sample is within `range` of the mean in each rgb channel
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ColorRange {
+    pub(crate) range: u8,
+}
+
+impl Default for ColorRange {
+    fn default() -> Self {
+        ColorRange { range: 50 }
+    }
+}
+
+impl PhotoConsistency for ColorRange {
+    fn consistent_color(&self, samples: &[ViewSample]) -> Option<Vector3<u8>> {
+        let colors: Vec<Vector3<u8>> = samples.iter().map(|sample| sample.color).collect();
+        colors_roughly_equal(&colors, self.range)
+    }
+}
+
+/// The standard deviation of every rgb channel is at most `max_deviation`
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct StandardDeviation {
+    pub(crate) max_deviation: f32,
+}
+
+impl Default for StandardDeviation {
+    fn default() -> Self {
+        StandardDeviation {
+            max_deviation: 25.0,
+        }
+    }
+}
+
+impl PhotoConsistency for StandardDeviation {
+    fn consistent_color(&self, samples: &[ViewSample]) -> Option<Vector3<u8>> {
+        let count = samples.len() as f32;
+        let colors: Vec<Vector3<f32>> = samples.iter().map(|sample| sample.color.cast()).collect();
+        let mean = colors.iter().sum::<Vector3<f32>>() / count;
+        let variance = colors
+            .iter()
+            .map(|color| (color - mean).component_mul(&(color - mean)))
+            .sum::<Vector3<f32>>()
+            / count;
+        if variance.max().sqrt() <= self.max_deviation {
+            Some(mean_color(samples))
+        } else {
+            None
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        non_negative("max_deviation", self.max_deviation)
+    }
+}
+
+/// Every sample is within `max_difference` CIEDE2000 of the mean color in CIELAB,
+/// which follows perceived differences better than rgb distances
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DeltaE2000 {
+    pub(crate) max_difference: f32,
+}
+
+impl Default for DeltaE2000 {
+    fn default() -> Self {
+        DeltaE2000 {
+            max_difference: 10.0,
+        }
+    }
+}
+
+impl PhotoConsistency for DeltaE2000 {
+    fn consistent_color(&self, samples: &[ViewSample]) -> Option<Vector3<u8>> {
+        let labs: Vec<Vector3<f32>> = samples.iter().map(|sample| lab(sample.color)).collect();
+        let mean = labs.iter().sum::<Vector3<f32>>() / labs.len() as f32;
+        if labs
+            .iter()
+            .all(|lab| delta_e_2000(lab, &mean) <= self.max_difference)
+        {
+            Some(mean_color(samples))
+        } else {
+            None
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        non_negative("max_difference", self.max_difference)
+    }
+}
+
+/// Every pair of views' patches correlates by at least `min_correlation` (normalized cross
+/// correlation of intensities), so texture has to line up and not just the average color.
+/// Patches too flat to correlate fall back to comparing their mean intensities.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CrossCorrelation {
+    pub(crate) radius: usize,
+    pub(crate) min_correlation: f32,
+    // largest difference in mean intensity for flat patches
+    pub(crate) flat_tolerance: f32,
+}
+
+impl Default for CrossCorrelation {
+    fn default() -> Self {
+        CrossCorrelation {
+            radius: 1,
+            min_correlation: 0.6,
+            flat_tolerance: 30.0,
+        }
+    }
+}
+
+impl PhotoConsistency for CrossCorrelation {
+    fn consistent_color(&self, samples: &[ViewSample]) -> Option<Vector3<u8>> {
+        let patches: Vec<Vec<f32>> = samples
+            .iter()
+            .map(|sample| sample.patch.iter().map(intensity).collect())
+            .collect();
+        let agree = |a: &[f32], b: &[f32]| match normalized_cross_correlation(a, b) {
+            Some(correlation) => correlation >= self.min_correlation,
+            None => (mean(a) - mean(b)).abs() <= self.flat_tolerance,
+        };
+        all_pairs(&patches, agree).then(|| mean_color(samples))
+    }
+
+    fn patch_radius(&self) -> usize {
+        self.radius
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        validate_correlation(
+            "cross correlation",
+            self.radius,
+            self.min_correlation,
+            self.flat_tolerance,
+        )
+    }
+}
+
+/// Every pair of views' patches have color histograms overlapping by at least `min_intersection`,
+/// which tolerates texture that doesn't line up between views
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HistogramIntersection {
+    pub(crate) radius: usize,
+    pub(crate) bins_per_channel: usize,
+    pub(crate) min_intersection: f32,
+}
+
+impl Default for HistogramIntersection {
+    fn default() -> Self {
+        HistogramIntersection {
+            radius: 1,
+            bins_per_channel: 4,
+            min_intersection: 0.5,
+        }
+    }
+}
+
+impl HistogramIntersection {
+    /// normalized histogram over bins_per_channel^3 bins
+    fn histogram(&self, patch: &[Vector3<u8>]) -> Vec<f32> {
+        let bins = self.bins_per_channel;
+        let mut histogram = vec![0.0; bins * bins * bins];
+        for color in patch {
+            let bin = color.map(|channel| channel as usize * bins / 256);
+            histogram[bin.x + bin.y * bins + bin.z * bins * bins] += 1.0 / patch.len() as f32;
+        }
+        histogram
+    }
+}
+
+impl PhotoConsistency for HistogramIntersection {
+    fn consistent_color(&self, samples: &[ViewSample]) -> Option<Vector3<u8>> {
+        let histograms: Vec<Vec<f32>> = samples
+            .iter()
+            .map(|sample| self.histogram(&sample.patch))
+            .collect();
+        let agree = |a: &[f32], b: &[f32]| {
+            let intersection: f32 = a.iter().zip(b).map(|(a, b)| a.min(*b)).sum();
+            intersection >= self.min_intersection
+        };
+        all_pairs(&histograms, agree).then(|| mean_color(samples))
+    }
+
+    fn patch_radius(&self) -> usize {
+        self.radius
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.radius == 0 {
+            return Err("histogram intersection needs a patch radius of at least 1".to_owned());
+        }
+        if !(1..=256).contains(&self.bins_per_channel) {
+            return Err(format!(
+                "bins_per_channel must be between 1 and 256, got {}",
+                self.bins_per_channel
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.min_intersection) {
+            return Err(format!(
+                "min_intersection must be in [0, 1], got {}",
+                self.min_intersection
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Every pair of views' patches orders its pixels' intensities alike, with a Spearman rank
+/// correlation of at least `min_correlation`. Only the ordering matters, so it tolerates
+/// different exposures and gains between cameras. Flat patches fall back as in `CrossCorrelation`.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Ordinal {
+    pub(crate) radius: usize,
+    pub(crate) min_correlation: f32,
+    pub(crate) flat_tolerance: f32,
+}
+
+impl Default for Ordinal {
+    fn default() -> Self {
+        Ordinal {
+            radius: 1,
+            min_correlation: 0.6,
+            flat_tolerance: 30.0,
+        }
+    }
+}
+
+impl PhotoConsistency for Ordinal {
+    fn consistent_color(&self, samples: &[ViewSample]) -> Option<Vector3<u8>> {
+        let patches: Vec<Vec<f32>> = samples
+            .iter()
+            .map(|sample| sample.patch.iter().map(intensity).collect())
+            .collect();
+        let agree = |a: &[f32], b: &[f32]| {
+            // ranks of flat patches are all ties
+            match normalized_cross_correlation(&ranks(a), &ranks(b)) {
+                Some(correlation) => correlation >= self.min_correlation,
+                None => (mean(a) - mean(b)).abs() <= self.flat_tolerance,
+            }
+        };
+        all_pairs(&patches, agree).then(|| mean_color(samples))
+    }
+
+    fn patch_radius(&self) -> usize {
+        self.radius
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        validate_correlation(
+            "ordinal",
+            self.radius,
+            self.min_correlation,
+            self.flat_tolerance,
+        )
+    }
+}
+
+/// the parameters `CrossCorrelation` and `Ordinal` share
+fn validate_correlation(
+    name: &str,
+    radius: usize,
+    min_correlation: f32,
+    flat_tolerance: f32,
+) -> Result<(), String> {
+    if radius == 0 {
+        return Err(format!("{name} needs a patch radius of at least 1"));
+    }
+    if !(-1.0..=1.0).contains(&min_correlation) {
+        return Err(format!(
+            "min_correlation must be in [-1, 1], got {min_correlation}"
+        ));
+    }
+    non_negative("flat_tolerance", flat_tolerance)
+}
+
+// infinity is allowed, it turns a threshold off
+fn non_negative(name: &str, value: f32) -> Result<(), String> {
+    if value >= 0.0 {
+        Ok(())
+    } else {
+        Err(format!("{name} can't be negative, got {value}"))
+    }
+}
+
+/// Drops samples that look like specular highlights rather than the surface's diffuse color:
+/// bright and nearly white in their own image, or much brighter than the voxel's other views,
+/// whose median stands in for the diffuse color. Set a threshold to infinity to turn it off.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HighlightFilter {
+    // a sample at least this bright with at most `max_saturation` is a highlight
+    pub(crate) min_intensity: f32,
+    pub(crate) max_saturation: f32,
+    // a sample this many times brighter than the median sample is a highlight
+    pub(crate) max_brightness_ratio: f32,
+}
+
+impl Default for HighlightFilter {
+    fn default() -> Self {
+        HighlightFilter {
+            min_intensity: 235.0,
+            max_saturation: 0.15,
+            max_brightness_ratio: 1.5,
+        }
+    }
+}
+
+impl HighlightFilter {
+    /// Removes the highlights from the samples and returns how many there were.
+    /// If every sample looks like one, they are all kept, there is nothing better to go on.
+    pub(crate) fn reject(&self, samples: &mut Vec<ViewSample>) -> usize {
+        let median = intensity(&median_color(samples));
+        let is_highlight = |sample: &ViewSample| {
+            let brightness = intensity(&sample.color);
+            (brightness >= self.min_intensity && saturation(&sample.color) <= self.max_saturation)
+                || brightness > self.max_brightness_ratio * median
+        };
+        let highlights = samples.iter().filter(|sample| is_highlight(sample)).count();
+        if highlights == samples.len() {
+            return 0;
+        }
+        samples.retain(|sample| !is_highlight(sample));
+        highlights
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        non_negative("min_intensity", self.min_intensity)?;
+        non_negative("max_saturation", self.max_saturation)?;
+        // any less and samples as bright as the median would be highlights
+        if self.max_brightness_ratio >= 1.0 {
+            Ok(())
+        } else {
+            Err(format!(
+                "max_brightness_ratio must be at least 1, got {}",
+                self.max_brightness_ratio
+            ))
+        }
+    }
+}
+
+fn all_pairs<T>(items: &[Vec<T>], agree: impl Fn(&[T], &[T]) -> bool) -> bool {
+    (0..items.len()).all(|i| (i + 1..items.len()).all(|j| agree(&items[i], &items[j])))
+}
+
+/// checks whether value1 and value2 are within a defined number of values apart
+pub(crate) fn is_roughly_equal(value1: u8, value2: u8, threshold: u8) -> bool {
+    let min = if value1.checked_sub(threshold).is_none() {
+        0
+    } else {
+        value1 - threshold
+    };
+
+    let max = if value1.checked_add(threshold).is_none() {
+        255
+    } else {
+        value1 + threshold
+    };
+
+    value2 >= min && value2 <= max
+}
+
+fn colors_roughly_equal(colors: &[Vector3<u8>], range: u8) -> Option<Vector3<u8>> {
+    let mut r_total = 0;
+    let mut g_total = 0;
+    let mut b_total = 0;
+    let count = colors.len();
+    for color in colors {
+        r_total += color[0] as usize;
+        g_total += color[1] as usize;
+        b_total += color[2] as usize;
+    }
+    let r_avg = (r_total / count) as u8;
+    let g_avg = (g_total / count) as u8;
+    let b_avg = (b_total / count) as u8;
+    for color in colors {
+        if !is_roughly_equal(r_avg, color[0], range)
+            || !is_roughly_equal(g_avg, color[1], range)
+            || !is_roughly_equal(b_avg, color[2], range)
+        {
+            return None;
+        }
+    }
+    Some(Vector3::new(r_avg, g_avg, b_avg))
+}
+
+fn mean_color(samples: &[ViewSample]) -> Vector3<u8> {
+    let total = samples
+        .iter()
+        .map(|sample| sample.color.cast::<usize>())
+        .sum::<Vector3<usize>>();
+    (total / samples.len()).map(|channel| channel as u8)
+}
+
+/// Per channel median, the upper one of the middle two for an even count
+pub(crate) fn median_color(samples: &[ViewSample]) -> Vector3<u8> {
+    Vector3::from_fn(|channel, _| {
+        let mut values: Vec<u8> = samples.iter().map(|sample| sample.color[channel]).collect();
+        values.sort_unstable();
+        values[values.len() / 2]
+    })
+}
+
+/// HSV saturation, 0 for grays
+fn saturation(color: &Vector3<u8>) -> f32 {
+    let max = color.max();
+    if max == 0 {
+        0.0
+    } else {
+        (max - color.min()) as f32 / max as f32
+    }
+}
+
+/// Rec. 601 luma
+fn intensity(color: &Vector3<u8>) -> f32 {
+    0.299 * color.x as f32 + 0.587 * color.y as f32 + 0.114 * color.z as f32
+}
+
+fn mean(values: &[f32]) -> f32 {
+    values.iter().sum::<f32>() / values.len() as f32
+}
+
+/// None if either side is (nearly) constant
+fn normalized_cross_correlation(a: &[f32], b: &[f32]) -> Option<f32> {
+    let (mean_a, mean_b) = (mean(a), mean(b));
+    let mut covariance = 0.0;
+    let mut variance_a = 0.0;
+    let mut variance_b = 0.0;
+    for (a, b) in a.iter().zip(b) {
+        covariance += (a - mean_a) * (b - mean_b);
+        variance_a += (a - mean_a) * (a - mean_a);
+        variance_b += (b - mean_b) * (b - mean_b);
+    }
+    let normalization = (variance_a * variance_b).sqrt();
+    if normalization < 1e-3 * a.len() as f32 {
+        return None;
+    }
+    Some(covariance / normalization)
+}
+
+/// ranks of the values, ties sharing the average of their ranks
+fn ranks(values: &[f32]) -> Vec<f32> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&i, &j| values[i].total_cmp(&values[j]));
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end - 1) as f32 / 2.0;
+        for &index in &order[start..end] {
+            ranks[index] = rank;
+        }
+        start = end;
+    }
+    ranks
+}
+
+/// sRGB to CIELAB under D65
+pub(crate) fn lab(color: Vector3<u8>) -> Vector3<f32> {
+    let linear = color.map(|channel| {
+        let c = channel as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    });
+    let x = (0.4124 * linear.x + 0.3576 * linear.y + 0.1805 * linear.z) / 0.95047;
+    let y = 0.2126 * linear.x + 0.7152 * linear.y + 0.0722 * linear.z;
+    let z = (0.0193 * linear.x + 0.1192 * linear.y + 0.9505 * linear.z) / 1.08883;
+    let f = |t: f32| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    Vector3::new(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+/// CIEDE2000 color difference between two CIELAB colors
+pub(crate) fn delta_e_2000(lab1: &Vector3<f32>, lab2: &Vector3<f32>) -> f32 {
+    let (l1, a1, b1) = (lab1.x, lab1.y, lab1.z);
+    let (l2, a2, b2) = (lab2.x, lab2.y, lab2.z);
+    let pow7 = |x: f32| x.powi(7);
+    let twenty_five_7 = pow7(25.0);
+
+    let c_bar = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) / 2.0;
+    let g = 0.5 * (1.0 - (pow7(c_bar) / (pow7(c_bar) + twenty_five_7)).sqrt());
+    let (a1, a2) = ((1.0 + g) * a1, (1.0 + g) * a2);
+    let (c1, c2) = ((a1 * a1 + b1 * b1).sqrt(), (a2 * a2 + b2 * b2).sqrt());
+    let hue = |b: f32, a: f32| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+    let (h1, h2) = (hue(b1, a1), hue(b2, a2));
+
+    let delta_l = l2 - l1;
+    let delta_c = c2 - c1;
+    let delta_h = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let delta_big_h = 2.0 * (c1 * c2).sqrt() * (delta_h.to_radians() / 2.0).sin();
+
+    let l_bar = (l1 + l2) / 2.0;
+    let c_bar = (c1 + c2) / 2.0;
+    let h_bar = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    let cos_deg = |degrees: f32| degrees.to_radians().cos();
+    let t = 1.0 - 0.17 * cos_deg(h_bar - 30.0)
+        + 0.24 * cos_deg(2.0 * h_bar)
+        + 0.32 * cos_deg(3.0 * h_bar + 6.0)
+        - 0.20 * cos_deg(4.0 * h_bar - 63.0);
+    let delta_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (pow7(c_bar) / (pow7(c_bar) + twenty_five_7)).sqrt();
+    let s_l = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar;
+    let s_h = 1.0 + 0.015 * c_bar * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    let (l, c, h) = (delta_l / s_l, delta_c / s_c, delta_big_h / s_h);
+    (l * l + c * c + h * h + r_t * c * h).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::{
+        delta_e_2000, is_roughly_equal, lab, ColorRange, CrossCorrelation, DeltaE2000,
+        HighlightFilter, HistogramIntersection, Ordinal, PhotoConsistency, StandardDeviation,
+        ViewSample,
+    };
+
+    fn sample(color: [u8; 3]) -> ViewSample {
+        ViewSample {
+            color: Vector3::from(color),
+            patch: vec![],
+        }
+    }
+
+    /// a 3x3 patch with a vertical edge, dark on the left, brightened by `gain`
+    fn edge(gain: f32, flipped: bool) -> ViewSample {
+        let patch: Vec<Vector3<u8>> = (0..9)
+            .map(|k| {
+                let left = (k % 3 == 0) != flipped;
+                let value = if left { 40.0 } else { 120.0 } * gain;
+                Vector3::repeat(value as u8)
+            })
+            .collect();
+        ViewSample {
+            color: patch[4],
+            patch,
+        }
+    }
+
+    #[test]
+    fn test_is_roughly_equal() {
+        assert!(is_roughly_equal(10, 10, 10));
+        assert!(is_roughly_equal(10, 12, 10));
+        assert!(is_roughly_equal(12, 10, 10));
+        assert!(is_roughly_equal(20, 10, 10));
+        assert!(is_roughly_equal(10, 20, 10));
+        assert!(is_roughly_equal(5, 10, 10));
+        assert!(is_roughly_equal(10, 5, 10));
+        assert!(is_roughly_equal(254, 250, 10));
+        assert!(is_roughly_equal(250, 254, 10));
+        assert!(!is_roughly_equal(22, 10, 10));
+        assert!(!is_roughly_equal(10, 22, 10));
+    }
+
+    #[test]
+    fn test_delta_e_2000() {
+        // pairs from Sharma, Wu and Dalal's CIEDE2000 test data
+        let pairs = [
+            ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+            ([50.0, 2.5, 0.0], [73.0, 25.0, -18.0], 27.1492),
+            (
+                [60.2574, -34.0099, 36.2677],
+                [60.4626, -34.1751, 39.4387],
+                1.2644,
+            ),
+        ];
+        for (lab1, lab2, expected) in pairs {
+            let difference = delta_e_2000(&Vector3::from(lab1), &Vector3::from(lab2));
+            assert!(
+                (difference - expected).abs() < 1e-3,
+                "{difference} vs {expected}"
+            );
+        }
+        assert!((lab(Vector3::repeat(255)) - Vector3::new(100.0, 0.0, 0.0)).norm() < 0.1);
+    }
+
+    #[test]
+    fn test_color_metrics() {
+        let similar = [sample([100, 150, 200]), sample([110, 140, 205])];
+        let different = [sample([60, 150, 220]), sample([220, 150, 60])];
+        let metrics: [Box<dyn PhotoConsistency>; 3] = [
+            Box::new(ColorRange::default()),
+            Box::new(StandardDeviation::default()),
+            Box::new(DeltaE2000::default()),
+        ];
+        for metric in metrics {
+            assert_eq!(
+                metric.consistent_color(&similar),
+                Some(Vector3::new(105, 145, 202))
+            );
+            assert_eq!(metric.consistent_color(&different), None);
+        }
+    }
+
+    #[test]
+    fn test_patch_metrics() {
+        // the same edge under a different exposure, and the edge mirrored
+        let same = [edge(1.0, false), edge(1.5, false)];
+        let mirrored = [edge(1.0, false), edge(1.0, true)];
+        let cross_correlation = CrossCorrelation::default();
+        let ordinal = Ordinal::default();
+        assert!(cross_correlation.consistent_color(&same).is_some());
+        assert!(cross_correlation.consistent_color(&mirrored).is_none());
+        assert!(ordinal.consistent_color(&same).is_some());
+        assert!(ordinal.consistent_color(&mirrored).is_none());
+
+        // mirroring keeps the histogram, a different exposure doesn't
+        let histogram = HistogramIntersection::default();
+        assert!(histogram.consistent_color(&mirrored).is_some());
+        assert!(histogram.consistent_color(&same).is_none());
+    }
+
+    #[test]
+    fn test_highlight_filter() {
+        let filter = HighlightFilter::default();
+        // a white highlight, and a highlight on a colored surface that only stands out from the others
+        let mut samples = vec![
+            sample([120, 80, 60]),
+            sample([250, 248, 245]),
+            sample([125, 85, 62]),
+            sample([220, 150, 120]),
+            sample([118, 82, 58]),
+        ];
+        assert_eq!(filter.reject(&mut samples), 2);
+        let colors: Vec<Vector3<u8>> = samples.iter().map(|sample| sample.color).collect();
+        assert_eq!(
+            colors,
+            vec![
+                Vector3::new(120, 80, 60),
+                Vector3::new(125, 85, 62),
+                Vector3::new(118, 82, 58)
+            ]
+        );
+
+        // a white object is all highlight, so nothing is dropped
+        let mut samples = vec![sample([250, 250, 250]), sample([245, 245, 245])];
+        assert_eq!(filter.reject(&mut samples), 0);
+        assert_eq!(samples.len(), 2);
+    }
+}
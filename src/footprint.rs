@@ -0,0 +1,203 @@
+use nalgebra::{Vector2, Vector3};
+
+use crate::{camera::CameraModel, image::Image, voxel::VoxelBlock};
+
+/// Indices of the pixels whose centers fall inside the projection of the voxel's cube,
+/// the convex hull of its eight projected corners.
+/// Empty if part of the voxel can't be projected, e.g. it is behind the camera.
+// the carver goes through `image_footprint`, the tests check these
+#[cfg(test)]
+pub(crate) fn footprint(
+    camera: &impl CameraModel,
+    voxel_block: &VoxelBlock,
+    index: usize,
+) -> Vec<usize> {
+    corner_pixels(camera, projected_corners(camera, voxel_block, index), false)
+}
+
+/// Like `footprint` but the axis aligned rectangle around the projected corners, a superset of
+/// the footprint that is cheaper to find.
+#[cfg(test)]
+pub(crate) fn bounding_rectangle(
+    camera: &impl CameraModel,
+    voxel_block: &VoxelBlock,
+    index: usize,
+) -> Vec<usize> {
+    corner_pixels(camera, projected_corners(camera, voxel_block, index), true)
+}
+
+/// `footprint` or `bounding_rectangle` in the image, with the corners from its projection cache
+/// when it has one.
+pub(crate) fn image_footprint(
+    image: &Image,
+    voxel_block: &VoxelBlock,
+    index: usize,
+    bounding_rectangle: bool,
+) -> Vec<usize> {
+    let corners = match &image.projections {
+        Some(cache) => {
+            let resolution = voxel_block.resolution;
+            let voxel = Vector3::new(
+                index % resolution,
+                index / resolution % resolution,
+                index / (resolution * resolution),
+            );
+            (0..8)
+                .map(|offset| {
+                    let shift = Vector3::new(offset & 1, (offset >> 1) & 1, (offset >> 2) & 1);
+                    cache.corner(&image.camera, voxel_block, voxel + shift)
+                })
+                .collect()
+        }
+        None => projected_corners(&image.camera, voxel_block, index),
+    };
+    corner_pixels(&image.camera, corners, bounding_rectangle)
+}
+
+fn corner_pixels(
+    camera: &impl CameraModel,
+    corners: Option<Vec<Vector2<f32>>>,
+    bounding_rectangle: bool,
+) -> Vec<usize> {
+    match corners {
+        None => vec![],
+        Some(corners) if bounding_rectangle => pixels_within(camera, &corners, |_| true),
+        Some(corners) => {
+            let hull = convex_hull(corners);
+            pixels_within(camera, &hull, |center| contains(&hull, center))
+        }
+    }
+}
+
+fn projected_corners(
+    camera: &impl CameraModel,
+    voxel_block: &VoxelBlock,
+    index: usize,
+) -> Option<Vec<Vector2<f32>>> {
+    let (x, y, z) = voxel_block.index_to_coordinate(index);
+    let corner = Vector3::new(x, y, z);
+    let voxel_length = voxel_block.voxel_length();
+    (0..8)
+        .map(|offset| {
+            let shift =
+                Vector3::new(offset & 1, (offset >> 1) & 1, (offset >> 2) & 1).cast::<f32>();
+            camera.project_unclipped(corner + shift * voxel_length)
+        })
+        .collect()
+}
+
+/// Pixels whose centers are inside the bounding box of the points and pass the test.
+fn pixels_within(
+    camera: &impl CameraModel,
+    points: &[Vector2<f32>],
+    inside: impl Fn(Vector2<f32>) -> bool,
+) -> Vec<usize> {
+    let (width, height) = (camera.width(), camera.height());
+    let min = points
+        .iter()
+        .fold(Vector2::repeat(f32::MAX), |min, p| min.inf(p));
+    let max = points
+        .iter()
+        .fold(Vector2::repeat(f32::MIN), |max, p| max.sup(p));
+    // pixels whose centers can be inside
+    let i_min = (min.x - 0.5).ceil().max(0.0) as usize;
+    let j_min = (min.y - 0.5).ceil().max(0.0) as usize;
+    let i_max = ((max.x - 0.5).floor()).min(width as f32 - 1.0);
+    let j_max = ((max.y - 0.5).floor()).min(height as f32 - 1.0);
+    if i_max < 0.0 || j_max < 0.0 {
+        return vec![];
+    }
+
+    let mut pixels = vec![];
+    for j in j_min..=j_max as usize {
+        for i in i_min..=i_max as usize {
+            if inside(Vector2::new(i as f32 + 0.5, j as f32 + 0.5)) {
+                pixels.push(i + j * width);
+            }
+        }
+    }
+    pixels
+}
+
+/// z component of (b - a) x (c - a), positive when a, b, c turn counterclockwise in a y up frame
+fn cross(a: Vector2<f32>, b: Vector2<f32>, c: Vector2<f32>) -> f32 {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+/// Andrew's monotone chain, the hull comes back in counterclockwise order
+fn convex_hull(mut points: Vec<Vector2<f32>>) -> Vec<Vector2<f32>> {
+    points.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+    let mut hull: Vec<Vector2<f32>> = vec![];
+    for pass in 0..2 {
+        let start = hull.len();
+        for &point in &points {
+            while hull.len() >= start + 2
+                && cross(hull[hull.len() - 2], hull[hull.len() - 1], point) <= 0.0
+            {
+                hull.pop();
+            }
+            hull.push(point);
+        }
+        // the last point is the first of the other chain
+        hull.pop();
+        if pass == 0 {
+            points.reverse();
+        }
+    }
+    hull
+}
+
+fn contains(hull: &[Vector2<f32>], point: Vector2<f32>) -> bool {
+    if hull.len() < 3 {
+        return false;
+    }
+    (0..hull.len()).all(|k| cross(hull[k], hull[(k + 1) % hull.len()], point) >= 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::{bounding_rectangle, footprint};
+    use crate::{camera::Camera, voxel::VoxelBlock};
+
+    #[test]
+    fn test_footprint() {
+        // a single voxel straight ahead, face on, covering the middle half of the image
+        let voxel_block = VoxelBlock::new(2, 1);
+        let pos = Vector3::new(0.0, 0.0, 5.0);
+        let camera = Camera::new(
+            16,
+            16,
+            pos,
+            Vector3::zeros(),
+            Vector3::y(),
+            2.0 * f32::atan(0.5),
+            0.01,
+            1000.0,
+        );
+        let pixels = footprint(&camera, &voxel_block, 0);
+
+        // the front face spans half the view each way from 4 units away, the back face less
+        assert_eq!(pixels.len(), 8 * 8);
+        assert!(pixels.contains(&(4 + 4 * 16)));
+        assert!(pixels.contains(&(11 + 11 * 16)));
+        assert!(!pixels.contains(&(3 + 4 * 16)));
+        // seen face on the hull is already a rectangle
+        assert_eq!(bounding_rectangle(&camera, &voxel_block, 0), pixels);
+
+        // nothing behind the camera
+        let behind = Camera::new(
+            16,
+            16,
+            pos,
+            Vector3::new(0.0, 0.0, 10.0),
+            Vector3::y(),
+            2.0 * f32::atan(0.5),
+            0.01,
+            1000.0,
+        );
+        assert!(footprint(&behind, &voxel_block, 0).is_empty());
+        assert!(bounding_rectangle(&behind, &voxel_block, 0).is_empty());
+    }
+}
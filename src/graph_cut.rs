@@ -0,0 +1,607 @@
+use std::collections::VecDeque;
+
+use nalgebra::Vector3;
+use serde::Deserialize;
+
+use crate::{
+    config::{CarvingConfig, CarvingMode},
+    consistency::{median_color, ViewSample},
+    image::Image,
+    provenance::{CameraSample, Provenance, Reason},
+    visual_hull::background_views,
+    voxel::{Votes, Voxel, VoxelBlock},
+};
+
+// capacities at or below this count as saturated
+const EPSILON: f32 = 1e-6;
+// the end of an edge list, or a voxel that isn't a node
+const NONE: u32 = u32::MAX;
+// what keeping the carve's label is worth to a voxel no view has evidence on, only enough to
+// break ties, so those follow their neighbors
+const UNTESTED_WEIGHT: f32 = 0.01;
+
+/// Smooths a carve by labeling every voxel occupied or empty at once, as a Markov random field
+/// solved exactly with a minimum cut. Each voxel pays for disagreeing with its own evidence, and
+/// each pair of 6-neighbors pays for taking different labels, so pits get filled and floating
+/// islands carved where that costs less than the evidence against it.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Regularization {
+    // cost of a face between an occupied and an empty voxel, higher gives smoother surfaces
+    pub(crate) smoothness: f32,
+    // cost of going against the color evidence, per view that agreed on a kept voxel's color or
+    // disagreed on a carved one, or per unit of log odds of probabilistic carving's occupancy
+    pub(crate) photo_weight: f32,
+    // cost of keeping a voxel, per view where its center lands on background, which
+    // probabilistic carving's occupancy already counts for the voxels it estimated
+    pub(crate) silhouette_weight: f32,
+    // how many voxels either side of the carve's surface can change, the rest keep their label
+    pub(crate) band: usize,
+}
+
+impl Default for Regularization {
+    fn default() -> Self {
+        Regularization {
+            // an isolated voxel or a single voxel pit costs 6 * 0.3 = 1.8 in faces,
+            // more than one view's evidence for it but not two
+            smoothness: 0.3,
+            photo_weight: 1.0,
+            silhouette_weight: 2.0,
+            band: 3,
+        }
+    }
+}
+
+impl Regularization {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("smoothness", self.smoothness),
+            ("photo_weight", self.photo_weight),
+            ("silhouette_weight", self.silhouette_weight),
+        ] {
+            if !(value.is_finite() && value >= 0.0) {
+                return Err(format!("{name} can't be negative, got {value}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the carve in the block with its regularized labeling, see `Regularization`, weighing
+/// the evidence `config`'s carve left on each voxel.
+/// Voxels it restores take the median color of their colored neighbors, spreading inwards from
+/// the voxels the carve kept, and lose their provenance. With `provenance` on, voxels it carves
+/// record the views that saw background there. Returns how many voxels it carved and restored.
+pub(crate) fn regularize(
+    voxel_block: &mut VoxelBlock,
+    images: &[Image],
+    config: &CarvingConfig,
+    regularization: &Regularization,
+) -> (usize, usize) {
+    let resolution = voxel_block.resolution;
+    let (nodes, node_count) = surface_band(voxel_block, regularization.band);
+    // the source side is occupied, the sink side empty
+    let (source, sink) = (node_count, node_count + 1);
+    let mut graph = FlowGraph::new(node_count + 2);
+
+    for (index, &node) in nodes.iter().enumerate() {
+        if node == NONE {
+            continue;
+        }
+        let voxel = &voxel_block.voxels[index];
+        let estimate = voxel_block
+            .occupancy
+            .as_ref()
+            .map(|estimates| &estimates[index])
+            .filter(|estimate| !estimate.evidence.is_empty());
+        // how much more carving the voxel costs than keeping it
+        let mut evidence = match (config.mode, estimate) {
+            (CarvingMode::Probabilistic(_), Some(estimate)) => {
+                let occupancy = estimate.occupancy.clamp(1e-6, 1.0 - 1e-6);
+                regularization.photo_weight * (occupancy / (1.0 - occupancy)).ln()
+            }
+            _ => {
+                let background =
+                    background_views(voxel_block, images, index, config.sampling).len() as f32;
+                let votes = voxel_block.votes(index);
+                regularization.photo_weight * color_evidence(voxel, votes, config)
+                    - regularization.silhouette_weight * background
+            }
+        };
+        if evidence == 0.0 {
+            evidence = if voxel.carved {
+                -UNTESTED_WEIGHT
+            } else {
+                UNTESTED_WEIGHT
+            };
+        }
+        let mut carve = evidence.max(0.0);
+        let mut keep = (-evidence).max(0.0);
+
+        let smoothness = regularization.smoothness;
+        for neighbor in neighbors(index, resolution) {
+            match nodes[neighbor] {
+                // outside the band neighbors keep their label, so only one side of the face costs
+                NONE if voxel_block.voxels[neighbor].carved => keep += smoothness,
+                NONE => carve += smoothness,
+                other if other > node => graph.add_edge(node, other, smoothness, smoothness),
+                _ => {}
+            }
+        }
+        // only the difference matters, cut when the voxel ends up on the other side
+        let shared = carve.min(keep);
+        graph.add_edge(source, node, carve - shared, 0.0);
+        graph.add_edge(node, sink, keep - shared, 0.0);
+    }
+
+    graph.max_flow(source, sink);
+    let source_side = graph.source_side(source);
+    let occupied: Vec<bool> = nodes
+        .iter()
+        .zip(&voxel_block.voxels)
+        .map(|(&node, voxel)| match node {
+            NONE => !voxel.carved,
+            node => source_side[node as usize],
+        })
+        .collect();
+
+    let mut carved = vec![];
+    let mut restored = vec![];
+    for (index, (voxel, &occupied)) in voxel_block.voxels.iter_mut().zip(&occupied).enumerate() {
+        if voxel.carved == occupied {
+            if occupied {
+                restored.push(index);
+                voxel.color = None;
+                if let Some(provenance) = &mut voxel_block.provenance {
+                    provenance.remove(&index);
+                }
+            } else {
+                carved.push(index);
+            }
+            voxel.carved = !occupied;
+        }
+    }
+    if config.provenance {
+        for &index in &carved {
+            let cameras = background_views(voxel_block, images, index, config.sampling)
+                .into_iter()
+                .map(|image| CameraSample { image, color: None })
+                .collect();
+            voxel_block.set_provenance(
+                index,
+                Provenance {
+                    round: None,
+                    sweep: None,
+                    slice: None,
+                    reason: Reason::Regularized,
+                    cameras,
+                },
+            );
+        }
+    }
+    color_restored(voxel_block, &restored);
+    voxel_block.update_visibility();
+    (carved.len(), restored.len())
+}
+
+/// Numbers the voxels at most `band` steps from the carve's surface, where a carved voxel meets
+/// a kept one or a kept one the edge of the block, as the graph's nodes. The rest are NONE.
+/// Returns the node of each voxel and how many there are.
+fn surface_band(voxel_block: &VoxelBlock, band: usize) -> (Vec<u32>, u32) {
+    let resolution = voxel_block.resolution;
+    let voxels = &voxel_block.voxels;
+    let mut distances = vec![usize::MAX; voxels.len()];
+    let mut queue: VecDeque<usize> = (0..voxels.len())
+        .filter(|&index| {
+            let carved = voxels[index].carved;
+            let exposed = !carved && neighbors(index, resolution).count() < 6;
+            exposed
+                || neighbors(index, resolution).any(|neighbor| voxels[neighbor].carved != carved)
+        })
+        .collect();
+    for &index in &queue {
+        distances[index] = 0;
+    }
+    while let Some(index) = queue.pop_front() {
+        if distances[index] == band {
+            continue;
+        }
+        for neighbor in neighbors(index, resolution) {
+            if distances[neighbor] == usize::MAX {
+                distances[neighbor] = distances[index] + 1;
+                queue.push_back(neighbor);
+            }
+        }
+    }
+
+    let count = distances
+        .iter()
+        .filter(|&&distance| distance != usize::MAX)
+        .count();
+    // 2 edges each for 2 terminal links and 3 neighbors per node, and the terminals
+    assert!(
+        (count + 2) * 10 < NONE as usize,
+        "Unable to number the edges of a graph of {count} voxels with u32"
+    );
+    let mut node = 0;
+    let nodes = distances
+        .iter()
+        .map(|&distance| {
+            if distance == usize::MAX {
+                return NONE;
+            }
+            node += 1;
+            node - 1
+        })
+        .collect();
+    (nodes, count as u32)
+}
+
+/// How many views the last test of the voxel had agreeing on its color, or against it where it
+/// was carved for disagreeing. Background is left to the silhouette term.
+fn color_evidence(voxel: &Voxel, votes: Votes, config: &CarvingConfig) -> f32 {
+    let agreeing = votes
+        .foreground
+        .saturating_sub(votes.outliers + votes.highlights);
+    let disagreeing = votes.foreground.saturating_sub(votes.highlights);
+    match (voxel.carved, voxel.color) {
+        // voxels the carve kept without a color were never tested
+        (false, None) => 0.0,
+        (false, Some(_)) => agreeing as f32,
+        (true, _) if votes.background >= config.voting.min_background_views => 0.0,
+        (true, _) => -(disagreeing as f32),
+    }
+}
+
+/// Colors restored voxels like their colored 6-neighbors, a pass at a time so that voxels deeper
+/// in a filled pit take their color from the ones that got one before them. Voxels with no
+/// colored voxel anywhere around them stay without one.
+fn color_restored(voxel_block: &mut VoxelBlock, restored: &[usize]) {
+    let resolution = voxel_block.resolution;
+    let mut uncolored = restored.to_vec();
+    loop {
+        let colors: Vec<Option<Vector3<u8>>> = uncolored
+            .iter()
+            .map(|&index| {
+                let samples: Vec<ViewSample> = neighbors(index, resolution)
+                    .filter_map(|neighbor| {
+                        let voxel = &voxel_block.voxels[neighbor];
+                        let color = voxel.color.filter(|_| !voxel.carved)?;
+                        Some(ViewSample {
+                            color,
+                            patch: vec![],
+                        })
+                    })
+                    .collect();
+                (!samples.is_empty()).then(|| median_color(&samples))
+            })
+            .collect();
+        if colors.iter().all(Option::is_none) {
+            return;
+        }
+        for (&index, color) in uncolored.iter().zip(&colors) {
+            voxel_block.voxels[index].color = *color;
+        }
+        uncolored = uncolored
+            .into_iter()
+            .zip(colors)
+            .filter_map(|(index, color)| color.is_none().then_some(index))
+            .collect();
+    }
+}
+
+/// Indices of the voxel's 6-neighbors inside the block
+fn neighbors(index: usize, resolution: usize) -> impl Iterator<Item = usize> {
+    let coordinates = [
+        index % resolution,
+        index / resolution % resolution,
+        index / (resolution * resolution),
+    ];
+    let steps = [1, resolution, resolution * resolution];
+    coordinates
+        .into_iter()
+        .zip(steps)
+        .flat_map(move |(coordinate, step)| {
+            [
+                (coordinate > 0).then(|| index - step),
+                (coordinate + 1 < resolution).then(|| index + step),
+            ]
+        })
+        .flatten()
+}
+
+/// A flow network for Dinic's algorithm, edges stored in pairs with their reverse. Nodes and
+/// edges are numbered with u32 to keep the graph of a large block in memory.
+struct FlowGraph {
+    // first edge out of each node
+    head: Vec<u32>,
+    // per edge, where it goes, how much more it can carry, and the next edge out of its node
+    to: Vec<u32>,
+    capacity: Vec<f32>,
+    next: Vec<u32>,
+}
+
+impl FlowGraph {
+    fn new(nodes: u32) -> Self {
+        FlowGraph {
+            head: vec![NONE; nodes as usize],
+            to: vec![],
+            capacity: vec![],
+            next: vec![],
+        }
+    }
+
+    /// An edge from `a` to `b` and its reverse, with their capacities. Edges that can't carry
+    /// anything either way are left out.
+    fn add_edge(&mut self, a: u32, b: u32, capacity: f32, reverse_capacity: f32) {
+        if capacity <= 0.0 && reverse_capacity <= 0.0 {
+            return;
+        }
+        for (from, to, capacity) in [(a, b, capacity), (b, a, reverse_capacity)] {
+            self.to.push(to);
+            self.capacity.push(capacity);
+            self.next.push(self.head[from as usize]);
+            self.head[from as usize] = (self.to.len() - 1) as u32;
+        }
+    }
+
+    fn max_flow(&mut self, source: u32, sink: u32) -> f32 {
+        let mut flow = 0.0;
+        while let Some(levels) = self.levels(source, sink) {
+            flow += self.blocking_flow(source, sink, &levels);
+        }
+        flow
+    }
+
+    /// Breadth first distances from the source over unsaturated edges, None once the sink is cut off
+    fn levels(&self, source: u32, sink: u32) -> Option<Vec<u32>> {
+        let mut levels = vec![NONE; self.head.len()];
+        levels[source as usize] = 0;
+        let mut queue = VecDeque::from([source]);
+        while let Some(node) = queue.pop_front() {
+            let mut edge = self.head[node as usize];
+            while edge != NONE {
+                let to = self.to[edge as usize] as usize;
+                if self.capacity[edge as usize] > EPSILON && levels[to] == NONE {
+                    levels[to] = levels[node as usize] + 1;
+                    queue.push_back(to as u32);
+                }
+                edge = self.next[edge as usize];
+            }
+        }
+        (levels[sink as usize] != NONE).then_some(levels)
+    }
+
+    /// Saturates every shortest path, walking them with an explicit stack since they can be as
+    /// long as the block is wide several times over.
+    fn blocking_flow(&mut self, source: u32, sink: u32, levels: &[u32]) -> f32 {
+        // the next edge to try out of each node
+        let mut current = self.head.clone();
+        let mut path: Vec<u32> = vec![];
+        let mut flow = 0.0;
+        let mut node = source;
+        loop {
+            if node == sink {
+                let bottleneck = path
+                    .iter()
+                    .map(|&edge| self.capacity[edge as usize])
+                    .fold(f32::INFINITY, f32::min);
+                for &edge in &path {
+                    self.capacity[edge as usize] -= bottleneck;
+                    self.capacity[(edge ^ 1) as usize] += bottleneck;
+                }
+                flow += bottleneck;
+                // back up to before the first edge it saturated
+                let saturated = path
+                    .iter()
+                    .position(|&edge| self.capacity[edge as usize] <= EPSILON)
+                    .unwrap_or(0);
+                path.truncate(saturated);
+                node = path.last().map_or(source, |&edge| self.to[edge as usize]);
+                continue;
+            }
+
+            let at = node as usize;
+            while current[at] != NONE {
+                let edge = current[at] as usize;
+                let to = self.to[edge] as usize;
+                if self.capacity[edge] > EPSILON && levels[to] == levels[at] + 1 {
+                    break;
+                }
+                current[at] = self.next[edge];
+            }
+            match current[at] {
+                NONE if node == source => return flow,
+                NONE => {
+                    // a dead end, never worth trying again this phase
+                    let edge = path.pop().expect("Unable to back up past the source");
+                    node = self.to[(edge ^ 1) as usize];
+                    current[node as usize] = self.next[current[node as usize] as usize];
+                }
+                edge => {
+                    path.push(edge);
+                    node = self.to[edge as usize];
+                }
+            }
+        }
+    }
+
+    /// Which nodes the source still reaches, the source side of a minimum cut after `max_flow`
+    fn source_side(&self, source: u32) -> Vec<bool> {
+        let levels = self
+            .levels(source, source)
+            .expect("Unable to reach the source");
+        levels.iter().map(|&level| level != NONE).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use nalgebra::Vector3;
+
+    use super::{regularize, surface_band, FlowGraph, Regularization, NONE};
+    use crate::{
+        carver::Observation,
+        config::{CarvingConfig, CarvingMode},
+        probabilistic::{Estimate, OccupancyModel},
+        provenance::{Provenance, Reason},
+        voxel::{Votes, VoxelBlock},
+    };
+
+    #[test]
+    fn test_regularize() {
+        // the flow network from Cormen et al., with a maximum flow of 23
+        let mut graph = FlowGraph::new(6);
+        for (a, b, capacity) in [
+            (0, 1, 16.0),
+            (0, 2, 13.0),
+            (2, 1, 4.0),
+            (1, 3, 12.0),
+            (3, 2, 9.0),
+            (2, 4, 14.0),
+            (4, 3, 7.0),
+            (3, 5, 20.0),
+            (4, 5, 4.0),
+        ] {
+            graph.add_edge(a, b, capacity, 0.0);
+        }
+        assert!((graph.max_flow(0, 5) - 23.0).abs() < 1e-4);
+        assert_eq!(
+            graph.source_side(0),
+            vec![true, true, true, false, true, false]
+        );
+
+        // a cube carved out of a block, with a pit in one face and a voxel floating beside it
+        let resolution = 8;
+        let index = |x: usize, y: usize, z: usize| x + y * resolution + z * resolution * resolution;
+        let in_cube = |index: usize| {
+            [
+                index % resolution,
+                index / resolution % resolution,
+                index / (resolution * resolution),
+            ]
+            .iter()
+            .all(|coordinate| (2..6).contains(coordinate))
+        };
+        // two views agreed on each voxel of the cube and disagreed on each one around it
+        let votes = |foreground| Votes {
+            foreground,
+            ..Default::default()
+        };
+        let mut voxel_block = VoxelBlock::new(2, resolution);
+        voxel_block.votes = Some(vec![votes(2); voxel_block.voxels.len()]);
+        voxel_block.provenance = Some(HashMap::new());
+        for i in 0..voxel_block.voxels.len() {
+            if in_cube(i) {
+                voxel_block.voxels[i].color = Some(Vector3::new(200, 100, i as u8));
+            } else {
+                voxel_block.carve(i);
+            }
+        }
+        // on the evidence of fewer views
+        let pit = index(2, 3, 3);
+        voxel_block.carve(pit);
+        voxel_block.set_votes(pit, votes(1));
+        voxel_block.set_provenance(
+            pit,
+            Provenance {
+                round: Some(0),
+                sweep: None,
+                slice: Some(2),
+                reason: Reason::Inconsistent,
+                cameras: vec![],
+            },
+        );
+        let island = index(1, 1, 6);
+        voxel_block.voxels[island].carved = false;
+        voxel_block.voxels[island].color = Some(Vector3::new(200, 100, 0));
+        voxel_block.set_votes(island, votes(1));
+        voxel_block.update_visibility();
+        let before = voxel_block.clone();
+
+        let config = CarvingConfig {
+            provenance: true,
+            ..Default::default()
+        };
+        let regularization = Regularization::default();
+        let (carved, restored) = regularize(&mut voxel_block, &[], &config, &regularization);
+        assert_eq!((carved, restored), (1, 1));
+        for (i, voxel) in voxel_block.voxels.iter().enumerate() {
+            assert_eq!(voxel.carved, !in_cube(i), "voxel {i}");
+        }
+        // only carved voxels say how they were carved
+        assert_eq!(voxel_block.provenance(pit), None);
+        let provenance = voxel_block.provenance(island).unwrap();
+        assert_eq!(provenance.reason, Reason::Regularized);
+        // the filled pit is back on the surface, the middle of the cube isn't
+        assert!(voxel_block.voxels[pit].visible);
+        assert!(!voxel_block.voxels[index(3, 3, 3)].visible);
+        // in the median color of the cube around it, so it gets written with the rest
+        let color = voxel_block.voxels[pit].color.unwrap();
+        assert_eq!((color.x, color.y), (200, 100));
+        let path = std::env::temp_dir().join("voxel_carving_test_regularize.obj");
+        let path = path.to_str().unwrap();
+        voxel_block.clone().save_to_file(path);
+        // the pit's left face, on the cube's surface
+        let vertex = |x: usize, y: usize, z: usize| {
+            x + y * (resolution + 1) + z * (resolution + 1) * (resolution + 1) + 1
+        };
+        let face = format!(
+            "f {} {} {}",
+            vertex(2, 3, 4),
+            vertex(2, 4, 4),
+            vertex(2, 3, 3)
+        );
+        let contents = std::fs::read_to_string(path).unwrap();
+        assert!(contents.lines().any(|line| line == face));
+
+        // a band of just the surface is enough for single voxels, and leaves out the deep inside
+        let (nodes, count) = surface_band(&before, 0);
+        assert_eq!(nodes[index(4, 4, 4)], NONE);
+        assert_eq!(nodes[index(7, 0, 0)], NONE);
+        assert!((count as usize) < before.voxels.len() / 2);
+        let regularization = Regularization {
+            band: 0,
+            ..Default::default()
+        };
+        let mut banded = before.clone();
+        assert_eq!(
+            regularize(&mut banded, &[], &config, &regularization),
+            (1, 1)
+        );
+
+        // without smoothness the carve stands as it was
+        let mut unsmoothed = before.clone();
+        let regularization = Regularization {
+            smoothness: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            regularize(&mut unsmoothed, &[], &config, &regularization),
+            (0, 0)
+        );
+        assert!(unsmoothed.voxels[pit].carved);
+
+        // and so does a pit more views disagreed on, or an island probabilistic carving is sure of
+        let mut voxel_block = before;
+        voxel_block.set_votes(pit, votes(3));
+        let mut estimates = vec![Estimate::default(); voxel_block.voxels.len()];
+        let observation = Observation::Color(Vector3::new(200, 100, 0), None);
+        estimates[island] = Estimate {
+            occupancy: 0.99,
+            evidence: vec![(0, observation)],
+        };
+        voxel_block.occupancy = Some(estimates);
+        let config = CarvingConfig {
+            mode: CarvingMode::Probabilistic(OccupancyModel::default()),
+            ..Default::default()
+        };
+        let regularization = Regularization::default();
+        assert_eq!(
+            regularize(&mut voxel_block, &[], &config, &regularization),
+            (0, 0)
+        );
+    }
+}
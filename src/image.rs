@@ -1,14 +1,20 @@
 use image::open;
-use nalgebra::Vector3;
+use nalgebra::{Vector2, Vector3};
 
-use crate::camera::Camera;
+use crate::{camera::Camera, projection::ProjectionCache};
 
 #[derive(Clone)]
 pub(crate) struct Image {
+    // where the image came from, used to label it in exports
+    pub(crate) name: String,
     // rgb data from image
     pub(crate) data: Vec<u8>,
     pub(crate) marked: Vec<bool>,
+    // color of the pixels that aren't the object
+    pub(crate) background: Vector3<u8>,
     pub(crate) camera: Camera,
+    // where the voxels of the block being carved land, if caching projections
+    pub(crate) projections: Option<ProjectionCache>,
     pub(crate) width: usize,
     pub(crate) height: usize,
 }
@@ -24,19 +30,176 @@ impl Image {
         height: usize,
     ) -> Self {
         // read from file
-        let image = open(file_path).unwrap().into_rgb8().into_vec();
+        let image = open(&file_path).unwrap().into_rgb8().into_vec();
         let marked = vec![false; image.len() / 3];
 
-        let look = focus - pos;
         let near = 0.01;
         let far = 1000.0;
-        let camera = Camera::new(width, height, pos, focus, look, up, height_angle, near, far);
+        let camera = Camera::new(width, height, pos, focus, up, height_angle, near, far);
         Image {
+            name: file_path,
             data: image,
             marked,
+            background: Vector3::zeros(),
             camera,
+            projections: None,
             width,
             height,
         }
     }
+
+    /// wraps already decoded rgb data with the camera it was taken from
+    pub(crate) fn new(name: String, data: Vec<u8>, camera: Camera) -> Self {
+        assert_eq!(data.len(), camera.width * camera.height * 3);
+        let marked = vec![false; data.len() / 3];
+        Image {
+            name,
+            data,
+            marked,
+            background: Vector3::zeros(),
+            camera,
+            projections: None,
+            width: camera.width,
+            height: camera.height,
+        }
+    }
+
+    /// The image flipped left to right, with the same camera
+    pub(crate) fn mirrored(mut self) -> Self {
+        for row in self.data.chunks_exact_mut(self.width * 3) {
+            row.reverse();
+            // reversing the bytes reversed each pixel's channels too
+            for pixel in row.chunks_exact_mut(3) {
+                pixel.reverse();
+            }
+        }
+        self
+    }
+
+    /// pixels of exactly the background color, black unless configured otherwise
+    pub(crate) fn is_background(&self, index: usize) -> bool {
+        self.data[index * 3..index * 3 + 3] == *self.background.as_slice()
+    }
+
+    /// Bilinearly samples the image at continuous image coordinates, where pixel (i, j) is centered
+    /// at (i + 0.5, j + 0.5). Returns None when the sample is mostly background, otherwise the
+    /// color interpolated over the foreground pixels only so silhouette edges don't darken it.
+    pub(crate) fn sample(&self, pixel: Vector2<f32>) -> Option<Vector3<u8>> {
+        let x = (pixel.x - 0.5).clamp(0.0, (self.width - 1) as f32);
+        let y = (pixel.y - 0.5).clamp(0.0, (self.height - 1) as f32);
+        let x0 = x.floor() as usize;
+        let y0 = y.floor() as usize;
+        let x1 = (x0 + 1).min(self.width - 1);
+        let y1 = (y0 + 1).min(self.height - 1);
+        let fx = x - x0 as f32;
+        let fy = y - y0 as f32;
+        let taps = [
+            (x0, y0, (1.0 - fx) * (1.0 - fy)),
+            (x1, y0, fx * (1.0 - fy)),
+            (x0, y1, (1.0 - fx) * fy),
+            (x1, y1, fx * fy),
+        ];
+
+        let mut background = 0.0;
+        let mut color = Vector3::zeros();
+        for (x, y, weight) in taps {
+            let index = x + y * self.width;
+            if self.is_background(index) {
+                background += weight;
+            } else {
+                let rgb = &self.data[index * 3..index * 3 + 3];
+                color += Vector3::new(rgb[0] as f32, rgb[1] as f32, rgb[2] as f32) * weight;
+            }
+        }
+        if background >= 0.5 {
+            return None;
+        }
+        let color = color / (1.0 - background);
+        Some(color.map(|c| c.round().clamp(0.0, 255.0) as u8))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Vector2, Vector3};
+
+    use super::Image;
+    use crate::camera::Camera;
+
+    fn image_from_rows(rows: &[&[[u8; 3]]]) -> Image {
+        let width = rows[0].len();
+        let height = rows.len();
+        let data = rows
+            .iter()
+            .flat_map(|row| row.iter().flatten().copied())
+            .collect();
+        let camera = Camera::new(
+            width,
+            height,
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector3::zeros(),
+            Vector3::y(),
+            0.5,
+            0.01,
+            1000.0,
+        );
+        Image::new("test".to_owned(), data, camera)
+    }
+
+    #[test]
+    fn test_sample() {
+        let image = image_from_rows(&[
+            &[[100, 0, 0], [200, 0, 0], [0, 0, 0]],
+            &[[100, 100, 0], [200, 100, 0], [0, 0, 0]],
+        ]);
+        // pixel centers return the pixel
+        assert_eq!(
+            image.sample(Vector2::new(0.5, 0.5)),
+            Some(Vector3::new(100, 0, 0))
+        );
+        assert_eq!(
+            image.sample(Vector2::new(1.5, 1.5)),
+            Some(Vector3::new(200, 100, 0))
+        );
+        // in between pixels interpolates
+        assert_eq!(
+            image.sample(Vector2::new(1.0, 1.0)),
+            Some(Vector3::new(150, 50, 0))
+        );
+        // background only counts by its weight and doesn't darken the color
+        assert_eq!(
+            image.sample(Vector2::new(1.75, 0.5)),
+            Some(Vector3::new(200, 0, 0))
+        );
+        assert_eq!(image.sample(Vector2::new(2.25, 0.5)), None);
+        assert_eq!(image.sample(Vector2::new(2.5, 1.5)), None);
+    }
+
+    #[test]
+    fn test_mirrored() {
+        let image = image_from_rows(&[
+            &[[100, 0, 0], [200, 0, 0], [0, 0, 0]],
+            &[[100, 100, 0], [200, 100, 0], [0, 0, 7]],
+        ]);
+        let mirrored = image.clone().mirrored();
+        assert_eq!(
+            mirrored.data,
+            [
+                [0, 0, 0],
+                [200, 0, 0],
+                [100, 0, 0],
+                [0, 0, 7],
+                [200, 100, 0],
+                [100, 100, 0]
+            ]
+            .concat()
+        );
+        // sampling the mirror samples the image at the mirrored coordinates
+        for (x, y) in [(0.5, 0.5), (1.0, 1.0), (1.75, 1.5), (2.25, 0.5)] {
+            assert_eq!(
+                mirrored.sample(Vector2::new(x, y)),
+                image.sample(Vector2::new(3.0 - x, y))
+            );
+        }
+    }
 }
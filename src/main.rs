@@ -1,25 +1,147 @@
-use carver::carve;
+use std::fs;
+
+use args::{Args, USAGE};
+use bounds::estimate_bounds;
+use calibration::{calibrate_from_files, Checkerboard};
+use camera::Lens;
+use carver::{carve, carve_with_new_views};
+use config::{CarvingConfig, CarvingMode};
+use image::Image;
+use nalgebra::Vector3;
+use progress::{CancellationToken, PrintProgress};
+use provenance::explain;
+use refinement::{carve_with_refinement, RefinementOptions};
+use rig::save_rig_to_file;
 use voxel::VoxelBlock;
 
+mod args;
+mod bounds;
+mod calibration;
 mod camera;
 mod carver;
+mod config;
+mod consistency;
+mod footprint;
+mod graph_cut;
 mod image;
+mod probabilistic;
+mod progress;
+mod projection;
+mod provenance;
 mod raytracer;
+mod refinement;
+mod rig;
 mod scene_generator;
+mod stats;
+#[cfg(test)]
+mod test_scenes;
+mod visibility;
+mod visual_hull;
 mod voxel;
-
-const LENGTH: usize = 4;
-const RESOLUTION: usize = 100;
+mod voxel_coloring;
 
 fn main() {
     let start: std::time::Instant = std::time::Instant::now();
-    let images = &mut scene_generator::two_cones();
+    let args = Args::parse(std::env::args().skip(1))
+        .unwrap_or_else(|error| panic!("Unable to parse arguments: {error}\n{USAGE}"));
+    let config = match &args.config {
+        Some(file_path) => CarvingConfig::from_file(file_path)
+            .unwrap_or_else(|error| panic!("Unable to load carving config: {error}")),
+        None => CarvingConfig::default(),
+    };
+    let images = &mut match &args.calibration_board {
+        Some(board) => calibrated_images(board),
+        None => scene_generator::by_name(&args.scene).expect("Unable to find scene"),
+    };
+    for image in images.iter_mut() {
+        // the bounds are estimated from the silhouettes before any carve sets this
+        image.background = config.background.into();
+        if let Some(field_of_view) = args.fisheye_field_of_view {
+            image.camera = image
+                .camera
+                .with_lens(Lens::equidistant(field_of_view, image.height));
+        }
+    }
+
+    let bounds = if args.estimate_bounds && args.continue_with_new_views.is_none() {
+        let bounds = estimate_bounds(images, args.bounds_padding);
+        if bounds.is_none() {
+            println!(
+                "Unable to estimate bounds, no view sees the object or the views don't agree \
+                 where it is, carving the configured block around the origin instead"
+            );
+        }
+        bounds
+    } else {
+        None
+    };
+    let mut voxel_block = match (args.continue_with_new_views, bounds) {
+        (Some(_), _) => VoxelBlock::load_state_from_file("./data/output/block.txt")
+            .expect("Unable to load saved block"),
+        (None, Some((min, max))) => VoxelBlock::from_bounds(min, max, config.resolution),
+        (None, None) => VoxelBlock::with_center(Vector3::zeros(), config.length, config.resolution),
+    };
+    // check this in a viewer when a scene doesn't carve as expected
+    save_rig_to_file(images, &voxel_block, "./data/output/rig.obj");
+    let stats = match (args.refine_poses, args.continue_with_new_views) {
+        (true, _) => carve_with_refinement(
+            &mut voxel_block,
+            images,
+            &config,
+            &RefinementOptions::default(),
+            &mut PrintProgress,
+            &CancellationToken::default(),
+        ),
+        (false, Some(first_new)) => carve_with_new_views(
+            &mut voxel_block,
+            images,
+            first_new,
+            &config,
+            &mut PrintProgress,
+            &CancellationToken::default(),
+        ),
+        (false, None) => carve(&mut voxel_block, images, &config),
+    };
+    fs::write("./data/output/stats.json", stats.to_json()).expect("Unable to write stats");
 
-    let mut voxel_block = VoxelBlock::new(LENGTH, RESOLUTION);
-    carve(&mut voxel_block, images);
+    for &(x, y, z) in &args.explain {
+        match explain(&voxel_block, images, &config, x, y, z) {
+            Ok(explanation) => println!("{explanation}"),
+            Err(error) => println!("Unable to explain voxel: {error}"),
+        }
+    }
 
+    if let CarvingMode::Probabilistic(_) = config.mode {
+        voxel_block.save_occupancy_to_file("./data/output/occupancy.txt");
+    }
+    voxel_block.save_state_to_file("./data/output/block.txt");
     voxel_block.save_to_file("./data/output/mesh.obj");
 
     let duration = start.elapsed();
     println!("Elapsed time: {:?}", duration);
 }
+
+/// The photos in ./data/input/photos with the cameras calibrated from the checkerboard photos in
+/// ./data/input/calibration, the two paired up in file name order
+fn calibrated_images(board: &Checkerboard) -> Vec<Image> {
+    let files = |directory: &str| -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(directory)
+            .expect("Unable to read directory")
+            .map(|entry| {
+                let entry = entry.expect("Unable to read directory entry");
+                entry.path().to_string_lossy().into_owned()
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let calibration = calibrate_from_files(&files("./data/input/calibration"), board)
+        .unwrap_or_else(|error| panic!("Unable to calibrate the cameras: {error}"));
+    println!(
+        "calibrated with a reprojection error of {:.3} pixels",
+        calibration.rms_error
+    );
+    calibration
+        .images(&files("./data/input/photos"))
+        .unwrap_or_else(|error| panic!("Unable to load photos: {error}"))
+}
@@ -0,0 +1,262 @@
+use nalgebra::Vector3;
+use serde::Deserialize;
+
+use crate::{
+    carver::{observe, Consistency, Observation},
+    config::CarvingConfig,
+    image::Image,
+    visibility::ItemBuffer,
+    voxel::{Votes, VoxelBlock},
+};
+
+/// What each image that has seen a voxel made of it, by image index
+pub(crate) type Evidence = Vec<(usize, Observation)>;
+
+/// A voxel's probability of being occupied and the evidence it rests on
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Estimate {
+    pub(crate) occupancy: f32,
+    pub(crate) evidence: Evidence,
+}
+
+/// How views' evidence turns into the probability that a voxel is occupied.
+/// An occupied voxel shows up in a view as foreground of its own color, give or take noise.
+/// An empty one shows whatever is behind it, background or not with even odds, in any color.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OccupancyModel {
+    // probability of a voxel being occupied before any view is considered
+    pub(crate) prior: f32,
+    // chance that a pixel of an occupied voxel was segmented as background
+    pub(crate) silhouette_error: f32,
+    // standard deviation of a view's color around the voxel's true color, per channel
+    pub(crate) color_sigma: f32,
+    // voxels less likely than this are carved, 0.5 is the maximum a posteriori estimate
+    pub(crate) threshold: f32,
+}
+
+impl Default for OccupancyModel {
+    fn default() -> Self {
+        OccupancyModel {
+            prior: 0.5,
+            silhouette_error: 0.05,
+            color_sigma: 10.0,
+            threshold: 0.5,
+        }
+    }
+}
+
+impl OccupancyModel {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("prior", self.prior),
+            ("silhouette_error", self.silhouette_error),
+            ("threshold", self.threshold),
+        ] {
+            if !(value > 0.0 && value < 1.0) {
+                return Err(format!("{name} must be in (0, 1), got {value}"));
+            }
+        }
+        if !(self.color_sigma.is_finite() && self.color_sigma > 0.0) {
+            return Err(format!(
+                "color_sigma must be positive, got {}",
+                self.color_sigma
+            ));
+        }
+        Ok(())
+    }
+
+    /// Log odds of the voxel being occupied given the views that see it, with the mean
+    /// foreground color. None if no view sees the voxel.
+    fn posterior(&self, observations: &[Observation]) -> Option<(f32, Option<Vector3<u8>>)> {
+        if observations.is_empty() {
+            return None;
+        }
+        let mut log_odds = (self.prior / (1.0 - self.prior)).ln();
+        let mut colors = vec![];
+        for observation in observations {
+            match observation {
+                Observation::Background => log_odds += (self.silhouette_error / 0.5).ln(),
+                Observation::Color(color, _) => {
+                    log_odds += ((1.0 - self.silhouette_error) / 0.5).ln();
+                    colors.push(color.cast::<f32>());
+                }
+                Observation::Unseen => {}
+            }
+        }
+
+        // each view's color against the mean of the others, a single view can't disagree
+        if colors.len() > 1 {
+            let sum: Vector3<f32> = colors.iter().sum();
+            let variance = self.color_sigma * self.color_sigma;
+            // 3d gaussian around the true color against uniform over the color cube
+            let log_ratio_at_zero =
+                -1.5 * (2.0 * std::f32::consts::PI * variance).ln() + 3.0 * 256f32.ln();
+            for color in &colors {
+                let others = (sum - color) / (colors.len() - 1) as f32;
+                log_odds += log_ratio_at_zero - (color - others).norm_squared() / (2.0 * variance);
+            }
+        }
+
+        let color = (!colors.is_empty()).then(|| {
+            (colors.iter().sum::<Vector3<f32>>() / colors.len() as f32)
+                .map(|channel| channel.round() as u8)
+        });
+        Some((log_odds, color))
+    }
+}
+
+/// The voxel's occupancy probability given every view that has seen it so far, its evidence
+/// from earlier tests updated with what `views` see now, None if no view ever has. A view seeing
+/// it again replaces what it saw before, so each view counts once however many sweeps and rounds
+/// it takes part in. `view_images` maps positions in `views` to image indices. Voxels below the
+/// model's threshold come back inconsistent, to be carved, and the rest consistent in their mean
+/// color. Unseen voxels are inconclusive.
+pub(crate) fn estimate_occupancy(
+    index: usize,
+    views: &[(&mut Image, &ItemBuffer)],
+    view_images: &[usize],
+    voxel_block: &VoxelBlock,
+    config: &CarvingConfig,
+    model: &OccupancyModel,
+) -> (Consistency, Votes, Option<Estimate>) {
+    let mut evidence = voxel_block
+        .occupancy
+        .as_ref()
+        .map_or(vec![], |estimates| estimates[index].evidence.clone());
+    let mut seen_by = vec![];
+    for (view, (image, item_buffer)) in views.iter().enumerate() {
+        let observation = observe(index, image, item_buffer, voxel_block, config.sampling);
+        if matches!(observation, Observation::Unseen) {
+            continue;
+        }
+        seen_by.push(view);
+        let image = view_images[view];
+        match evidence.iter_mut().find(|(seen, _)| *seen == image) {
+            Some((_, seen)) => *seen = observation,
+            None => evidence.push((image, observation)),
+        }
+    }
+    let observations: Vec<Observation> = evidence
+        .iter()
+        .map(|(_, observation)| observation.clone())
+        .collect();
+    let foreground = observations
+        .iter()
+        .filter(|observation| matches!(observation, Observation::Color(..)))
+        .count();
+    let votes = Votes {
+        background: observations.len() - foreground,
+        foreground,
+        ..Default::default()
+    };
+    let Some((log_odds, color)) = model.posterior(&observations) else {
+        return (Consistency::Inconclusive, votes, None);
+    };
+
+    let occupancy = 1.0 / (1.0 + (-log_odds).exp());
+    let consistency = match color {
+        Some(color) if occupancy >= model.threshold => Consistency::Consistent(color, seen_by),
+        // only background views, likely enough to be left for other views to decide
+        None if occupancy >= model.threshold => Consistency::Inconclusive,
+        _ => Consistency::Inconsistent(seen_by),
+    };
+    (
+        consistency,
+        votes,
+        Some(Estimate {
+            occupancy,
+            evidence,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Vector2, Vector3};
+
+    use super::OccupancyModel;
+    use crate::{
+        carver::{carve, Observation},
+        config::{CarvingConfig, CarvingMode},
+        test_scenes::render_around,
+        voxel::VoxelBlock,
+    };
+
+    #[test]
+    fn test_posterior() {
+        let model = OccupancyModel::default();
+        let probability = |observations: &[Observation]| {
+            let (log_odds, _) = model.posterior(observations).unwrap();
+            1.0 / (1.0 + (-log_odds).exp())
+        };
+        let color = |r: u8| Observation::Color(Vector3::new(r, 100, 100), Some(Vector2::zeros()));
+
+        assert!(model.posterior(&[]).is_none());
+        // agreeing views make the voxel likely, and more of them more so
+        assert!(probability(&[color(100), color(104)]) > 0.99);
+        let log_odds = |observations: &[Observation]| model.posterior(observations).unwrap().0;
+        assert!(
+            log_odds(&[color(100), color(104), color(98)]) > log_odds(&[color(100), color(104)])
+        );
+        // a view that disagrees, or one that sees background, outweighs the others
+        assert!(probability(&[color(100), color(104), color(200)]) < 0.5);
+        assert!(probability(&[color(100), Observation::Background]) < 0.5);
+        // the mean color comes back
+        let (_, mean) = model.posterior(&[color(100), color(104)]).unwrap();
+        assert_eq!(mean, Some(Vector3::new(102, 100, 100)));
+    }
+
+    #[test]
+    fn test_probabilistic_carve() {
+        let mut object = VoxelBlock::new(2, 2);
+        object.carve(7);
+        let mut images = render_around(&object);
+        let mut voxel_block = VoxelBlock::new(2, 6);
+        let model = OccupancyModel::default();
+        let config = CarvingConfig {
+            mode: CarvingMode::Probabilistic(model),
+            ..Default::default()
+        };
+        carve(&mut voxel_block, &mut images, &config);
+
+        let path = std::env::temp_dir().join("voxel_carving_test_occupancy.txt");
+        let path = path.to_str().unwrap();
+        voxel_block.save_occupancy_to_file(path);
+        let contents = std::fs::read_to_string(path).unwrap();
+        assert_eq!(contents.lines().count(), voxel_block.voxels.len() + 1);
+
+        let mut accumulated = false;
+        let estimates = voxel_block.occupancy.as_ref().unwrap();
+        for ((voxel, estimate), line) in voxel_block
+            .voxels
+            .iter()
+            .zip(estimates)
+            .zip(contents.lines().skip(1))
+        {
+            if estimate.evidence.is_empty() {
+                // never tested, so no estimate rather than the prior
+                assert_eq!(line, "-");
+                continue;
+            }
+            let occupancy: f32 = line.parse().unwrap();
+            assert_eq!(occupancy, estimate.occupancy);
+            assert_eq!(voxel.carved, occupancy < model.threshold);
+            // each image counts once, however many sweeps it saw the voxel in
+            let mut images: Vec<usize> =
+                estimate.evidence.iter().map(|(image, _)| *image).collect();
+            images.sort_unstable();
+            images.dedup();
+            assert_eq!(images.len(), estimate.evidence.len());
+            accumulated |= images.len() > 1;
+        }
+        assert!(accumulated);
+        // the missing corner's voxels were carved on the views' evidence
+        let corner = 5 + 5 * 6 + 5 * 36;
+        assert!(voxel_block.voxels[corner].carved);
+        assert!(!estimates[corner].evidence.is_empty());
+        // the middle of the block was never seen
+        let middle = 2 + 2 * 6 + 2 * 36;
+        assert_eq!(contents.lines().nth(middle + 1), Some("-"));
+    }
+}
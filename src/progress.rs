@@ -0,0 +1,82 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::config::Sweep;
+
+/// Hears how a carve is going. Everything is reported between slices, one call at a time,
+/// and every method does nothing unless overridden.
+pub(crate) trait CarvingObserver: Send {
+    /// A space carving round, counted from 0, is about to run every sweep.
+    fn round_started(&mut self, _round: usize) {}
+
+    /// A sweep is starting, `views` images take part in at least one of its slices.
+    fn sweep_started(&mut self, _sweep: Sweep, _views: usize) {}
+
+    /// Slice `slice` of `slices` is done, a sweep's slice or a voxel coloring layer.
+    fn slice_finished(&mut self, _slice: usize, _slices: usize, _carved: usize) {}
+
+    fn sweep_finished(&mut self, _sweep: Sweep, _carved: usize) {}
+
+    fn round_finished(&mut self, _round: usize, _carved: usize) {}
+
+    /// The whole carve is done, or was cancelled, having carved `carved` voxels.
+    fn finished(&mut self, _carved: usize) {}
+
+    /// Anything else worth telling the user, e.g. a mode falling back to another.
+    fn message(&mut self, _message: &str) {}
+}
+
+/// Prints rounds, sweeps and messages, the way the carver always has.
+pub(crate) struct PrintProgress;
+
+impl CarvingObserver for PrintProgress {
+    fn round_started(&mut self, round: usize) {
+        println!("round {round}");
+    }
+
+    fn sweep_started(&mut self, sweep: Sweep, views: usize) {
+        println!("sweep {sweep:?} with {views} views");
+    }
+
+    fn sweep_finished(&mut self, _sweep: Sweep, carved: usize) {
+        println!("carved {carved} voxels");
+    }
+
+    fn finished(&mut self, carved: usize) {
+        println!("carved {carved} voxels in total");
+    }
+
+    fn message(&mut self, message: &str) {
+        println!("{message}");
+    }
+}
+
+/// Hears nothing
+#[cfg(test)]
+pub(crate) struct NoProgress;
+
+#[cfg(test)]
+impl CarvingObserver for NoProgress {}
+
+/// Stops a carve from another thread. The carver checks it between slices, so a cancelled carve
+/// leaves every slice either fully applied or untouched.
+#[derive(Clone, Default)]
+pub(crate) struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    // the binary carves on its main thread and never cancels, only the tests do so far
+    #[cfg(test)]
+    pub(crate) fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The carve stopped early because its token was cancelled
+#[derive(Debug, PartialEq)]
+pub(crate) struct Cancelled;
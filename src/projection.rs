@@ -0,0 +1,237 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    OnceLock,
+};
+
+use nalgebra::{Vector2, Vector3};
+
+use crate::{camera::CameraModel, voxel::VoxelBlock};
+
+// what a slot holds besides packed image coordinates
+// not projected yet
+const UNPROJECTED: u64 = u64::MAX;
+// the camera can't see the point at all
+const UNPROJECTABLE: u64 = u64::MAX - 1;
+// points along each side of a brick of slots
+const BRICK: usize = 8;
+
+/// Where the voxels of one block land in one image. Neither the cameras nor the voxels move during
+/// a carve, so each voxel center and each corner of the voxel lattice, shared by up to eight
+/// voxels' footprints, is projected once and looked up after that.
+/// Slots are filled the first time they are looked up, through atomics so threads can share the
+/// cache. They take 8 bytes each and are allocated a brick of 8x8x8 at a time, when one is first
+/// looked up, so only the bricks around the surface being carved ever take memory.
+#[derive(Clone)]
+pub(crate) struct ProjectionCache {
+    resolution: usize,
+    centers: Lattice,
+    corners: Lattice,
+}
+
+impl ProjectionCache {
+    pub(crate) fn new(voxel_block: &VoxelBlock) -> Self {
+        let resolution = voxel_block.resolution;
+        ProjectionCache {
+            resolution,
+            centers: Lattice::new(resolution),
+            corners: Lattice::new(resolution + 1),
+        }
+    }
+
+    /// Unclipped image coordinates of the voxel's center, see `CameraModel::project_unclipped`.
+    pub(crate) fn center(
+        &self,
+        camera: &impl CameraModel,
+        voxel_block: &VoxelBlock,
+        index: usize,
+    ) -> Option<Vector2<f32>> {
+        debug_assert_eq!(self.resolution, voxel_block.resolution);
+        let resolution = self.resolution;
+        let voxel = Vector3::new(
+            index % resolution,
+            index / resolution % resolution,
+            index / (resolution * resolution),
+        );
+        cached(self.centers.slot(voxel), || {
+            let (x, y, z) = voxel_block.index_to_coordinate(index);
+            let half_voxel_length = voxel_block.voxel_length() / 2.0;
+            camera.project_unclipped(Vector3::new(x, y, z).add_scalar(half_voxel_length))
+        })
+    }
+
+    /// Unclipped image coordinates of the lattice corner (x, y, z), where (0, 0, 0) is the
+    /// block's minimum corner and the voxel at (x, y, z) spans to (x + 1, y + 1, z + 1).
+    pub(crate) fn corner(
+        &self,
+        camera: &impl CameraModel,
+        voxel_block: &VoxelBlock,
+        corner: Vector3<usize>,
+    ) -> Option<Vector2<f32>> {
+        debug_assert_eq!(self.resolution, voxel_block.resolution);
+        cached(self.corners.slot(corner), || {
+            let (min, _) = voxel_block.bounds();
+            camera.project_unclipped(min + corner.cast::<f32>() * voxel_block.voxel_length())
+        })
+    }
+
+    /// Memory the allocated slots take
+    pub(crate) fn bytes(&self) -> usize {
+        self.centers.bytes() + self.corners.bytes()
+    }
+}
+
+/// Slots for a cube of `side` points along each axis, in bricks allocated on first use.
+struct Lattice {
+    side: usize,
+    bricks_per_side: usize,
+    bricks: Vec<OnceLock<Box<[AtomicU64]>>>,
+}
+
+impl Lattice {
+    fn new(side: usize) -> Self {
+        let bricks_per_side = side.div_ceil(BRICK);
+        Lattice {
+            side,
+            bricks_per_side,
+            bricks: (0..bricks_per_side.pow(3))
+                .map(|_| OnceLock::new())
+                .collect(),
+        }
+    }
+
+    fn slot(&self, point: Vector3<usize>) -> &AtomicU64 {
+        debug_assert!(point.max() < self.side);
+        let (brick, offset) = (point / BRICK, point.map(|coordinate| coordinate % BRICK));
+        let brick =
+            brick.x + brick.y * self.bricks_per_side + brick.z * self.bricks_per_side.pow(2);
+        let slots = self.bricks[brick].get_or_init(|| {
+            (0..BRICK.pow(3))
+                .map(|_| AtomicU64::new(UNPROJECTED))
+                .collect()
+        });
+        &slots[offset.x + offset.y * BRICK + offset.z * BRICK * BRICK]
+    }
+
+    fn bytes(&self) -> usize {
+        let allocated = self
+            .bricks
+            .iter()
+            .filter(|brick| brick.get().is_some())
+            .count();
+        allocated * BRICK.pow(3) * std::mem::size_of::<AtomicU64>()
+    }
+}
+
+impl Clone for Lattice {
+    fn clone(&self) -> Self {
+        let bricks = self
+            .bricks
+            .iter()
+            .map(|brick| match brick.get() {
+                Some(slots) => OnceLock::from(
+                    slots
+                        .iter()
+                        .map(|slot| AtomicU64::new(slot.load(Ordering::Relaxed)))
+                        .collect::<Box<[AtomicU64]>>(),
+                ),
+                None => OnceLock::new(),
+            })
+            .collect();
+        Lattice {
+            side: self.side,
+            bricks_per_side: self.bricks_per_side,
+            bricks,
+        }
+    }
+}
+
+/// The slot's coordinates, projecting and storing them first if it is empty.
+/// Threads racing to fill a slot store the same value.
+fn cached(
+    slot: &AtomicU64,
+    project: impl FnOnce() -> Option<Vector2<f32>>,
+) -> Option<Vector2<f32>> {
+    match slot.load(Ordering::Relaxed) {
+        UNPROJECTED => {
+            let pixel = project();
+            let packed = match pixel {
+                Some(pixel) => (pixel.x.to_bits() as u64) << 32 | pixel.y.to_bits() as u64,
+                None => UNPROJECTABLE,
+            };
+            slot.store(packed, Ordering::Relaxed);
+            pixel
+        }
+        UNPROJECTABLE => None,
+        packed => Some(Vector2::new(
+            f32::from_bits((packed >> 32) as u32),
+            f32::from_bits(packed as u32),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::ProjectionCache;
+    use crate::{camera::Camera, camera::CameraModel, voxel::VoxelBlock};
+
+    #[test]
+    fn test_cache_matches_camera() {
+        let voxel_block = VoxelBlock::new(2, 3);
+        let pos = Vector3::new(3.0, 2.0, 4.0);
+        let camera = Camera::new(
+            64,
+            48,
+            pos,
+            Vector3::zeros(),
+            Vector3::y(),
+            1.0,
+            0.01,
+            1000.0,
+        );
+        let cache = ProjectionCache::new(&voxel_block);
+        let voxel_length = voxel_block.voxel_length();
+
+        // twice, the second time from the cache
+        for _ in 0..2 {
+            for index in 0..voxel_block.voxels.len() {
+                let (x, y, z) = voxel_block.index_to_coordinate(index);
+                let corner = Vector3::new(x, y, z);
+                assert_eq!(
+                    cache.center(&camera, &voxel_block, index),
+                    camera.project_unclipped(corner.add_scalar(voxel_length / 2.0))
+                );
+                let lattice = Vector3::new(index % 3, index / 3 % 3, index / 9);
+                let projected = cache.corner(&camera, &voxel_block, lattice).unwrap();
+                let expected = camera.project_unclipped(corner).unwrap();
+                assert!((projected - expected).norm() < 1e-3);
+            }
+        }
+
+        // only the bricks looked up take memory
+        let voxel_block = VoxelBlock::new(2, 20);
+        let cache = ProjectionCache::new(&voxel_block);
+        assert_eq!(cache.bytes(), 0);
+        cache.center(&camera, &voxel_block, 0);
+        cache.center(&camera, &voxel_block, 7 + 7 * 20);
+        assert_eq!(cache.bytes(), 8 * 8 * 8 * 8);
+        cache.center(&camera, &voxel_block, 19 + 19 * 20 + 19 * 400);
+        cache.corner(&camera, &voxel_block, Vector3::new(20, 20, 20));
+        assert_eq!(cache.bytes(), 3 * 8 * 8 * 8 * 8);
+        let copy = cache.clone();
+        assert_eq!(copy.bytes(), cache.bytes());
+        assert_eq!(
+            copy.center(&camera, &voxel_block, 0),
+            cache.center(&camera, &voxel_block, 0)
+        );
+
+        // points behind the camera stay unprojectable
+        let behind = Camera::new(64, 48, pos, pos * 2.0, Vector3::y(), 1.0, 0.01, 1000.0);
+        let voxel_block = VoxelBlock::new(2, 3);
+        let cache = ProjectionCache::new(&voxel_block);
+        for _ in 0..2 {
+            assert_eq!(cache.center(&behind, &voxel_block, 13), None);
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::fmt;
+
+use nalgebra::Vector3;
+
+use crate::{
+    carver::{observe, should_carve_voxel, Consistency, Observation},
+    config::{CarvingConfig, CarvingMode, Sampling, Sweep},
+    image::Image,
+    probabilistic::estimate_occupancy,
+    visibility::ItemBuffer,
+    voxel::{Votes, VoxelBlock},
+};
+
+/// Why a voxel was carved
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Reason {
+    // enough views saw background
+    Background,
+    // the views disagreed on its color, or its occupancy fell below the threshold
+    Inconsistent,
+    // the visual hull mode's background test
+    OutsideVisualHull,
+    // no view ever tested it, see `Inconclusive::Carve`
+    Inconclusive,
+    // the regularization's minimum cut, with the views that saw background there
+    Regularized,
+}
+
+/// What one camera behind a decision saw
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CameraSample {
+    // index of the image
+    pub(crate) image: usize,
+    // None where it saw background
+    pub(crate) color: Option<Vector3<u8>>,
+}
+
+/// Where and why a voxel was carved, kept on the voxel when `provenance` is on
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Provenance {
+    // None outside rounds of sweeps, voxel coloring's pass is round 0
+    pub(crate) round: Option<usize>,
+    pub(crate) sweep: Option<Sweep>,
+    // slice of the sweep in sweep order, or layer of voxel coloring
+    pub(crate) slice: Option<usize>,
+    pub(crate) reason: Reason,
+    pub(crate) cameras: Vec<CameraSample>,
+}
+
+/// Why the carver is carving a voxel it just tested, and what the views behind that saw,
+/// None if it isn't carving it. `view_images` maps positions in `views` to image indices.
+pub(crate) fn carved_by(
+    consistency: &Consistency,
+    index: usize,
+    views: &[(&mut Image, &ItemBuffer)],
+    view_images: &[usize],
+    voxel_block: &VoxelBlock,
+    sampling: Sampling,
+) -> Option<(Reason, Vec<CameraSample>)> {
+    let (reason, deciding) = match consistency {
+        Consistency::Background(deciding) => (Reason::Background, deciding),
+        Consistency::Inconsistent(deciding) => (Reason::Inconsistent, deciding),
+        _ => return None,
+    };
+    let cameras = deciding
+        .iter()
+        .map(|&view| {
+            let (image, item_buffer) = &views[view];
+            let color = match observe(index, image, item_buffer, voxel_block, sampling) {
+                Observation::Color(color, _) => Some(color),
+                _ => None,
+            };
+            CameraSample {
+                image: view_images[view],
+                color,
+            }
+        })
+        .collect();
+    Some((reason, cameras))
+}
+
+/// Everything that goes into one voxel's decision, see `explain`
+#[derive(Debug)]
+pub(crate) struct Explanation {
+    pub(crate) index: usize,
+    pub(crate) carved: bool,
+    // how it was carved, if that was recorded
+    pub(crate) provenance: Option<Provenance>,
+    // what each image makes of it, by image name in image order
+    pub(crate) observations: Vec<(String, Observation)>,
+    pub(crate) consistency: Consistency,
+    pub(crate) votes: Votes,
+    // only estimated by probabilistic carving
+    pub(crate) occupancy: Option<f32>,
+}
+
+/// Tests the voxel at (x, y, z) in the block's grid again, against every image rather than the
+/// ones a sweep would pick, as if it were uncarved and no pixels were marked. The rest of the
+/// block stays as it is, so carved voxels no longer hide it. The images are left as they were.
+/// Errors if (x, y, z) is outside the block.
+pub(crate) fn explain(
+    voxel_block: &VoxelBlock,
+    images: &mut [Image],
+    config: &CarvingConfig,
+    x: usize,
+    y: usize,
+    z: usize,
+) -> Result<Explanation, String> {
+    let resolution = voxel_block.resolution;
+    if [x, y, z].iter().any(|&coordinate| coordinate >= resolution) {
+        return Err(format!(
+            "({x}, {y}, {z}) is outside the block of resolution {resolution}"
+        ));
+    }
+    let index = x + y * resolution + z * resolution * resolution;
+    let voxel = &voxel_block.voxels[index];
+
+    // the item buffers have to see the voxel for it to be tested
+    let mut block = voxel_block.clone();
+    block.voxels[index].carved = false;
+    block.voxels[index].visible = true;
+
+    let saved: Vec<_> = images
+        .iter_mut()
+        .map(|image| {
+            let background = std::mem::replace(&mut image.background, config.background.into());
+            let unmarked = vec![false; image.marked.len()];
+            (std::mem::replace(&mut image.marked, unmarked), background)
+        })
+        .collect();
+    let item_buffers: Vec<ItemBuffer> = images
+        .iter()
+        .map(|image| ItemBuffer::new(&image.camera))
+        .collect();
+    let views: Vec<_> = images.iter_mut().zip(item_buffers.iter()).collect();
+
+    let observations = views
+        .iter()
+        .map(|(image, item_buffer)| {
+            let observation = observe(index, image, item_buffer, &block, config.sampling);
+            (image.name.clone(), observation)
+        })
+        .collect();
+    let (consistency, votes, occupancy) = match config.mode {
+        CarvingMode::Probabilistic(model) => {
+            let view_images: Vec<usize> = (0..views.len()).collect();
+            let (consistency, votes, occupancy) =
+                estimate_occupancy(index, &views, &view_images, &block, config, &model);
+            (
+                consistency,
+                votes,
+                occupancy.map(|estimate| estimate.occupancy),
+            )
+        }
+        _ => {
+            let (consistency, votes) = should_carve_voxel(index, &views, &block, config);
+            (consistency, votes, None)
+        }
+    };
+
+    for (image, (marked, background)) in images.iter_mut().zip(saved) {
+        image.marked = marked;
+        image.background = background;
+    }
+    Ok(Explanation {
+        index,
+        carved: voxel.carved,
+        provenance: voxel_block.provenance(index).cloned(),
+        observations,
+        consistency,
+        votes,
+        occupancy,
+    })
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.carved { "carved" } else { "kept" };
+        writeln!(f, "voxel {} ({state})", self.index)?;
+        if let Some(provenance) = &self.provenance {
+            writeln!(
+                f,
+                "  carved as {:?} in round {:?}, sweep {:?}, slice {:?}",
+                provenance.reason, provenance.round, provenance.sweep, provenance.slice
+            )?;
+            for camera in &provenance.cameras {
+                writeln!(f, "    image {} saw {}", camera.image, color(camera.color))?;
+            }
+        }
+        for (name, observation) in &self.observations {
+            let seen = match observation {
+                Observation::Unseen => "nothing".to_owned(),
+                Observation::Background => "background".to_owned(),
+                Observation::Color(pixel, _) => color(Some(*pixel)),
+            };
+            writeln!(f, "  {name} sees {seen}")?;
+        }
+        let votes = self.votes;
+        writeln!(
+            f,
+            "  votes: {} background, {} foreground, {} outliers, {} highlights",
+            votes.background, votes.foreground, votes.outliers, votes.highlights
+        )?;
+        if let Some(occupancy) = self.occupancy {
+            writeln!(f, "  occupancy {occupancy:.3}")?;
+        }
+        match &self.consistency {
+            Consistency::Consistent(pixel, _) => write!(f, "  consistent, {}", color(Some(*pixel))),
+            Consistency::Inconsistent(views) => write!(f, "  inconsistent between views {views:?}"),
+            Consistency::Background(views) => write!(f, "  background in views {views:?}"),
+            Consistency::Inconclusive => write!(f, "  inconclusive"),
+        }
+    }
+}
+
+fn color(color: Option<Vector3<u8>>) -> String {
+    match color {
+        Some(color) => format!("({}, {}, {})", color.x, color.y, color.z),
+        None => "background".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::{explain, Reason};
+    use crate::{
+        carver::{carve, Consistency},
+        config::CarvingConfig,
+        test_scenes::render_around,
+        voxel::VoxelBlock,
+    };
+
+    #[test]
+    fn test_provenance_and_explain() {
+        // a block missing its top corner, so the voxels there are carved
+        let mut object = VoxelBlock::new(2, 2);
+        object.carve(7);
+        let mut images = render_around(&object);
+        let mut voxel_block = VoxelBlock::new(2, 4);
+        let config = CarvingConfig {
+            provenance: true,
+            ..Default::default()
+        };
+        carve(&mut voxel_block, &mut images, &config);
+        // only what the config asks for is kept
+        assert!(voxel_block.votes.is_none() && voxel_block.occupancy.is_none());
+
+        let mut carved = 0;
+        for (index, voxel) in voxel_block.voxels.iter().enumerate() {
+            let Some(provenance) = voxel_block.provenance(index) else {
+                assert!(!voxel.carved);
+                continue;
+            };
+            carved += 1;
+            assert!(voxel.carved);
+            assert!(provenance.round.is_some() && provenance.sweep.is_some());
+            assert!(provenance.slice.is_some_and(|slice| slice < 4));
+            assert!(!provenance.cameras.is_empty());
+            assert!(provenance.cameras.iter().all(|camera| camera.image < 4));
+            match provenance.reason {
+                Reason::Background => {
+                    assert!(provenance
+                        .cameras
+                        .iter()
+                        .all(|camera| camera.color.is_none()))
+                }
+                Reason::Inconsistent => {
+                    assert!(provenance
+                        .cameras
+                        .iter()
+                        .any(|camera| camera.color.is_some()))
+                }
+                reason => panic!("carved by a sweep as {reason:?}"),
+            }
+        }
+        assert!(carved > 0);
+
+        // the carved corner, explained against every image
+        images[0].marked[0] = true;
+        images[1].background = Vector3::new(1, 2, 3);
+        let explanation = explain(&voxel_block, &mut images, &config, 3, 3, 3).unwrap();
+        assert!(explanation.carved);
+        assert_eq!(explanation.index, 63);
+        assert_eq!(explanation.provenance, voxel_block.provenance(63).cloned());
+        assert_eq!(explanation.observations.len(), 4);
+        assert!(matches!(
+            explanation.consistency,
+            Consistency::Background(_) | Consistency::Inconsistent(_)
+        ));
+        assert!(explanation.to_string().starts_with("voxel 63 (carved)"));
+        // explaining leaves the images and the block alone
+        assert!(images[0].marked[0]);
+        assert_eq!(images[1].background, Vector3::new(1, 2, 3));
+        assert!(voxel_block.voxels[63].carved);
+
+        // and a voxel of the object that was kept
+        let explanation = explain(&voxel_block, &mut images, &config, 0, 0, 0).unwrap();
+        assert!(!explanation.carved);
+        assert_eq!(explanation.provenance, None);
+        assert!(matches!(
+            explanation.consistency,
+            Consistency::Consistent(..) | Consistency::Inconclusive
+        ));
+
+        // but not one outside the block
+        assert!(explain(&voxel_block, &mut images, &config, 0, 4, 0).is_err());
+    }
+}
@@ -1,38 +1,25 @@
-use nalgebra::{Vector3, Vector4};
-
-use crate::{
-    camera::Camera,
-    voxel::{find_cube_intersect, Voxel, VoxelBlock},
-    RESOLUTION,
-};
-
-pub(crate) enum LightType {
-    PointLight,
-    DirectionalLight,
-    SpotLight,
-}
+#[cfg(test)]
+use nalgebra::Vector2;
+use nalgebra::Vector4;
 
-pub(crate) struct SceneLightData {
-    pub(crate) light_type: LightType,
-    pub(crate) color: Vector3<f32>,
-    // pub(crate) att_function: Vector3<f32>,
-    pub(crate) dir: Option<Vector4<f32>>,
-    pub(crate) pos: Option<Vector4<f32>>,
-}
+#[cfg(test)]
+use crate::camera::{Camera, CameraModel};
+#[cfg(test)]
+use crate::voxel::{find_cube_intersect, VoxelBlock};
 
 pub(crate) struct Ray {
     pub(crate) p: Vector4<f32>,
     pub(crate) d: Vector4<f32>,
 }
 
-pub(crate) fn generate_ray(i: usize, j: usize, k: f32, camera: &Camera) -> Ray {
-    let dir = get_ray_dir_for_pixel(i, j, k, camera);
-    // convert ray to world space
-    let p = camera.pos.push(1.0);
-    let d = (camera.inv_view_matrix * dir).normalize();
-    Ray { p, d }
+/// Ray from the camera through the center of pixel[i,j]
+// only the tests render scenes, the carver projects voxels instead
+#[cfg(test)]
+pub(crate) fn generate_ray(i: usize, j: usize, camera: &impl CameraModel) -> Ray {
+    camera.unproject(Vector2::new(i as f32 + 0.5, j as f32 + 0.5))
 }
 
+#[cfg(test)]
 pub(crate) fn generate_ray_direct(x: f32, y: f32, z: f32, camera: &Camera) -> Ray {
     // let p = Vector4::new(x,y,z,1.0);
     let p = camera.pos.push(1.0);
@@ -40,23 +27,9 @@ pub(crate) fn generate_ray_direct(x: f32, y: f32, z: f32, camera: &Camera) -> Ra
     Ray { p, d }
 }
 
-/// For pixel[i,j], this computes and returns the direction from the camera to that pixel in camera space
-fn get_ray_dir_for_pixel(i: usize, j: usize, k: f32, camera: &Camera) -> Vector4<f32> {
-    let x = (i as f32 + 0.5) / camera.width as f32 - 0.5;
-    let y = (j as f32 + 0.5) / camera.height as f32 - 0.5;
-    let aspect_ratio = camera.get_aspect_ratio();
-    let theta_h = camera.height_angle;
-    let big_v = 2.0 * k * (theta_h / 2.0).tan();
-    let big_u = big_v * aspect_ratio;
-    let uvk = Vector4::new(big_u * x, -big_v * y, -k, 1.0);
-
-    // a bit unnecessary but demonstrative as the the direction is in camera space
-    let eye = Vector4::new(0.0, 0.0, 0.0, 1.0);
-    uvk - eye
-}
-
 /// For a given ray position and direction, trace it through the scene to closest intersect to determine color
 /// Returns the first voxel hit (by index?)
+#[cfg(test)]
 pub(crate) fn trace_ray(
     ray: &Ray,
     voxel_block: &VoxelBlock,
@@ -67,6 +40,7 @@ pub(crate) fn trace_ray(
 
 /// Returns the t value for the closest intersection of the ray from position p and direction d to the objects in the scene
 /// p and d are in world space
+#[cfg(test)]
 pub(crate) fn find_closest_intersection(
     ray: &Ray,
     voxel_block: &VoxelBlock,
@@ -92,13 +66,6 @@ pub(crate) fn find_closest_intersection(
                 // find intersect
                 if let Some(intersect) = find_cube_intersect(voxel, ray.p, ray.d) {
                     t_values[index] = intersect;
-                    let intersect_pos = ray.p + ray.d * intersect;
-                    if expected_index == 6 {
-                        println!("d {}", ray.d);
-                        println!(
-                            "intersect with voxel {index} at t {intersect}, pos {intersect_pos}"
-                        );
-                    }
                 }
             }
         }
@@ -107,9 +74,9 @@ pub(crate) fn find_closest_intersection(
     // determine object with smallest t
     let mut min_t = f32::MAX;
     let mut min_index: i32 = -1;
-    for i in 0..t_values.len() {
-        if t_values[i] > 0.0 && t_values[i] < min_t {
-            min_t = t_values[i];
+    for (i, &t) in t_values.iter().enumerate() {
+        if t > 0.0 && t < min_t {
+            min_t = t;
             min_index = i as i32;
         }
     }
@@ -128,11 +95,11 @@ pub(crate) fn find_closest_intersection(
 
 #[cfg(test)]
 mod tests {
-    use nalgebra::{Perspective3, Point3, Vector3, Vector4};
+    use nalgebra::{Vector3, Vector4};
 
     use crate::{
+        camera::{Camera, CameraModel},
         raytracer::generate_ray_direct,
-        scene_generator,
         voxel::{find_cube_intersect, VoxelBlock},
     };
 
@@ -140,21 +107,22 @@ mod tests {
 
     #[test]
     fn test_trace_ray() {
-        let images = &mut scene_generator::cone();
-        let mut voxel_block = VoxelBlock::new(2, 2);
+        // looking at the block from the corner nearest voxel 4
+        let pos = Vector3::new(-3.0, -3.0, 3.0);
+        let camera = Camera::new(
+            1024,
+            768,
+            pos,
+            Vector3::zeros(),
+            Vector3::y(),
+            1.415,
+            0.01,
+            1000.0,
+        );
+        let voxel_block = VoxelBlock::new(2, 2);
         // voxel_block.carve(1,1,1);
         // voxel_block.carve(0,1,1);
 
-        let ray = generate_ray(569, 417, 1.0, &images[0].camera);
-        let dir = ray.d;
-        let pos = ray.p;
-        println!("ray pos {pos}, ray dir {dir}");
-
-        let ray_direct = generate_ray_direct(-0.5, -0.5, 0.5, &images[0].camera);
-        let dir = ray_direct.d;
-        let pos = ray_direct.p;
-        println!("ray_direct pos {pos}, ray dir {dir}");
-
         let voxel = &voxel_block.voxels[4];
         // println!("center {}", voxel.ctm * Vector4::new(0.0,0.0,0.0, 1.0));
         // println!("corner {}", voxel.ctm * Vector4::new(-0.5, -0.5, -0.5, 1.0));
@@ -167,15 +135,33 @@ mod tests {
             Vector4::new(3.0, 3.0, 3.0, 1.0),
             voxel.inverse_ctm * Vector4::new(2.5, 2.5, 3.5, 1.0)
         );
-        let intersect = find_cube_intersect(voxel, pos, dir);
+
+        // the voxel projects onto the image at (i,j)
+        let center = Vector3::new(-0.5, -0.5, 0.5);
+        let pixel = camera.project(center).unwrap();
+        let (i, j) = (pixel.x as usize, pixel.y as usize);
+
+        // and the ray traced from (i,j) into the scene hits it
+        let ray = generate_ray(i, j, &camera);
+        // from the camera, towards the voxel's center
+        assert!((ray.p - pos.push(1.0)).norm() < 1e-4);
+        assert!((ray.d.norm() - 1.0).abs() < 1e-4);
+        let to_center = (center - pos).normalize();
+        assert!(ray.d.xyz().dot(&to_center) > 0.999);
+        let intersect = find_cube_intersect(voxel, ray.p, ray.d);
         assert!(intersect.is_some());
+        let intersected_voxel = trace_ray(&ray, &voxel_block, 4);
+        assert_eq!(intersected_voxel, Some(4));
+
+        let ray_direct = generate_ray_direct(-0.5, -0.5, 0.5, &camera);
+        assert_eq!(ray_direct.p, pos.push(1.0));
+        assert!((ray_direct.d.xyz() - to_center).norm() < 1e-6);
+        assert_eq!(ray_direct.d.w, 0.0);
+        // less than a pixel apart
+        assert!((ray_direct.d - ray.d).norm() < 1.0 / camera.focal_length());
 
         let intersected_voxel = trace_ray(&ray_direct, &voxel_block, 4);
         assert!(intersected_voxel.is_some());
         assert_eq!(intersected_voxel.unwrap(), 4);
-
-        // the issue:
-        // the voxel projects onto the image at (i,j)
-        // when a ray is traced from (i,j) into the scene, it does not intersect with the voxel ?!
     }
 }
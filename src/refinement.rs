@@ -0,0 +1,334 @@
+use std::time::Instant;
+
+use nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
+
+use crate::{
+    camera::{Camera, CameraModel},
+    carver::carve_with_progress,
+    config::CarvingConfig,
+    image::Image,
+    progress::{CancellationToken, CarvingObserver},
+    stats::CarvingStats,
+    voxel::VoxelBlock,
+};
+
+/// How far and how long camera poses may be adjusted between carves.
+pub(crate) struct RefinementOptions {
+    // number of carve/refine alternations
+    pub(crate) rounds: usize,
+    // pattern search iterations per camera per round
+    pub(crate) iterations: usize,
+    pub(crate) refine_focal_length: bool,
+    // initial search steps: radians, world units and relative focal length
+    pub(crate) rotation_step: f32,
+    pub(crate) translation_step: f32,
+    pub(crate) focal_step: f32,
+}
+
+impl Default for RefinementOptions {
+    fn default() -> Self {
+        RefinementOptions {
+            rounds: 3,
+            iterations: 50,
+            refine_focal_length: false,
+            rotation_step: 0.01,
+            translation_step: 0.05,
+            focal_step: 0.01,
+        }
+    }
+}
+
+/// Alternates carving with silhouette based camera refinement.
+/// Each round carves a fresh block with the current cameras, then nudges every camera so the
+/// silhouette of the resulting hull best matches that view's foreground.
+/// The block ends up carved with the refined cameras, which are written back into `images`.
+/// Returns the stats of the last carve, or of the one the token stopped, which is then the last.
+pub(crate) fn carve_with_refinement(
+    voxel_block: &mut VoxelBlock,
+    images: &mut [Image],
+    config: &CarvingConfig,
+    options: &RefinementOptions,
+    observer: &mut dyn CarvingObserver,
+    cancel: &CancellationToken,
+) -> CarvingStats {
+    let start = Instant::now();
+    for round in 0..options.rounds {
+        *voxel_block = voxel_block.uncarved();
+        let mut stats = carve_with_progress(voxel_block, images, config, observer, cancel);
+        if stats.cancelled {
+            stats.seconds = start.elapsed().as_secs_f64();
+            return stats;
+        }
+
+        let mut total_before = 0.0;
+        let mut total_after = 0.0;
+        for image in images.iter_mut() {
+            let mask = foreground_mask(image);
+            total_before += silhouette_agreement(voxel_block, &image.camera, &mask);
+            image.camera = refine_camera(voxel_block, &image.camera, &mask, options);
+            total_after += silhouette_agreement(voxel_block, &image.camera, &mask);
+        }
+        let count = images.len() as f32;
+        observer.message(&format!(
+            "refinement round {round}: mean silhouette agreement {} -> {}",
+            total_before / count,
+            total_after / count
+        ));
+    }
+    *voxel_block = voxel_block.uncarved();
+    let mut stats = carve_with_progress(voxel_block, images, config, observer, cancel);
+    stats.seconds = start.elapsed().as_secs_f64();
+    stats
+}
+
+/// pixels that are not background
+pub(crate) fn foreground_mask(image: &Image) -> Vec<bool> {
+    (0..image.width * image.height)
+        .map(|index| !image.is_background(index))
+        .collect()
+}
+
+/// Adjusts the camera's extrinsics (and optionally focal length) by pattern search
+/// to maximize the agreement between the hull's silhouette and the mask.
+pub(crate) fn refine_camera(
+    voxel_block: &VoxelBlock,
+    camera: &Camera,
+    mask: &[bool],
+    options: &RefinementOptions,
+) -> Camera {
+    // rotation, translation (both in the camera frame) and relative focal change
+    let parameter_count = if options.refine_focal_length { 7 } else { 6 };
+    let mut steps = [
+        options.rotation_step,
+        options.rotation_step,
+        options.rotation_step,
+        options.translation_step,
+        options.translation_step,
+        options.translation_step,
+        options.focal_step,
+    ];
+    let mut params = [0.0; 7];
+    let mut best = silhouette_agreement(voxel_block, camera, mask);
+    if best == 0.0 {
+        // nothing to align against
+        return *camera;
+    }
+
+    for _ in 0..options.iterations {
+        let mut improved = false;
+        for k in 0..parameter_count {
+            for sign in [1.0, -1.0] {
+                let mut candidate = params;
+                candidate[k] += sign * steps[k];
+                let score = silhouette_agreement(voxel_block, &perturb(camera, &candidate), mask);
+                if score > best {
+                    best = score;
+                    params = candidate;
+                    improved = true;
+                    break;
+                }
+            }
+        }
+        if !improved {
+            for step in &mut steps {
+                *step /= 2.0;
+            }
+            if steps[0] < options.rotation_step / 64.0 {
+                break;
+            }
+        }
+    }
+    perturb(camera, &params)
+}
+
+fn perturb(camera: &Camera, params: &[f32; 7]) -> Camera {
+    let delta = Isometry3::from_parts(
+        Translation3::new(params[3], params[4], params[5]),
+        UnitQuaternion::from_scaled_axis(Vector3::new(params[0], params[1], params[2])),
+    );
+    let view_matrix = delta.to_homogeneous() * camera.view_matrix;
+    // scaling the focal length scales the tangent of the half angle inversely
+    let height_angle = 2.0 * f32::atan(f32::tan(camera.height_angle / 2.0) / (1.0 + params[6]));
+    camera.with_view_matrix(view_matrix, height_angle)
+}
+
+/// Intersection over union of the hull's projected silhouette and the foreground mask.
+pub(crate) fn silhouette_agreement(
+    voxel_block: &VoxelBlock,
+    camera: &Camera,
+    mask: &[bool],
+) -> f32 {
+    let silhouette = hull_silhouette(voxel_block, camera);
+    let mut intersection = 0;
+    let mut union = 0;
+    for (&hull, &foreground) in silhouette.iter().zip(mask) {
+        if hull && foreground {
+            intersection += 1;
+        }
+        if hull || foreground {
+            union += 1;
+        }
+    }
+    if union == 0 {
+        0.0
+    } else {
+        intersection as f32 / union as f32
+    }
+}
+
+/// Splats the surface voxels of the hull into the camera's image as squares of their projected size.
+pub(crate) fn hull_silhouette(voxel_block: &VoxelBlock, camera: &Camera) -> Vec<bool> {
+    let mut silhouette = vec![false; camera.width * camera.height];
+    let half_voxel_length = voxel_block.voxel_length() / 2.0;
+    let focal = camera.focal_length();
+
+    for (index, voxel) in voxel_block.voxels.iter().enumerate() {
+        if voxel.carved || !voxel.visible {
+            continue;
+        }
+        let (x, y, z) = voxel_block.index_to_coordinate(index);
+        let center = Vector3::new(
+            x + half_voxel_length,
+            y + half_voxel_length,
+            z + half_voxel_length,
+        );
+        let Some(pixel) = camera.project(center) else {
+            continue;
+        };
+        let depth = (center - camera.pos).norm();
+        let (px, py) = (pixel.x, pixel.y);
+        let radius = half_voxel_length * focal / depth;
+
+        let x_min = (px - radius).floor().max(0.0) as i64;
+        let x_max = ((px + radius).ceil() as i64).min(camera.width as i64 - 1);
+        let y_min = (py - radius).floor().max(0.0) as i64;
+        let y_max = ((py + radius).ceil() as i64).min(camera.height as i64 - 1);
+        for py in y_min..=y_max {
+            for px in x_min..=x_max {
+                silhouette[px as usize + py as usize * camera.width] = true;
+            }
+        }
+    }
+    silhouette
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Isometry3, Vector3};
+
+    use super::{
+        carve_with_refinement, hull_silhouette, refine_camera, silhouette_agreement,
+        RefinementOptions,
+    };
+    use crate::{
+        camera::Camera,
+        config::CarvingConfig,
+        progress::{CancellationToken, CarvingObserver},
+        test_scenes::render_around,
+        voxel::VoxelBlock,
+    };
+
+    #[test]
+    fn test_refine_camera() {
+        // an ellipsoid, so the silhouette pins down the pose
+        let mut voxel_block = VoxelBlock::new(2, 16);
+        for index in 0..voxel_block.voxels.len() {
+            let (x, y, z) = voxel_block.index_to_coordinate(index);
+            let half = voxel_block.voxel_length() / 2.0;
+            let (x, y, z) = (x + half, y + half, z + half);
+            if x * x / 0.8 + y * y / 0.3 + z * z / 0.5 > 1.0 {
+                voxel_block.carve(index);
+            }
+        }
+
+        let camera = Camera::new(
+            80,
+            60,
+            Vector3::new(3.0, 1.0, 2.0),
+            Vector3::zeros(),
+            Vector3::y(),
+            0.8,
+            0.01,
+            1000.0,
+        );
+        let mask = hull_silhouette(&voxel_block, &camera);
+
+        let offset = Isometry3::new(
+            Vector3::new(0.08, -0.05, 0.0),
+            Vector3::new(0.0, 0.02, 0.01),
+        );
+        let perturbed = camera.with_view_matrix(
+            offset.to_homogeneous() * camera.view_matrix,
+            camera.height_angle,
+        );
+        let before = silhouette_agreement(&voxel_block, &perturbed, &mask);
+
+        let refined = refine_camera(
+            &voxel_block,
+            &perturbed,
+            &mask,
+            &RefinementOptions::default(),
+        );
+        let after = silhouette_agreement(&voxel_block, &refined, &mask);
+
+        assert!(after > before);
+        assert!(after > 0.98);
+    }
+
+    /// keeps the messages it is sent
+    #[derive(Default)]
+    struct Messages(Vec<String>);
+
+    impl CarvingObserver for Messages {
+        fn message(&mut self, message: &str) {
+            self.0.push(message.to_string());
+        }
+    }
+
+    #[test]
+    fn test_carve_with_refinement() {
+        let mut object = VoxelBlock::new(2, 2);
+        object.carve(7);
+        let mut images = render_around(&object);
+        let options = RefinementOptions {
+            rounds: 1,
+            iterations: 2,
+            ..Default::default()
+        };
+        let mut voxel_block = VoxelBlock::new(2, 4);
+        let mut observer = Messages::default();
+        let stats = carve_with_refinement(
+            &mut voxel_block,
+            &mut images,
+            &CarvingConfig::default(),
+            &options,
+            &mut observer,
+            &CancellationToken::default(),
+        );
+        assert!(!stats.cancelled);
+        assert!(stats.carved > 0);
+        assert_eq!(stats.cameras.len(), 4);
+        assert!(observer
+            .0
+            .iter()
+            .any(|message| message.starts_with("refinement round 0")));
+
+        // a cancelled carve stops the refinement before any round
+        let cancel = CancellationToken::default();
+        cancel.cancel();
+        let mut observer = Messages::default();
+        let stats = carve_with_refinement(
+            &mut voxel_block,
+            &mut images,
+            &CarvingConfig::default(),
+            &options,
+            &mut observer,
+            &cancel,
+        );
+        assert!(stats.cancelled);
+        assert!(observer
+            .0
+            .iter()
+            .all(|message| !message.starts_with("refinement")));
+    }
+}
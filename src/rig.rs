@@ -0,0 +1,214 @@
+use std::{fs::File, io::Write};
+
+use nalgebra::{Vector2, Vector3};
+
+use crate::{camera::CameraModel, image::Image, voxel::VoxelBlock};
+
+/// A named polygon mesh, faces index into its own vertices
+struct Mesh {
+    name: String,
+    vertices: Vec<Vector3<f32>>,
+    faces: Vec<Vec<usize>>,
+}
+
+/// Writes every image's camera as a frustum pyramid reaching to the middle of the block,
+/// along with the bounds of the block as a box, so the capture setup can be checked in a viewer.
+/// Each camera is labeled with its image's name. The format follows the extension, `.ply` or OBJ otherwise.
+pub(crate) fn save_rig_to_file(images: &[Image], voxel_block: &VoxelBlock, file_path: &str) {
+    let (min, max) = voxel_block.bounds();
+    let center = (min + max) / 2.0;
+
+    let mut meshes = vec![block_box(min, max)];
+    for image in images {
+        let depth = (center - image.camera.pos).norm();
+        meshes.push(camera_frustum(image, depth));
+    }
+
+    let f = File::create(file_path);
+    let mut file = f.expect("Unable to open or create file");
+    let contents = if file_path.ends_with(".ply") {
+        to_ply(&meshes)
+    } else {
+        to_obj(&meshes)
+    };
+    file.write_all(contents.as_bytes())
+        .expect("Unable to write to file");
+}
+
+fn block_box(min: Vector3<f32>, max: Vector3<f32>) -> Mesh {
+    let mut vertices = vec![];
+    for z in [min.z, max.z] {
+        for y in [min.y, max.y] {
+            for x in [min.x, max.x] {
+                vertices.push(Vector3::new(x, y, z));
+            }
+        }
+    }
+    // vertex index bits are x, y, z
+    let faces = vec![
+        vec![0, 2, 3, 1],
+        vec![4, 5, 7, 6],
+        vec![0, 1, 5, 4],
+        vec![2, 6, 7, 3],
+        vec![0, 4, 6, 2],
+        vec![1, 3, 7, 5],
+    ];
+    Mesh {
+        name: "bounds".to_owned(),
+        vertices,
+        faces,
+    }
+}
+
+/// Pyramid from the camera center to the image corners `depth` along the optical axis,
+/// with a triangle on the top edge showing which way is up in the image.
+fn camera_frustum(image: &Image, depth: f32) -> Mesh {
+    let camera = &image.camera;
+    let (width, height) = (camera.width() as f32, camera.height() as f32);
+    let apex = camera.pos;
+    let axis = camera
+        .unproject(Vector2::new(width / 2.0, height / 2.0))
+        .d
+        .xyz();
+
+    let corner = |x: f32, y: f32| {
+        let dir = camera.unproject(Vector2::new(x, y)).d.xyz();
+        let cos = dir.dot(&axis);
+        // keep the base flat, unless the corner is too far off axis (wide fisheyes)
+        let distance = if cos > 0.1 { depth / cos } else { depth };
+        apex + dir * distance
+    };
+    // top left, top right, bottom right, bottom left
+    let corners = [
+        corner(0.0, 0.0),
+        corner(width, 0.0),
+        corner(width, height),
+        corner(0.0, height),
+    ];
+
+    let base_center = corners.iter().sum::<Vector3<f32>>() / 4.0;
+    let top_center = (corners[0] + corners[1]) / 2.0;
+    let up_left = corners[0].lerp(&corners[1], 0.25);
+    let up_right = corners[0].lerp(&corners[1], 0.75);
+    let up_tip = top_center + (top_center - base_center) * 0.5;
+
+    let mut vertices = vec![apex];
+    vertices.extend(corners);
+    vertices.extend([up_left, up_right, up_tip]);
+    let faces = vec![
+        vec![0, 1, 2],
+        vec![0, 2, 3],
+        vec![0, 3, 4],
+        vec![0, 4, 1],
+        vec![1, 4, 3, 2],
+        vec![5, 6, 7],
+    ];
+    Mesh {
+        name: image.name.clone(),
+        vertices,
+        faces,
+    }
+}
+
+fn to_obj(meshes: &[Mesh]) -> String {
+    let mut contents = String::new();
+    // OBJ files index starting at 1
+    let mut offset = 1;
+    for mesh in meshes {
+        contents += &format!("o {}\n", mesh.name);
+        for v in &mesh.vertices {
+            contents += &format!("v {} {} {}\n", v.x, v.y, v.z);
+        }
+        for face in &mesh.faces {
+            let indices: Vec<String> = face.iter().map(|i| (i + offset).to_string()).collect();
+            contents += &format!("f {}\n", indices.join(" "));
+        }
+        offset += mesh.vertices.len();
+    }
+    contents
+}
+
+/// PLY has no object names, so faces carry the index of their object and comments name them
+fn to_ply(meshes: &[Mesh]) -> String {
+    let vertex_count: usize = meshes.iter().map(|mesh| mesh.vertices.len()).sum();
+    let face_count: usize = meshes.iter().map(|mesh| mesh.faces.len()).sum();
+
+    let mut contents = String::from("ply\nformat ascii 1.0\n");
+    for (object, mesh) in meshes.iter().enumerate() {
+        contents += &format!("comment object {object} {}\n", mesh.name);
+    }
+    contents += &format!(
+        "element vertex {vertex_count}\nproperty float x\nproperty float y\nproperty float z\n"
+    );
+    contents += &format!(
+        "element face {face_count}\nproperty list uchar int vertex_indices\nproperty int object\n"
+    );
+    contents += "end_header\n";
+
+    for mesh in meshes {
+        for v in &mesh.vertices {
+            contents += &format!("{} {} {}\n", v.x, v.y, v.z);
+        }
+    }
+    let mut offset = 0;
+    for (object, mesh) in meshes.iter().enumerate() {
+        for face in &mesh.faces {
+            let indices: Vec<String> = face.iter().map(|i| (i + offset).to_string()).collect();
+            contents += &format!("{} {} {object}\n", face.len(), indices.join(" "));
+        }
+        offset += mesh.vertices.len();
+    }
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::{block_box, camera_frustum, to_obj};
+    use crate::{camera::Camera, image::Image};
+
+    #[test]
+    fn test_camera_frustum() {
+        let pos = Vector3::new(0.0, 0.0, 5.0);
+        let camera = Camera::new(
+            4,
+            2,
+            pos,
+            Vector3::zeros(),
+            Vector3::y(),
+            std::f32::consts::FRAC_PI_2,
+            0.01,
+            1000.0,
+        );
+        let image = Image::new("front.png".to_owned(), vec![0; 4 * 2 * 3], camera);
+        let frustum = camera_frustum(&image, 5.0);
+
+        // a 90 degree height angle at a depth of 5 spans 10 units vertically, twice that horizontally
+        let expected = [
+            Vector3::new(-10.0, 5.0, 0.0),
+            Vector3::new(10.0, 5.0, 0.0),
+            Vector3::new(10.0, -5.0, 0.0),
+            Vector3::new(-10.0, -5.0, 0.0),
+        ];
+        assert_eq!(frustum.vertices[0], pos);
+        for (corner, expected) in frustum.vertices[1..5].iter().zip(expected) {
+            assert!((corner - expected).norm() < 1e-4, "{corner} vs {expected}");
+        }
+        // the up marker sits above the image
+        assert!(frustum.vertices[7].y > 5.0);
+
+        let obj = to_obj(&[
+            block_box(Vector3::repeat(-1.0), Vector3::repeat(1.0)),
+            frustum,
+        ]);
+        assert!(obj.contains("o bounds\n"));
+        assert!(obj.contains("o front.png\n"));
+        assert_eq!(
+            obj.lines().filter(|line| line.starts_with("v ")).count(),
+            16
+        );
+        // the frustum's faces point past the box's vertices
+        assert!(obj.contains("f 9 10 11\n"));
+    }
+}
@@ -2,10 +2,31 @@ use nalgebra::Vector3;
 
 use crate::image::Image;
 
+/// Vertical field of view of the scenes' cameras in radians, about 81 degrees. The images are in
+/// the raytracer's convention: x to the right and y down from the top left, as seen by a camera
+/// looking at the origin with y up, see `CameraModel`.
+/// The scenes used to pass 30, whose half angle has a negative tangent. That is the same field of
+/// view, but the old carver's projection read the images mirrored left to right with it, see
+/// `by_name`.
+const HEIGHT_ANGLE: f32 = 1.415;
+
+/// The scene of that name, None if there is no such scene.
+/// Its images are mirrored left to right as they are loaded, which is how the carver has always
+/// read them, so the scenes carve the same way round as they always have.
+pub(crate) fn by_name(name: &str) -> Option<Vec<Image>> {
+    let images = match name {
+        "three_cylinders" => three_cylinders(),
+        "cone" => cone(),
+        "two_cones" => two_cones(),
+        _ => return None,
+    };
+    Some(images.into_iter().map(Image::mirrored).collect())
+}
+
 pub(crate) fn three_cylinders() -> Vec<Image> {
     let up = Vector3::new(0.0, 1.0, 0.0);
     let focus = Vector3::new(0.0, 0.0, 0.0);
-    let height_angle = 30.0;
+    let height_angle = HEIGHT_ANGLE;
     let width = 1024;
     let height = 768;
     let image_0 = Image::new_from_file(
@@ -106,7 +127,7 @@ pub(crate) fn three_cylinders() -> Vec<Image> {
 pub(crate) fn cone() -> Vec<Image> {
     let up = Vector3::new(0.0, 1.0, 0.0);
     let focus = Vector3::new(0.0, 0.0, 0.0);
-    let height_angle = 30.0;
+    let height_angle = HEIGHT_ANGLE;
     let width = 1024;
     let height = 768;
     let image_0 = Image::new_from_file(
@@ -154,49 +175,13 @@ pub(crate) fn cone() -> Vec<Image> {
         width,
         height,
     );
-    let image_5 = Image::new_from_file(
-        "./data/input/cone/cone_3,-05,0.png".to_owned(),
-        Vector3::new(3.0, -0.5, 0.0),
-        up,
-        focus,
-        height_angle,
-        width,
-        height,
-    );
-    let image_6 = Image::new_from_file(
-        "./data/input/cone/cone_-3,-05,0.png".to_owned(),
-        Vector3::new(-3.0, -0.5, 0.0),
-        up,
-        focus,
-        height_angle,
-        width,
-        height,
-    );
-    let image_7 = Image::new_from_file(
-        "./data/input/cone/cone_0,-05,3.png".to_owned(),
-        Vector3::new(0.0, -0.5, 3.0),
-        up,
-        focus,
-        height_angle,
-        width,
-        height,
-    );
-    let image_8 = Image::new_from_file(
-        "./data/input/cone/cone_0,-05,-3.png".to_owned(),
-        Vector3::new(0.0, -0.5, -3.0),
-        up,
-        focus,
-        height_angle,
-        width,
-        height,
-    );
-    vec![image_0, image_1, image_2, image_3, image_4] //, image_5, image_6, image_7, image_8]
+    vec![image_0, image_1, image_2, image_3, image_4]
 }
 
 pub(crate) fn two_cones() -> Vec<Image> {
     let up = Vector3::new(0.0, 1.0, 0.0);
     let focus = Vector3::new(0.0, 0.0, 0.0);
-    let height_angle = 30.0;
+    let height_angle = HEIGHT_ANGLE;
     let width = 1024;
     let height = 768;
     let image_0 = Image::new_from_file(
@@ -293,3 +278,51 @@ pub(crate) fn two_cones() -> Vec<Image> {
         image_0, image_1, image_2, image_3, image_4, image_5, image_6, image_7, image_8, image_9,
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Vector2, Vector3};
+
+    use super::HEIGHT_ANGLE;
+    use crate::{
+        camera::{Camera, CameraModel},
+        raytracer::generate_ray,
+    };
+
+    #[test]
+    fn test_orientation() {
+        // like the scenes' camera on the +x axis, which has -z on its right
+        let camera = Camera::new(
+            1024,
+            768,
+            Vector3::new(5.0, 0.0, 0.0),
+            Vector3::zeros(),
+            Vector3::y(),
+            HEIGHT_ANGLE,
+            0.01,
+            1000.0,
+        );
+        let right = camera.project(Vector3::new(0.0, 0.0, -1.0)).unwrap();
+        assert!(right.x > 512.0 && (right.y - 384.0).abs() < 1e-2, "{right}");
+        // the old carver's height angle of 30 gave about the opposite focal length, so it read
+        // this point from the other side of the file, where the mirrored image has it
+        let old_focal_length = 384.0 / (30.0f32 / 2.0).tan();
+        let old_x = 512.0 + (right.x - 512.0) * old_focal_length / camera.focal_length();
+        assert!((old_x - (1024.0 - right.x)).abs() < 0.5, "{old_x}");
+        let up = camera.project(Vector3::new(0.0, 1.0, 0.0)).unwrap();
+        assert!(up.y < 384.0 && (up.x - 512.0).abs() < 1e-2, "{up}");
+        // the top edge of the image is half the field of view above the axis
+        let top = Vector3::new(0.0, 5.0 * (HEIGHT_ANGLE / 2.0).tan(), 0.0);
+        assert!(camera.project_unclipped(top).unwrap().y.abs() < 1e-2);
+
+        // the raytracer renders the same way round
+        let ray = generate_ray(1000, 384, &camera);
+        assert!(ray.d.z < 0.0);
+        let ray = generate_ray(512, 10, &camera);
+        assert!(ray.d.y > 0.0);
+        assert!(
+            (camera.project(ray.p.xyz() + ray.d.xyz()).unwrap() - Vector2::new(512.5, 10.5)).norm()
+                < 1e-2
+        );
+    }
+}
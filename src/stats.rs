@@ -0,0 +1,153 @@
+use serde::Serialize;
+
+use crate::{carver::Consistency, config::Sweep, image::Image};
+
+/// What a carve did, returned by `carve` and written out as JSON so runs can be compared.
+/// Voxels are counted once per test, so a voxel kept in one sweep and tested again in the next
+/// counts in both.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct CarvingStats {
+    // every voxel the carve carved, whatever carved it, less any the regularization restored
+    pub(crate) carved: usize,
+    pub(crate) rounds: Vec<RoundStats>,
+    // carved by the visual hull mode
+    pub(crate) outside_visual_hull: usize,
+    // never tested by any view and carved at the end, see `Inconclusive::Carve`
+    pub(crate) inconclusive: usize,
+    // carved and restored by the graph cut after the carve, see `Regularization`
+    pub(crate) regularization_carved: usize,
+    pub(crate) regularization_restored: usize,
+    // in the order of the images
+    pub(crate) cameras: Vec<CameraStats>,
+    pub(crate) seconds: f64,
+    // stopped early by its cancellation token
+    pub(crate) cancelled: bool,
+}
+
+/// One round of sweeps, or voxel coloring's single pass
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct RoundStats {
+    pub(crate) background: usize,
+    pub(crate) inconsistent: usize,
+    pub(crate) sweeps: Vec<SweepStats>,
+    pub(crate) seconds: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct SweepStats {
+    // None for voxel coloring, which sweeps along its own direction
+    pub(crate) sweep: Option<Sweep>,
+    // images taking part in at least one slice
+    pub(crate) views: usize,
+    // images taking part in each slice, in sweep order
+    pub(crate) slice_views: Vec<usize>,
+    pub(crate) tested: usize,
+    pub(crate) consistent: usize,
+    // carved because enough views saw background
+    pub(crate) background: usize,
+    // carved because the views disagreed on the color
+    pub(crate) inconsistent: usize,
+    // samples dropped as specular highlights
+    pub(crate) highlights: usize,
+    pub(crate) seconds: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct CameraStats {
+    pub(crate) name: String,
+    // slices of sweeps, or voxel coloring layers, the camera took part in
+    pub(crate) slices: usize,
+    // voxel tests the camera's samples took part in
+    pub(crate) tests: usize,
+    // voxels carved on its evidence, background it saw or a color the others disagreed with
+    pub(crate) carved: usize,
+    // memory its projection cache took by the end, see `CarvingConfig::cache_projections`
+    pub(crate) projection_cache_bytes: usize,
+}
+
+impl CarvingStats {
+    pub(crate) fn new(images: &[Image]) -> Self {
+        CarvingStats {
+            cameras: images
+                .iter()
+                .map(|image| CameraStats {
+                    name: image.name.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds the stats of a carve over `images[first_image..]` to these, over all the images.
+    pub(crate) fn absorb(&mut self, other: CarvingStats, first_image: usize) {
+        self.carved += other.carved;
+        self.rounds.extend(other.rounds);
+        self.outside_visual_hull += other.outside_visual_hull;
+        self.inconclusive += other.inconclusive;
+        self.regularization_carved += other.regularization_carved;
+        self.regularization_restored += other.regularization_restored;
+        self.cancelled |= other.cancelled;
+        for (camera, other) in self.cameras[first_image..].iter_mut().zip(other.cameras) {
+            camera.tests += other.tests;
+            camera.carved += other.carved;
+            camera.slices += other.slices;
+            // each pass has a cache of its own
+            camera.projection_cache_bytes = camera
+                .projection_cache_bytes
+                .max(other.projection_cache_bytes);
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Unable to serialize carving stats")
+    }
+}
+
+impl RoundStats {
+    /// Adds a finished sweep's counts to the round's.
+    pub(crate) fn push(&mut self, sweep: SweepStats) {
+        self.background += sweep.background;
+        self.inconsistent += sweep.inconsistent;
+        self.sweeps.push(sweep);
+    }
+}
+
+impl SweepStats {
+    pub(crate) fn carved(&self) -> usize {
+        self.background + self.inconsistent
+    }
+
+    /// Counts a voxel's test and credits the cameras behind it, `images` giving the image index
+    /// of each view position in the verdict.
+    pub(crate) fn record(
+        &mut self,
+        consistency: &Consistency,
+        images: &[usize],
+        cameras: &mut [CameraStats],
+    ) {
+        self.tested += 1;
+        let (views, carved) = match consistency {
+            Consistency::Consistent(_, seen_by) => {
+                self.consistent += 1;
+                (seen_by, false)
+            }
+            Consistency::Inconsistent(seen_by) => {
+                self.inconsistent += 1;
+                (seen_by, true)
+            }
+            Consistency::Background(saw_background) => {
+                self.background += 1;
+                (saw_background, true)
+            }
+            Consistency::Inconclusive => return,
+        };
+        for &view in views {
+            let camera = &mut cameras[images[view]];
+            camera.tests += 1;
+            if carved {
+                camera.carved += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use nalgebra::Vector3;
+
+use crate::{
+    camera::Camera,
+    image::Image,
+    raytracer::{generate_ray, trace_ray},
+    voxel::VoxelBlock,
+};
+
+// cameras around a block at the origin, above and below it, each seeing a different corner
+pub(crate) const AROUND: [[f32; 3]; 4] = [
+    [4.0, 2.5, 3.0],
+    [-2.5, 3.0, 4.0],
+    [3.0, -4.0, -2.5],
+    [-3.0, 2.0, -4.0],
+];
+
+/// One color per voxel of a block of resolution 2
+pub(crate) fn voxel_color(index: usize) -> [u8; 3] {
+    [40 + 25 * index as u8, 200 - 20 * index as u8, 100]
+}
+
+/// A camera at `pos` looking at the origin, up along y
+pub(crate) fn camera_at(
+    pos: Vector3<f32>,
+    width: usize,
+    height: usize,
+    height_angle: f32,
+) -> Camera {
+    Camera::new(
+        width,
+        height,
+        pos,
+        Vector3::zeros(),
+        Vector3::y(),
+        height_angle,
+        0.01,
+        1000.0,
+    )
+}
+
+/// Renders the block with the raytracer, each voxel hit in its `color`, on black background
+pub(crate) fn render_with(
+    voxel_block: &VoxelBlock,
+    camera: Camera,
+    color: impl Fn(usize) -> [u8; 3],
+) -> Image {
+    let (width, height) = (camera.width, camera.height);
+    let mut data = vec![0; width * height * 3];
+    for j in 0..height {
+        for i in 0..width {
+            if let Some(hit) = trace_ray(&generate_ray(i, j, &camera), voxel_block, 0) {
+                let index = (i + j * width) * 3;
+                data[index..index + 3].copy_from_slice(&color(hit));
+            }
+        }
+    }
+    Image::new(format!("{}", camera.pos), data, camera)
+}
+
+/// Renders the block from `pos` in `voxel_color`
+pub(crate) fn render(voxel_block: &VoxelBlock, pos: Vector3<f32>) -> Image {
+    render_with(voxel_block, camera_at(pos, 64, 48, 1.0), voxel_color)
+}
+
+/// Renders the block from each of `AROUND`
+pub(crate) fn render_around(voxel_block: &VoxelBlock) -> Vec<Image> {
+    AROUND
+        .iter()
+        .map(|&[x, y, z]| render(voxel_block, Vector3::new(x, y, z)))
+        .collect()
+}
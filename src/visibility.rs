@@ -0,0 +1,199 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use nalgebra::{Vector2, Vector3};
+
+use crate::{
+    camera::{Camera, CameraModel},
+    voxel::VoxelBlock,
+};
+
+// what a pixel holds besides a voxel index
+// not looked up yet
+const UNTRACED: usize = usize::MAX;
+// the pixel's ray leaves the block without hitting anything
+const EMPTY: usize = usize::MAX - 1;
+
+/// Item buffer from Generalized Voxel Coloring: for every pixel of an image, the nearest uncarved
+/// voxel along the pixel's ray. A voxel only takes part in a view's consistency test if it owns the
+/// pixel it projects to there.
+/// Pixels are traced the first time they are looked up, and traced again when the voxel they hold
+/// has been carved since, so the buffer follows the block as it is carved.
+/// Lookups only need a shared reference so threads can share a buffer, two threads tracing the
+/// same pixel of the same block store the same voxel.
+pub(crate) struct ItemBuffer {
+    items: Vec<AtomicUsize>,
+    width: usize,
+    height: usize,
+}
+
+impl ItemBuffer {
+    pub(crate) fn new(camera: &Camera) -> Self {
+        ItemBuffer {
+            items: (0..camera.width * camera.height)
+                .map(|_| AtomicUsize::new(UNTRACED))
+                .collect(),
+            width: camera.width,
+            height: camera.height,
+        }
+    }
+
+    /// The nearest uncarved voxel seen through the pixel containing the image coordinates.
+    pub(crate) fn item(
+        &self,
+        pixel: Vector2<f32>,
+        camera: &Camera,
+        voxel_block: &VoxelBlock,
+    ) -> Option<usize> {
+        if pixel.x < 0.0 || pixel.y < 0.0 {
+            return None;
+        }
+        let (i, j) = (pixel.x as usize, pixel.y as usize);
+        if i >= self.width || j >= self.height {
+            return None;
+        }
+        let item = &self.items[i + j * self.width];
+        let mut value = item.load(Ordering::Relaxed);
+        let stale = match value {
+            UNTRACED => true,
+            EMPTY => false,
+            index => voxel_block.voxels[index].carved,
+        };
+        if stale {
+            let center = Vector2::new(i as f32 + 0.5, j as f32 + 0.5);
+            value = first_uncarved_voxel(camera, voxel_block, center).unwrap_or(EMPTY);
+            item.store(value, Ordering::Relaxed);
+        }
+        match value {
+            EMPTY => None,
+            index => Some(index),
+        }
+    }
+
+    /// Whether the voxel is what the camera sees at the image coordinates.
+    pub(crate) fn sees(
+        &self,
+        index: usize,
+        pixel: Vector2<f32>,
+        camera: &Camera,
+        voxel_block: &VoxelBlock,
+    ) -> bool {
+        self.item(pixel, camera, voxel_block) == Some(index)
+    }
+}
+
+/// Walks the ray through the image coordinates voxel by voxel (Amanatides and Woo)
+/// and returns the first one that isn't carved.
+fn first_uncarved_voxel(
+    camera: &Camera,
+    voxel_block: &VoxelBlock,
+    pixel: Vector2<f32>,
+) -> Option<usize> {
+    let ray = camera.unproject(pixel);
+    let (origin, dir) = (ray.p.xyz(), ray.d.xyz());
+    let (min, max) = voxel_block.bounds();
+    let resolution = voxel_block.resolution;
+    let voxel_length = voxel_block.voxel_length();
+
+    // clip the ray to the block
+    let mut t_enter = 0.0f32;
+    let mut t_exit = f32::MAX;
+    for axis in 0..3 {
+        if dir[axis] == 0.0 {
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return None;
+            }
+            continue;
+        }
+        let t_min = (min[axis] - origin[axis]) / dir[axis];
+        let t_max = (max[axis] - origin[axis]) / dir[axis];
+        t_enter = t_enter.max(t_min.min(t_max));
+        t_exit = t_exit.min(t_min.max(t_max));
+    }
+    if t_enter > t_exit {
+        return None;
+    }
+
+    let entry = origin + dir * t_enter;
+    let mut cell = Vector3::zeros();
+    let mut step = Vector3::zeros();
+    let mut t_next = Vector3::repeat(f32::MAX);
+    let mut t_delta = Vector3::repeat(f32::MAX);
+    for axis in 0..3 {
+        let local = (entry[axis] - min[axis]) / voxel_length;
+        cell[axis] = (local.floor() as i64).clamp(0, resolution as i64 - 1);
+        if dir[axis] > 0.0 {
+            step[axis] = 1;
+            let boundary = min[axis] + (cell[axis] + 1) as f32 * voxel_length;
+            t_next[axis] = (boundary - origin[axis]) / dir[axis];
+            t_delta[axis] = voxel_length / dir[axis];
+        } else if dir[axis] < 0.0 {
+            step[axis] = -1;
+            let boundary = min[axis] + cell[axis] as f32 * voxel_length;
+            t_next[axis] = (boundary - origin[axis]) / dir[axis];
+            t_delta[axis] = -voxel_length / dir[axis];
+        }
+    }
+
+    loop {
+        let index = cell.x as usize
+            + cell.y as usize * resolution
+            + cell.z as usize * resolution * resolution;
+        if !voxel_block.voxels[index].carved {
+            return Some(index);
+        }
+        let axis = t_next.imin();
+        if t_next[axis] > t_exit {
+            return None;
+        }
+        cell[axis] += step[axis];
+        if cell[axis] < 0 || cell[axis] >= resolution as i64 {
+            return None;
+        }
+        t_next[axis] += t_delta[axis];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::ItemBuffer;
+    use crate::{
+        camera::{Camera, CameraModel},
+        voxel::VoxelBlock,
+    };
+
+    #[test]
+    fn test_item_buffer_follows_carving() {
+        // a row of voxels along x, seen end on so each hides the ones behind it
+        let mut voxel_block = VoxelBlock::new(3, 3);
+        let pos = Vector3::new(5.0, 0.0, 0.0);
+        let camera = Camera::new(
+            32,
+            32,
+            pos,
+            Vector3::zeros(),
+            Vector3::y(),
+            0.5,
+            0.01,
+            1000.0,
+        );
+        let item_buffer = ItemBuffer::new(&camera);
+        let row: Vec<usize> = (0..3).map(|x| x + 3 + 9).collect();
+        let center = |index: usize| {
+            let (x, y, z) = voxel_block.index_to_coordinate(index);
+            Vector3::new(x, y, z).add_scalar(voxel_block.voxel_length() / 2.0)
+        };
+        let pixel = camera.project(center(row[0])).unwrap();
+        assert_eq!(pixel, camera.project(center(row[2])).unwrap());
+
+        // nearest first, and further ones once those in front are carved
+        assert!(item_buffer.sees(row[2], pixel, &camera, &voxel_block));
+        assert!(!item_buffer.sees(row[0], pixel, &camera, &voxel_block));
+        voxel_block.carve(row[2]);
+        assert!(item_buffer.sees(row[1], pixel, &camera, &voxel_block));
+        voxel_block.carve(row[1]);
+        voxel_block.carve(row[0]);
+        assert_eq!(item_buffer.item(pixel, &camera, &voxel_block), None);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{
+    carver::{observe_silhouette, Observation},
+    config::{CarvingConfig, Sampling},
+    image::Image,
+    provenance::{CameraSample, Provenance, Reason},
+    stats::CameraStats,
+    voxel::VoxelBlock,
+};
+
+/// Carves the visual hull of the images' silhouettes in one pass, colors are ignored.
+/// A voxel is carved when it is background in at least `min_background_views` images, sampled as
+/// `config.sampling` says, views that don't see the voxel don't count either way.
+/// Visibility plays no part, so there is no sweep order and every voxel is decided at once.
+/// The views behind each verdict are credited in `cameras` as a sweep's would be.
+/// Returns the number of voxels carved, recording the images that carved each if `provenance`.
+pub(crate) fn carve_visual_hull(
+    voxel_block: &mut VoxelBlock,
+    images: &[Image],
+    min_background_views: usize,
+    config: &CarvingConfig,
+    cameras: &mut [CameraStats],
+) -> usize {
+    let mut carved = vec![];
+    for (index, voxel) in voxel_block.voxels.iter().enumerate() {
+        if voxel.carved {
+            continue;
+        }
+        let observations = silhouette_observations(voxel_block, images, index, config.sampling);
+        let background_views: Vec<usize> = observations
+            .iter()
+            .filter(|(_, observation)| matches!(observation, Observation::Background))
+            .map(|&(image, _)| image)
+            .collect();
+        if background_views.len() >= min_background_views.max(1) {
+            for &image in &background_views {
+                cameras[image].tests += 1;
+                cameras[image].carved += 1;
+            }
+            carved.push((index, background_views));
+        } else {
+            for (image, _) in observations {
+                cameras[image].tests += 1;
+            }
+        }
+    }
+
+    let carved_count = carved.len();
+    for (index, background_views) in carved {
+        if config.provenance {
+            voxel_block.set_provenance(
+                index,
+                Provenance {
+                    round: None,
+                    sweep: None,
+                    slice: None,
+                    reason: Reason::OutsideVisualHull,
+                    cameras: background_views
+                        .into_iter()
+                        .map(|image| CameraSample { image, color: None })
+                        .collect(),
+                },
+            );
+        }
+        voxel_block.carve(index);
+    }
+    carved_count
+}
+
+/// Indices of the images where the voxel is background, sampled as `sampling` says
+pub(crate) fn background_views(
+    voxel_block: &VoxelBlock,
+    images: &[Image],
+    index: usize,
+    sampling: Sampling,
+) -> Vec<usize> {
+    silhouette_observations(voxel_block, images, index, sampling)
+        .into_iter()
+        .filter(|(_, observation)| matches!(observation, Observation::Background))
+        .map(|(image, _)| image)
+        .collect()
+}
+
+/// What each image that sees the voxel's silhouette makes of it, by image index
+fn silhouette_observations(
+    voxel_block: &VoxelBlock,
+    images: &[Image],
+    index: usize,
+    sampling: Sampling,
+) -> Vec<(usize, Observation)> {
+    images
+        .iter()
+        .enumerate()
+        .map(|(image_index, image)| {
+            let observation = observe_silhouette(index, image, voxel_block, sampling);
+            (image_index, observation)
+        })
+        .filter(|(_, observation)| !matches!(observation, Observation::Unseen))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::carve_visual_hull;
+    use crate::{
+        camera::Camera,
+        config::{CarvingConfig, Sampling},
+        image::Image,
+        raytracer::{generate_ray, trace_ray},
+        stats::CarvingStats,
+        voxel::VoxelBlock,
+    };
+
+    #[test]
+    fn test_carve_visual_hull() {
+        // one voxel's worth of object, seen along each axis
+        let object = VoxelBlock::with_center(Vector3::repeat(0.25), 0.5, 1);
+        let target = object.center;
+        let images: Vec<Image> = [
+            (Vector3::x(), Vector3::y()),
+            (Vector3::y(), Vector3::z()),
+            (Vector3::z(), Vector3::y()),
+        ]
+        .iter()
+        .map(|&(axis, up)| {
+            let pos = target + axis * 6.0;
+            let camera = Camera::new(32, 32, pos, target, up, 0.5, 0.01, 1000.0);
+            let mut data = vec![0; 32 * 32 * 3];
+            for j in 0..32 {
+                for i in 0..32 {
+                    if trace_ray(&generate_ray(i, j, &camera), &object, 0).is_some() {
+                        let index = (i + j * 32) * 3;
+                        data[index..index + 3].copy_from_slice(&[255, 255, 255]);
+                    }
+                }
+            }
+            Image::new(format!("{axis}"), data, camera)
+        })
+        .collect();
+        let object_index = 2 + 2 * 4 + 2 * 16;
+        let survivors = |voxel_block: &VoxelBlock| -> Vec<usize> {
+            (0..voxel_block.voxels.len())
+                .filter(|&index| !voxel_block.voxels[index].carved)
+                .collect()
+        };
+
+        let config = CarvingConfig::default();
+
+        // any background view carves, so only the object is left
+        let mut voxel_block = VoxelBlock::new(2, 4);
+        let mut stats = CarvingStats::new(&images);
+        let carved = carve_visual_hull(&mut voxel_block, &images, 1, &config, &mut stats.cameras);
+        assert_eq!(carved, 63);
+        assert_eq!(survivors(&voxel_block), vec![object_index]);
+        // every view sees every voxel, and carves the ones it sees background at
+        for camera in &stats.cameras {
+            assert!(camera.carved > 0);
+            assert!(camera.tests > camera.carved);
+        }
+
+        // the same through the voxels' footprints
+        let footprint = CarvingConfig {
+            sampling: Sampling::Footprint {
+                bounding_rectangle: false,
+                max_background_fraction: 0.5,
+            },
+            ..Default::default()
+        };
+        let mut voxel_block = VoxelBlock::new(2, 4);
+        let mut stats = CarvingStats::new(&images);
+        carve_visual_hull(&mut voxel_block, &images, 1, &footprint, &mut stats.cameras);
+        assert_eq!(survivors(&voxel_block), vec![object_index]);
+
+        // needing every view to agree keeps everything in front of or behind the object in some view
+        let mut lenient = VoxelBlock::new(2, 4);
+        carve_visual_hull(&mut lenient, &images, 3, &config, &mut stats.cameras);
+        let lenient_survivors = survivors(&lenient);
+        assert!(lenient_survivors.contains(&object_index));
+        assert!(lenient_survivors.contains(&(2 + 2 * 4 + 3 * 16)));
+        assert!(lenient_survivors.len() > 1);
+    }
+}
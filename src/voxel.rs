@@ -1,12 +1,30 @@
 use std::{
-    fs::File,
+    collections::HashMap,
+    fs::{self, File},
     io::Write,
     ops::{Index, IndexMut},
 };
 
-use nalgebra::{Matrix4, Translation3, Vector3, Vector4};
+#[cfg(test)]
+use nalgebra::Vector4;
+use nalgebra::{Matrix4, Translation3, Vector3};
 use ordered_float::OrderedFloat;
 
+use crate::{probabilistic::Estimate, provenance::Provenance};
+
+/// How the views voted on a voxel the last time it was tested
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Votes {
+    // views that saw background where the voxel is
+    pub(crate) background: usize,
+    // views that saw it in some color
+    pub(crate) foreground: usize,
+    // foreground views left out for disagreeing with the rest
+    pub(crate) outliers: usize,
+    // foreground views left out as specular highlights, before looking for outliers
+    pub(crate) highlights: usize,
+}
+
 #[derive(Default, Clone, Debug)]
 pub(crate) struct Voxel {
     pub(crate) carved: bool,
@@ -17,12 +35,23 @@ pub(crate) struct Voxel {
     pub(crate) inverse_ctm: Matrix4<f32>,
 }
 
+#[derive(Clone)]
 pub(crate) struct VoxelBlock {
     pub(crate) voxels: Vec<Voxel>,
     // side length for the block to be carved
-    pub(crate) length: usize,
+    pub(crate) length: f32,
+    // world space center of the block
+    pub(crate) center: Vector3<f32>,
     // how many voxels per side
     pub(crate) resolution: usize,
+    // what only some carves keep about the voxels, by index, None unless the carve's config asks
+    // for it so other carves don't pay for it
+    // each voxel's occupancy estimate, kept by probabilistic carving
+    pub(crate) occupancy: Option<Vec<Estimate>>,
+    // how the views voted on each voxel the last time it was tested, kept for regularization
+    pub(crate) votes: Option<Vec<Votes>>,
+    // how the carved voxels were carved, kept when `provenance` is on
+    pub(crate) provenance: Option<HashMap<usize, Provenance>>,
 }
 
 impl Index<(OrderedFloat<f32>, OrderedFloat<f32>, OrderedFloat<f32>)> for VoxelBlock {
@@ -65,11 +94,31 @@ impl Voxel {
 }
 
 impl VoxelBlock {
+    /// A block centered on the origin
+    // the tests' blocks, the carve's are sized by its config
+    #[cfg(test)]
     pub fn new(length: usize, resolution: usize) -> Self {
+        Self::with_center(Vector3::zeros(), length as f32, resolution)
+    }
+
+    /// The smallest block containing the box from `min` to `max`.
+    /// Blocks are cubes, so it spans the longest side of the box and is centered on it.
+    pub fn from_bounds(min: Vector3<f32>, max: Vector3<f32>, resolution: usize) -> Self {
+        let center = (min + max) / 2.0;
+        let length = (max - min).max();
+        Self::with_center(center, length, resolution)
+    }
+
+    /// A new, uncarved block over the same volume
+    pub fn uncarved(&self) -> Self {
+        Self::with_center(self.center, self.length, self.resolution)
+    }
+
+    pub fn with_center(center: Vector3<f32>, length: f32, resolution: usize) -> Self {
         let mut voxels = vec![Voxel::new(); resolution * resolution * resolution];
 
-        let voxel_length = length as f32 / resolution as f32;
-        let baseline_shift = -(length as f32 / 2.0 - 0.5 * voxel_length);
+        let voxel_length = length / resolution as f32;
+        let baseline_shift = center.add_scalar(-(length / 2.0 - 0.5 * voxel_length));
         // println!("voxel_length: {}", voxel_length);
         // println!("baseline_shift: {}", baseline_shift);
 
@@ -101,26 +150,69 @@ impl VoxelBlock {
         VoxelBlock {
             voxels,
             length,
+            center,
             resolution,
+            occupancy: None,
+            votes: None,
+            provenance: None,
+        }
+    }
+
+    /// How the views voted on the voxel the last time it was tested, none if votes aren't kept
+    pub fn votes(&self, index: usize) -> Votes {
+        self.votes
+            .as_ref()
+            .map_or(Votes::default(), |votes| votes[index])
+    }
+
+    /// Keeps the votes, if they are kept
+    pub fn set_votes(&mut self, index: usize, votes: Votes) {
+        if let Some(all) = &mut self.votes {
+            all[index] = votes;
         }
     }
 
+    /// How the voxel was carved, if provenance is kept and it was
+    pub fn provenance(&self, index: usize) -> Option<&Provenance> {
+        self.provenance.as_ref()?.get(&index)
+    }
+
+    /// Keeps how the voxel was carved, if provenance is kept
+    pub fn set_provenance(&mut self, index: usize, provenance: Provenance) {
+        if let Some(all) = &mut self.provenance {
+            all.insert(index, provenance);
+        }
+    }
+
+    /// world space corners of the block, minimum first
+    pub fn bounds(&self) -> (Vector3<f32>, Vector3<f32>) {
+        let half = self.length / 2.0;
+        (self.center.add_scalar(-half), self.center.add_scalar(half))
+    }
+
     pub fn voxel_length(&self) -> f32 {
-        self.length as f32 / self.resolution as f32
+        self.length / self.resolution as f32
     }
 
     pub fn coordinate_to_index(&self, x: f32, y: f32, z: f32) -> usize {
-        Self::_coordinate_to_index(x, y, z, self.length, self.resolution)
+        Self::_coordinate_to_index(x, y, z, self.center, self.length, self.resolution)
     }
 
-    fn _coordinate_to_index(x: f32, y: f32, z: f32, length: usize, resolution: usize) -> usize {
-        // reshift (0,0,0) to origin
-        let half = length as f32 / 2.0;
-        let x_2 = x + half;
-        let y_2 = y + half;
-        let z_2 = z + half;
-
-        let voxel_length = length as f32 / resolution as f32;
+    fn _coordinate_to_index(
+        x: f32,
+        y: f32,
+        z: f32,
+        center: Vector3<f32>,
+        length: f32,
+        resolution: usize,
+    ) -> usize {
+        // reshift the minimum corner of the block to origin
+        let half = length / 2.0;
+        let x_2 = x - center.x + half;
+        let y_2 = y - center.y + half;
+        let z_2 = z - center.z + half;
+
+        let voxel_length = length / resolution as f32;
         let x_index = (x_2 / voxel_length) as usize;
         let y_index = (y_2 / voxel_length) as usize;
         let z_index = (z_2 / voxel_length) as usize;
@@ -129,19 +221,28 @@ impl VoxelBlock {
     }
 
     pub fn index_to_coordinate(&self, index: usize) -> (f32, f32, f32) {
-        Self::_index_to_coordinate(index, self.length, self.resolution)
+        Self::_index_to_coordinate(index, self.center, self.length, self.resolution)
     }
 
-    fn _index_to_coordinate(index: usize, length: usize, resolution: usize) -> (f32, f32, f32) {
-        let voxel_length = length as f32 / resolution as f32;
+    fn _index_to_coordinate(
+        index: usize,
+        center: Vector3<f32>,
+        length: f32,
+        resolution: usize,
+    ) -> (f32, f32, f32) {
+        let voxel_length = length / resolution as f32;
         let z = (index / (resolution * resolution)) as f32 * voxel_length;
         let remainder = index % (resolution * resolution);
         let y = (remainder / resolution) as f32 * voxel_length;
         let x = (remainder % resolution) as f32 * voxel_length;
 
         // recenter origin as the center of the block
-        let half = length as f32 / 2.0;
-        (x - half, y - half, z - half)
+        let half = length / 2.0;
+        (
+            x - half + center.x,
+            y - half + center.y,
+            z - half + center.z,
+        )
     }
 
     fn calculate_ctm(
@@ -149,7 +250,7 @@ impl VoxelBlock {
         y: usize,
         z: usize,
         voxel_length: f32,
-        baseline_shift: f32,
+        baseline_shift: Vector3<f32>,
     ) -> Matrix4<f32> {
         let scale = Matrix4::new_scaling(voxel_length);
         let trans = Self::translation(x, y, z, voxel_length, baseline_shift);
@@ -162,11 +263,11 @@ impl VoxelBlock {
         y: usize,
         z: usize,
         voxel_length: f32,
-        baseline_shift: f32,
+        baseline_shift: Vector3<f32>,
     ) -> Matrix4<f32> {
-        let x_shift = x as f32 * voxel_length + baseline_shift;
-        let y_shift = y as f32 * voxel_length + baseline_shift;
-        let z_shift = z as f32 * voxel_length + baseline_shift;
+        let x_shift = x as f32 * voxel_length + baseline_shift.x;
+        let y_shift = y as f32 * voxel_length + baseline_shift.y;
+        let z_shift = z as f32 * voxel_length + baseline_shift.z;
         let t = Translation3::new(x_shift, y_shift, z_shift);
         t.to_homogeneous()
     }
@@ -269,6 +370,134 @@ impl VoxelBlock {
         println!("Inconclusive {inconclusive}");
     }
 
+    /// Writes every voxel's occupancy probability, one per line in index order (x fastest, then
+    /// y, then z), after a header line with the resolution, length and center of the block.
+    /// Voxels no view ever saw have no estimate and are written as `-`.
+    pub fn save_occupancy_to_file(&self, file_path: &str) {
+        let f = File::create(file_path);
+        let mut file = f.expect("Unable to open or create file");
+
+        let mut contents = format!(
+            "# resolution {} length {} center {} {} {}\n",
+            self.resolution, self.length, self.center.x, self.center.y, self.center.z
+        );
+        let estimates = self.occupancy.as_deref().unwrap_or_default();
+        for index in 0..self.voxels.len() {
+            match estimates.get(index) {
+                Some(estimate) if !estimate.evidence.is_empty() => {
+                    contents.push_str(&format!("{}\n", estimate.occupancy))
+                }
+                _ => contents.push_str("-\n"),
+            }
+        }
+        file.write_all(contents.as_bytes())
+            .expect("Unable to write to file");
+    }
+
+    /// Writes what a carve decided so it can be continued later, see `from_state`: a header line
+    /// like the occupancy file's, then one line per voxel in index order, `x` if it is carved,
+    /// its color as `r g b` or `-` if it has none.
+    pub fn save_state_to_file(&self, file_path: &str) {
+        let f = File::create(file_path);
+        let mut file = f.expect("Unable to open or create file");
+
+        let mut contents = format!(
+            "# resolution {} length {} center {} {} {}\n",
+            self.resolution, self.length, self.center.x, self.center.y, self.center.z
+        );
+        for voxel in &self.voxels {
+            match voxel.color {
+                _ if voxel.carved => contents.push_str("x\n"),
+                Some(color) => contents.push_str(&format!("{} {} {}\n", color.x, color.y, color.z)),
+                None => contents.push_str("-\n"),
+            }
+        }
+        file.write_all(contents.as_bytes())
+            .expect("Unable to write to file");
+    }
+
+    pub fn load_state_from_file(file_path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(file_path).map_err(|error| error.to_string())?;
+        Self::from_state(&contents)
+    }
+
+    /// A block as `save_state_to_file` wrote it, carved and colored the same
+    pub fn from_state(contents: &str) -> Result<Self, String> {
+        let mut lines = contents.lines();
+        let header: Vec<&str> = lines
+            .next()
+            .ok_or("empty block state")?
+            .split_whitespace()
+            .collect();
+        let number = |position: usize| -> Result<f32, String> {
+            header
+                .get(position)
+                .and_then(|value| value.parse().ok())
+                .ok_or(format!("bad block state header {header:?}"))
+        };
+        if header.first() != Some(&"#") {
+            return Err(format!("bad block state header {header:?}"));
+        }
+        let resolution = header
+            .get(2)
+            .and_then(|value| value.parse().ok())
+            .ok_or(format!("bad block state header {header:?}"))?;
+        let center = Vector3::new(number(6)?, number(7)?, number(8)?);
+        let mut voxel_block = Self::with_center(center, number(4)?, resolution);
+
+        let mut count = 0;
+        for (index, line) in lines.enumerate() {
+            if index >= voxel_block.voxels.len() {
+                return Err(format!("more than {} voxels", voxel_block.voxels.len()));
+            }
+            match line.trim() {
+                "x" => voxel_block.carve(index),
+                "-" => {}
+                color => {
+                    let channels: Vec<u8> = color
+                        .split_whitespace()
+                        .map(|channel| channel.parse())
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| format!("bad color {color:?} for voxel {index}"))?;
+                    let [r, g, b] = channels[..] else {
+                        return Err(format!("bad color {color:?} for voxel {index}"));
+                    };
+                    voxel_block.voxels[index].color = Some(Vector3::new(r, g, b));
+                }
+            }
+            count += 1;
+        }
+        if count != voxel_block.voxels.len() {
+            return Err(format!(
+                "{count} voxels for a resolution of {resolution}, expected {}",
+                voxel_block.voxels.len()
+            ));
+        }
+        Ok(voxel_block)
+    }
+
+    /// Works out again which uncarved voxels are on the surface, on the boundary of the block or
+    /// next to a carved voxel, for when voxels are carved or restored other than by `carve`.
+    pub fn update_visibility(&mut self) {
+        let resolution = self.resolution;
+        let carved: Vec<bool> = self.voxels.iter().map(|voxel| voxel.carved).collect();
+        for (index, voxel) in self.voxels.iter_mut().enumerate() {
+            let coordinates = [
+                index % resolution,
+                index / resolution % resolution,
+                index / (resolution * resolution),
+            ];
+            let steps = [1, resolution, resolution * resolution];
+            let exposed = coordinates.iter().zip(steps).any(|(&coordinate, step)| {
+                coordinate == 0
+                    || coordinate == resolution - 1
+                    || carved[index - step]
+                    || carved[index + step]
+            });
+            voxel.visible = !voxel.carved && exposed;
+        }
+    }
+
     pub fn carve(&mut self, index: usize) {
         let res_squared = self.resolution * self.resolution;
         let voxel = &mut self.voxels[index];
@@ -324,6 +553,8 @@ impl VoxelBlock {
     }
 }
 
+// ray casting is only for rendering the tests' scenes
+#[cfg(test)]
 pub(crate) fn find_cube_intersect(
     voxel: &Voxel,
     pos: Vector4<f32>,
@@ -361,6 +592,7 @@ pub(crate) fn find_cube_intersect(
     }
 }
 
+#[cfg(test)]
 pub(crate) fn is_valid_cube_t(p: Vector4<f32>, d: Vector4<f32>, t: f32) -> bool {
     if t <= 0.0 {
         return false;
@@ -380,7 +612,7 @@ pub(crate) fn is_valid_cube_t(p: Vector4<f32>, d: Vector4<f32>, t: f32) -> bool
 
 #[cfg(test)]
 mod tests {
-    use nalgebra::Vector4;
+    use nalgebra::{Vector3, Vector4};
 
     use super::{find_cube_intersect, VoxelBlock};
 
@@ -451,4 +683,31 @@ mod tests {
 
         // assert!(intercept.is_some());
     }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut voxel_block = VoxelBlock::with_center(Vector3::new(0.5, -1.0, 2.0), 3.0, 3);
+        voxel_block.carve(4);
+        voxel_block.carve(13);
+        voxel_block.voxels[1].color = Some(Vector3::new(10, 200, 30));
+        let path = std::env::temp_dir().join("voxel_carving_test_state.txt");
+        let path = path.to_str().unwrap();
+        voxel_block.save_state_to_file(path);
+
+        let loaded = VoxelBlock::load_state_from_file(path).unwrap();
+        assert_eq!(
+            (loaded.resolution, loaded.length, loaded.center),
+            (3, 3.0, Vector3::new(0.5, -1.0, 2.0))
+        );
+        for (voxel, expected) in loaded.voxels.iter().zip(&voxel_block.voxels) {
+            assert_eq!(voxel.carved, expected.carved);
+            assert_eq!(voxel.visible, expected.visible);
+            assert_eq!(voxel.color, expected.color);
+        }
+
+        assert!(VoxelBlock::from_state("").is_err());
+        assert!(VoxelBlock::from_state("# resolution 1 length 1 center 0 0 0\n-\nx\n").is_err());
+        assert!(VoxelBlock::from_state("# resolution 1 length 1 center 0 0 0\n1 2\n").is_err());
+        assert!(VoxelBlock::from_state("# resolution 1 length 1 center 0 0 0\n1 2 3\n").is_ok());
+    }
 }
@@ -0,0 +1,355 @@
+use std::time::Instant;
+
+use nalgebra::Vector3;
+
+use crate::{
+    carver::{mark_footprints, should_carve_voxel, Consistency},
+    config::CarvingConfig,
+    image::Image,
+    progress::{CancellationToken, Cancelled, CarvingObserver},
+    provenance::{carved_by, Provenance},
+    stats::{CarvingStats, RoundStats, SweepStats},
+    visibility::ItemBuffer,
+    voxel::VoxelBlock,
+};
+
+/// Seitz and Dyer's voxel coloring: a single front to back pass over layers of voxels,
+/// marking the pixels of every consistent voxel so the ones behind it ignore them.
+/// Only valid when the cameras meet the ordinal visibility constraint, here that a plane separates
+/// every camera from the block. Layers are slabs parallel to that plane, taken in order of
+/// increasing distance from the cameras' side, so anything that can hide a voxel is decided first.
+/// Returns the number of voxels carved, or an error before touching the block if the cameras don't
+/// meet the constraint, as sweeping them in any order would carve the wrong voxels.
+/// The token is checked between layers. The pass is recorded in `stats` as one round of one sweep.
+pub(crate) fn color_voxels(
+    voxel_block: &mut VoxelBlock,
+    images: &mut [Image],
+    config: &CarvingConfig,
+    observer: &mut dyn CarvingObserver,
+    cancel: &CancellationToken,
+    stats: &mut CarvingStats,
+) -> Result<usize, ColoringError> {
+    let Some(normal) = ordinal_visibility_direction(voxel_block, images) else {
+        return Err(ColoringError::NoOrdinalVisibility);
+    };
+    let start = Instant::now();
+    let mut sweep_stats = SweepStats {
+        views: images.len(),
+        ..Default::default()
+    };
+    let result = color_layers(
+        voxel_block,
+        images,
+        normal,
+        config,
+        observer,
+        cancel,
+        &mut sweep_stats,
+        stats,
+    );
+    sweep_stats.seconds = start.elapsed().as_secs_f64();
+    let mut round_stats = RoundStats::default();
+    round_stats.push(sweep_stats);
+    round_stats.seconds = start.elapsed().as_secs_f64();
+    stats.rounds.push(round_stats);
+    Ok(result?)
+}
+
+/// Why voxel coloring stopped
+#[derive(Debug, PartialEq)]
+pub(crate) enum ColoringError {
+    // no plane separates the cameras from the block, so there is no front to back order
+    NoOrdinalVisibility,
+    Cancelled,
+}
+
+impl From<Cancelled> for ColoringError {
+    fn from(_: Cancelled) -> Self {
+        ColoringError::Cancelled
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn color_layers(
+    voxel_block: &mut VoxelBlock,
+    images: &mut [Image],
+    normal: Vector3<f32>,
+    config: &CarvingConfig,
+    observer: &mut dyn CarvingObserver,
+    cancel: &CancellationToken,
+    sweep_stats: &mut SweepStats,
+    stats: &mut CarvingStats,
+) -> Result<usize, Cancelled> {
+    // every image is a view, in order
+    let view_images: Vec<usize> = (0..images.len()).collect();
+    let item_buffers: Vec<ItemBuffer> = images
+        .iter()
+        .map(|image| ItemBuffer::new(&image.camera))
+        .collect();
+    let mut views: Vec<_> = images
+        .iter_mut()
+        .zip(item_buffers.iter())
+        .map(|(image, item_buffer)| {
+            image.marked.fill(false);
+            (image, item_buffer)
+        })
+        .collect();
+
+    let mut carved_count = 0;
+    let layers = layers(voxel_block, normal);
+    let layer_count = layers.len();
+    for (layer_number, layer) in layers.into_iter().enumerate() {
+        if cancel.is_cancelled() {
+            return Err(Cancelled);
+        }
+        sweep_stats.slice_views.push(view_images.len());
+        for camera in stats.cameras.iter_mut() {
+            camera.slices += 1;
+        }
+        let mut carved = vec![];
+        let mut consistent = vec![];
+        for index in layer {
+            if voxel_block.voxels[index].carved {
+                continue;
+            }
+            let (consistency, votes) = should_carve_voxel(index, &views, voxel_block, config);
+            voxel_block.set_votes(index, votes);
+            sweep_stats.highlights += votes.highlights;
+            sweep_stats.record(&consistency, &view_images, &mut stats.cameras);
+            if config.provenance {
+                let carved_by = carved_by(
+                    &consistency,
+                    index,
+                    &views,
+                    &view_images,
+                    voxel_block,
+                    config.sampling,
+                );
+                if let Some((reason, cameras)) = carved_by {
+                    voxel_block.set_provenance(
+                        index,
+                        Provenance {
+                            round: Some(0),
+                            sweep: None,
+                            slice: Some(layer_number),
+                            reason,
+                            cameras,
+                        },
+                    );
+                }
+            }
+            match consistency {
+                Consistency::Consistent(color, seen_by) => {
+                    voxel_block.voxels[index].color = Some(color);
+                    consistent.push((index, seen_by));
+                }
+                Consistency::Inconsistent(_) | Consistency::Background(_) => carved.push(index),
+                Consistency::Inconclusive => {}
+            }
+        }
+        // voxels in one layer can hide each other, the item buffer keeps the hidden one from
+        // testing the pixels in front of it, so the layer's footprints are marked together
+        mark_footprints(&mut views, voxel_block, consistent);
+
+        carved_count += carved.len();
+        observer.slice_finished(layer_number, layer_count, carved.len());
+        for index in carved {
+            voxel_block.carve(index);
+        }
+    }
+    Ok(carved_count)
+}
+
+/// Normal of a plane with every camera strictly on its positive side and the whole block on the
+/// other, the one leaving the widest gap, or None if there is no such plane.
+/// The hull of the cameras and the block are convex, so if they are apart one of the block's face
+/// normals, the hull's face normals or the cross products of an edge of each separates them.
+/// Every pair and triple of cameras stands in for the hull's edges and faces, cubic in the cameras.
+pub(crate) fn ordinal_visibility_direction(
+    voxel_block: &VoxelBlock,
+    images: &[Image],
+) -> Option<Vector3<f32>> {
+    let positions: Vec<Vector3<f32>> = images.iter().map(|image| image.camera.pos).collect();
+    let axes = [Vector3::x(), Vector3::y(), Vector3::z()];
+
+    let mut candidates = axes.to_vec();
+    for (i, a) in positions.iter().enumerate() {
+        for (j, b) in positions.iter().enumerate().skip(i + 1) {
+            candidates.extend(axes.iter().map(|axis| axis.cross(&(b - a))));
+            for c in &positions[j + 1..] {
+                candidates.push((b - a).cross(&(c - a)));
+            }
+        }
+    }
+
+    let half = voxel_block.length / 2.0;
+    // how far the nearest camera is in front of the block along the normal
+    let gap = |normal: &Vector3<f32>| {
+        // furthest the block reaches along the normal
+        let block_extent = normal.dot(&voxel_block.center) + half * normal.abs().sum();
+        positions
+            .iter()
+            .map(|pos| normal.dot(pos) - block_extent)
+            .fold(f32::MAX, f32::min)
+    };
+    candidates
+        .into_iter()
+        .filter_map(|candidate| candidate.try_normalize(1e-6))
+        .flat_map(|normal| [normal, -normal])
+        .map(|normal| (gap(&normal), normal))
+        .filter(|&(gap, _)| gap > 0.0 && !positions.is_empty())
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, normal)| normal)
+}
+
+/// Voxel indices grouped into slabs one voxel thick along the normal, nearest the cameras first.
+fn layers(voxel_block: &VoxelBlock, normal: Vector3<f32>) -> Vec<Vec<usize>> {
+    let half_voxel_length = voxel_block.voxel_length() / 2.0;
+    let distance = |index: usize| {
+        let (x, y, z) = voxel_block.index_to_coordinate(index);
+        -normal.dot(&Vector3::new(x, y, z).add_scalar(half_voxel_length))
+    };
+    let nearest = (0..voxel_block.voxels.len())
+        .map(distance)
+        .fold(f32::MAX, f32::min);
+
+    let mut layers: Vec<Vec<usize>> = vec![];
+    for index in 0..voxel_block.voxels.len() {
+        let layer = ((distance(index) - nearest) / voxel_block.voxel_length()) as usize;
+        if layers.len() <= layer {
+            layers.resize(layer + 1, vec![]);
+        }
+        layers[layer].push(index);
+    }
+    layers
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::{color_voxels, ordinal_visibility_direction, ColoringError};
+    use crate::{
+        config::CarvingConfig,
+        image::Image,
+        progress::{CancellationToken, NoProgress},
+        stats::CarvingStats,
+        test_scenes::{camera_at, render_with, AROUND},
+        voxel::VoxelBlock,
+    };
+
+    // far apart, so a voxel in front of the object looks inconsistent
+    fn voxel_color(index: usize) -> [u8; 3] {
+        [[250, 30, 30], [30, 250, 30], [30, 30, 250], [250, 250, 30]][index % 4]
+    }
+
+    fn render(voxel_block: &VoxelBlock, pos: Vector3<f32>) -> Image {
+        render_with(voxel_block, camera_at(pos, 64, 64, 0.9), voxel_color)
+    }
+
+    #[test]
+    fn test_color_voxels() {
+        // the bottom half of a block, seen from above at an angle from four sides
+        let mut object = VoxelBlock::new(2, 2);
+        for index in 4..8 {
+            object.carve(index);
+        }
+        let positions = [
+            Vector3::new(3.0, 0.3, 2.4),
+            Vector3::new(-3.0, -0.3, 2.4),
+            Vector3::new(0.3, 3.0, 2.4),
+            Vector3::new(-0.3, -3.0, 2.4),
+        ];
+        let mut images: Vec<Image> = positions.iter().map(|&pos| render(&object, pos)).collect();
+
+        let mut voxel_block = VoxelBlock::new(2, 2);
+        assert_eq!(
+            ordinal_visibility_direction(&voxel_block, &images).map(|normal| normal.z > 0.0),
+            Some(true)
+        );
+        let color = |voxel_block: &mut VoxelBlock, images: &mut [Image]| {
+            let config = CarvingConfig::default();
+            color_voxels(
+                voxel_block,
+                images,
+                &config,
+                &mut NoProgress,
+                &CancellationToken::default(),
+                &mut CarvingStats::new(images),
+            )
+            .unwrap()
+        };
+        color(&mut voxel_block, &mut images);
+        for index in 0..8 {
+            assert_eq!(
+                voxel_block.voxels[index].carved,
+                index >= 4,
+                "voxel {index}"
+            );
+        }
+        for index in 0..4 {
+            let color = voxel_color(index);
+            assert_eq!(
+                voxel_block.voxels[index].color,
+                Some(Vector3::new(color[0], color[1], color[2]))
+            );
+        }
+    }
+
+    // an image of nothing, only its camera matters
+    fn blank(pos: Vector3<f32>) -> Image {
+        Image::new(
+            format!("{pos}"),
+            vec![0; 4 * 4 * 3],
+            camera_at(pos, 4, 4, 1.0),
+        )
+    }
+
+    #[test]
+    fn test_ordinal_visibility_direction() {
+        let voxel_block = VoxelBlock::new(2, 2);
+        let direction = |positions: &[[f32; 3]]| {
+            let images: Vec<Image> = positions
+                .iter()
+                .map(|&[x, y, z]| blank(Vector3::new(x, y, z)))
+                .collect();
+            ordinal_visibility_direction(&voxel_block, &images)
+        };
+        // only a diagonal plane separates these, neither an axis nor the way to their centroid does
+        let normal = direction(&[
+            [5.0, -2.0, 0.0],
+            [5.0, -2.2, 0.5],
+            [5.0, -2.0, -0.5],
+            [-2.0, 5.0, 0.0],
+        ])
+        .unwrap();
+        assert!(normal.x > 0.5 && normal.y > 0.5, "{normal}");
+        assert!(direction(&[[1.5, 0.0, 0.0]]).is_some());
+        // cameras on both sides of the block can't be ordered
+        assert!(direction(&[[4.0, 0.5, 0.0], [-4.0, 0.0, 0.5]]).is_none());
+        // nor can a camera inside it
+        assert!(direction(&[[0.2, 0.1, 0.3]]).is_none());
+        assert!(direction(&[]).is_none());
+    }
+
+    #[test]
+    fn test_cameras_all_around() {
+        let mut images: Vec<Image> = AROUND
+            .iter()
+            .map(|&[x, y, z]| blank(Vector3::new(x, y, z)))
+            .collect();
+        let mut stats = CarvingStats::new(&images);
+        let mut voxel_block = VoxelBlock::new(2, 2);
+        let result = color_voxels(
+            &mut voxel_block,
+            &mut images,
+            &CarvingConfig::default(),
+            &mut NoProgress,
+            &CancellationToken::default(),
+            &mut stats,
+        );
+        assert_eq!(result, Err(ColoringError::NoOrdinalVisibility));
+        assert!(voxel_block.voxels.iter().all(|voxel| !voxel.carved));
+        assert!(stats.rounds.is_empty());
+    }
+}